@@ -0,0 +1,102 @@
+//! Throughput of writing a stream of frames to a file, per frame vs. coalesced by `WriteBuffer`.
+//!
+//! Run with `cargo bench --bench write_buffer`.
+
+#[path = "../src/buffer.rs"]
+#[allow(dead_code, unused_imports)]
+mod buffer;
+
+use std::{
+    env::temp_dir,
+    fs::{remove_file, File},
+    io,
+    time::{Duration, Instant},
+};
+
+use buffer::WriteBuffer;
+
+const TOTAL_SIZE: usize = 64 * 1024 * 1024;
+const FRAME_SIZES: [usize; 3] = [1024, 8 * 1024, 64 * 1024];
+const BUFFER_SIZES: [Option<usize>; 4] = [
+    None,
+    Some(64 * 1024),
+    Some(1024 * 1024),
+    Some(4 * 1024 * 1024),
+];
+
+/// Write the whole buffer at `pos` of the file, by `pwrite` like the server does on Unix.
+#[cfg(unix)]
+fn write_at(file: &File, buf: &[u8], pos: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(buf, pos)
+}
+
+/// Write the whole buffer at `pos` of the file, by seeking since the frames are written in order.
+#[cfg(not(unix))]
+fn write_at(mut file: &File, buf: &[u8], pos: u64) -> io::Result<()> {
+    use std::io::{Seek, SeekFrom, Write};
+    file.seek(SeekFrom::Start(pos))?;
+    file.write_all(buf)
+}
+
+fn write_frames(file: &File, frame: &[u8], buffer_size: Option<usize>) -> io::Result<()> {
+    let mut pos = 0;
+    match buffer_size {
+        None => {
+            while pos < TOTAL_SIZE {
+                write_at(file, frame, pos as u64)?;
+                pos += frame.len();
+            }
+        }
+        Some(buffer_size) => {
+            let mut buffer = WriteBuffer::new(buffer_size, 0);
+            while pos < TOTAL_SIZE {
+                let mut bytes = frame;
+                while !bytes.is_empty() {
+                    let n = buffer.fill(bytes);
+                    bytes = &bytes[n..];
+                    if buffer.is_full() {
+                        let (buf, at) = buffer.take();
+                        write_at(file, &buf, at)?;
+                        buffer.recycle(buf);
+                    }
+                }
+                pos += frame.len();
+            }
+            if !buffer.is_empty() {
+                let (buf, at) = buffer.take();
+                write_at(file, &buf, at)?;
+            }
+        }
+    }
+    file.sync_data()
+}
+
+fn bench(frame_size: usize, buffer_size: Option<usize>) -> io::Result<Duration> {
+    let path = temp_dir().join(format!("intray-bench-{}-{:?}", frame_size, buffer_size));
+    let file = File::create(&path)?;
+    let frame = vec![0x42; frame_size];
+    let start = Instant::now();
+    let result = write_frames(&file, &frame, buffer_size);
+    let elapsed = start.elapsed();
+    drop(file);
+    remove_file(&path)?;
+    result.map(|_| elapsed)
+}
+
+fn main() -> io::Result<()> {
+    println!("Writing {} MiB in total:", TOTAL_SIZE / 1024 / 1024);
+    for &frame_size in FRAME_SIZES.iter() {
+        for &buffer_size in BUFFER_SIZES.iter() {
+            let elapsed = bench(frame_size, buffer_size)?;
+            println!(
+                "frame: {:>6} B, buffer: {:>9}, elapsed: {:>8.2?}, throughput: {:>8.2} MiB/s",
+                frame_size,
+                buffer_size.map_or_else(|| String::from("none"), |size| format!("{} B", size)),
+                elapsed,
+                TOTAL_SIZE as f64 / 1024.0 / 1024.0 / elapsed.as_secs_f64()
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use axum::{
+    body::Body,
+    extract::{self, Json, Path, Request},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid as UUID;
+
+use std::sync::Arc;
+
+use crate::{
+    auth::AuthUser,
+    config::Config,
+    history::{HistoryEntry, HistoryQuery},
+    state::{PendingUpload, State},
+    web::serve_embedded_file,
+};
+
+/// The number of recent completions shown in the dashboard.
+const RECENT_LIMIT: usize = 20;
+
+/// Middleware restricting access to administrators, to be used with `axum::middleware::from_fn`
+/// inside `SimplisticHTTPBasicAuth`.
+///
+/// Requests are rejected with HTTP 403 unless authenticated as one of the administrators, so
+/// nobody is an administrator if authentication is disabled.
+pub async fn require_admin(config: Arc<Config>, req: Request, next: Next) -> Response {
+    match req.extensions().get::<AuthUser>() {
+        Some(AuthUser(user)) if config.is_admin(user) => next.run(req).await,
+        _ => Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Body::from("Forbidden"))
+            .unwrap(),
+    }
+}
+
+pub async fn handle_admin() -> Response {
+    serve_embedded_file("/admin.html")
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiskStatus {
+    /// Space (in bytes) available in the storage, if known
+    pub free: Option<u64>,
+    /// Total size (in bytes) of the objects in the storage
+    pub usage: Option<u64>,
+    pub quota: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseAdminStatus {
+    pub ok: bool,
+    pub pending: Vec<PendingUpload>,
+    /// Recent completions from the newest, only if the history is enabled
+    pub recent: Option<Vec<HistoryEntry>>,
+    pub disk: DiskStatus,
+    pub error: Option<String>,
+}
+
+pub async fn handle_admin_status(
+    extract::State(state): extract::State<State>,
+) -> Json<ResponseAdminStatus> {
+    let mut errors = vec![];
+    let recent = if state.config().history.is_some() {
+        let query = HistoryQuery {
+            limit: Some(RECENT_LIMIT),
+            ..Default::default()
+        };
+        match state.query_history(query).await {
+            Ok((entries, _more)) => Some(entries),
+            Err(e) => {
+                errors.push(format!("Failed to query the history: {}", e));
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let free = state
+        .disk_free()
+        .await
+        .map_err(|e| errors.push(format!("Failed to get free space: {}", e)))
+        .ok()
+        .flatten();
+    let usage = state
+        .dir_usage()
+        .await
+        .map_err(|e| errors.push(format!("Failed to get disk usage: {}", e)))
+        .ok();
+    Json(ResponseAdminStatus {
+        ok: errors.is_empty(),
+        pending: state.pending_uploads().await,
+        recent,
+        disk: DiskStatus {
+            free,
+            usage,
+            quota: state.config().quota,
+        },
+        error: if errors.is_empty() {
+            None
+        } else {
+            Some(errors.join(" "))
+        },
+    })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseAdminCancel {
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+pub async fn handle_admin_cancel(
+    extract::State(state): extract::State<State>,
+    Path(file_token): Path<UUID>,
+) -> Json<ResponseAdminCancel> {
+    Json(match state.cancel_upload(file_token).await {
+        Ok(()) => ResponseAdminCancel {
+            ok: true,
+            error: None,
+        },
+        Err(e) => ResponseAdminCancel {
+            ok: false,
+            error: Some(e.to_string()),
+        },
+    })
+}
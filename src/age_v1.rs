@@ -0,0 +1,215 @@
+//! The primitives of the [age v1](https://age-encryption.org/v1) format that `encrypt` stores files
+//! in, i.e. the X25519 recipient stanza, the header MAC and the STREAM encryption of the payload.
+//!
+//! They are implemented here rather than taken from the `age` crate, since its encryptor only
+//! takes the payload sequentially, while chunks of a file arrive concurrently and in any order.
+//! Feeding them in order would mean either holding the chunks ahead in memory without bound or
+//! stalling the uploads until the chunks behind arrive. Instead, every segment of the payload is
+//! sealed on its own by its index here, which the format allows as long as each segment is sealed
+//! at most once. Everything else is left to `encrypt`, and the output is checked against the `age`
+//! crate by the tests.
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use bech32::{FromBase32, ToBase32, Variant};
+use chacha20poly1305::{aead::AeadInPlace, ChaCha20Poly1305, KeyInit};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use std::{fmt, io, str::FromStr};
+
+/// The size of the plaintext of every segment except the last one.
+pub const SEGMENT_SIZE: u64 = 64 * 1024;
+
+/// The size of the authentication tag appended to every segment.
+pub const TAG_SIZE: u64 = 16;
+
+/// The size of the nonce preceding the payload, from which the payload key is derived.
+const NONCE_SIZE: usize = 16;
+
+/// The human-readable part of the Bech32-encoded recipients.
+const RECIPIENT_HRP: &str = "age";
+
+/// An X25519 recipient to encrypt files to, in the form of `age1...` as generated by `age-keygen`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Recipient(PublicKey);
+
+impl FromStr for Recipient {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid age recipient {:?}", s);
+        let (hrp, data, variant) = bech32::decode(s).map_err(|_| invalid())?;
+        if hrp != RECIPIENT_HRP || variant != Variant::Bech32 {
+            return Err(invalid());
+        }
+        let key: [u8; 32] = Vec::<u8>::from_base32(&data)
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(invalid)?;
+        Ok(Recipient(PublicKey::from(key)))
+    }
+}
+
+impl TryFrom<String> for Recipient {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let encoded = bech32::encode(
+            RECIPIENT_HRP,
+            self.0.as_bytes().to_base32(),
+            Variant::Bech32,
+        )
+        .map_err(|_| fmt::Error)?;
+        write!(f, "{}", encoded)
+    }
+}
+
+/// Derive a 32-byte key by HKDF-SHA-256.
+fn hkdf(salt: &[u8], ikm: &[u8], info: &[u8]) -> [u8; 32] {
+    let mut key = [0; 32];
+    Hkdf::<Sha256>::new(Some(salt), ikm)
+        .expand(info, &mut key)
+        .expect("32 bytes is a valid length for HKDF-SHA-256");
+    key
+}
+
+fn random_bytes<const N: usize>() -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    getrandom::getrandom(&mut bytes).map_err(io::Error::other)?;
+    Ok(bytes)
+}
+
+/// Generate the header of a file encrypted with `file_key` to the recipient.
+fn header(recipient: &Recipient, file_key: &[u8; 16]) -> io::Result<Vec<u8>> {
+    let ephemeral = StaticSecret::from(random_bytes::<32>()?);
+    let ephemeral_share = PublicKey::from(&ephemeral);
+    let shared_secret = ephemeral.diffie_hellman(&recipient.0);
+    if !shared_secret.was_contributory() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The age recipient is a low-order point.",
+        ));
+    }
+    let mut salt = ephemeral_share.as_bytes().to_vec();
+    salt.extend_from_slice(recipient.0.as_bytes());
+    let wrap_key = hkdf(
+        &salt,
+        shared_secret.as_bytes(),
+        b"age-encryption.org/v1/X25519",
+    );
+    let mut wrapped = file_key.to_vec();
+    ChaCha20Poly1305::new(&wrap_key.into())
+        .encrypt_in_place(&[0; 12].into(), b"", &mut wrapped)
+        .map_err(|_| io::Error::other("Failed to wrap the file key."))?;
+
+    let mut header = format!(
+        "age-encryption.org/v1\n-> X25519 {}\n{}\n---",
+        STANDARD_NO_PAD.encode(ephemeral_share.as_bytes()),
+        STANDARD_NO_PAD.encode(&wrapped)
+    )
+    .into_bytes();
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&hkdf(b"", file_key, b"header"))
+        .expect("HMAC takes keys of any length");
+    mac.update(&header);
+    let mac = STANDARD_NO_PAD.encode(mac.finalize().into_bytes());
+    header.extend_from_slice(format!(" {}\n", mac).as_bytes());
+    Ok(header)
+}
+
+/// Get the number of segments of a payload of the size, which is positive even if empty.
+pub fn segment_count(size: u64) -> u64 {
+    size.div_ceil(SEGMENT_SIZE).max(1)
+}
+
+/// Get the position of the sealed segment at `index` in the payload.
+pub fn segment_position(index: u64) -> u64 {
+    index * (SEGMENT_SIZE + TAG_SIZE)
+}
+
+/// The cipher sealing the segments of the payload of a new file.
+pub struct PayloadCipher(ChaCha20Poly1305);
+
+impl PayloadCipher {
+    /// Generate a new file key for the recipient, return the cipher along with the header and the
+    /// nonce preceding the payload.
+    pub fn new(recipient: &Recipient) -> io::Result<(Self, Vec<u8>)> {
+        let file_key = random_bytes::<16>()?;
+        let nonce = random_bytes::<NONCE_SIZE>()?;
+        let mut prefix = header(recipient, &file_key)?;
+        prefix.extend_from_slice(&nonce);
+        let payload_key = hkdf(&nonce, &file_key, b"payload");
+        let cipher = ChaCha20Poly1305::new(&payload_key.into());
+        Ok((PayloadCipher(cipher), prefix))
+    }
+
+    /// Seal the plaintext of the segment at `index` in place, which must be done at most once for
+    /// each segment, since the nonce is determined by the index.
+    pub fn seal(&self, index: u64, last: bool, data: &mut Vec<u8>) -> io::Result<()> {
+        let mut nonce = [0; 12];
+        nonce[3..11].copy_from_slice(&index.to_be_bytes());
+        nonce[11] = last as u8;
+        self.0
+            .encrypt_in_place(&nonce.into(), b"", data)
+            .map_err(|_| io::Error::other("Failed to encrypt the segment."))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use age::secrecy::ExposeSecret;
+    use std::io::Read;
+
+    #[test]
+    fn test_recipient() {
+        let identity = age::x25519::Identity::generate();
+        let encoded = identity.to_public().to_string();
+        let recipient: Recipient = encoded.parse().unwrap();
+        assert_eq!(recipient.to_string(), encoded);
+        assert!(encoded.to_uppercase().parse::<Recipient>().is_ok());
+        assert!(identity
+            .to_string()
+            .expose_secret()
+            .parse::<Recipient>()
+            .is_err());
+        assert!("age1xyz".parse::<Recipient>().is_err());
+    }
+
+    #[test]
+    fn test_payload_cipher() {
+        let identity = age::x25519::Identity::generate();
+        let recipient = identity.to_public().to_string().parse().unwrap();
+        let plaintext: Vec<u8> = (0..SEGMENT_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let (cipher, mut encrypted) = PayloadCipher::new(&recipient).unwrap();
+        // segments are sealed in any order
+        let mut sealed: Vec<_> = plaintext
+            .chunks(SEGMENT_SIZE as usize)
+            .enumerate()
+            .rev()
+            .map(|(index, segment)| {
+                let mut segment = segment.to_vec();
+                let last = index == 2;
+                cipher.seal(index as u64, last, &mut segment).unwrap();
+                segment
+            })
+            .collect();
+        sealed.reverse();
+        encrypted.extend(sealed.concat());
+        let decryptor = age::Decryptor::new(&encrypted[..]).unwrap();
+        let mut reader = decryptor
+            .decrypt(std::iter::once(&identity as &dyn age::Identity))
+            .unwrap();
+        let mut decrypted = vec![];
+        reader.read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+}
@@ -1,43 +1,118 @@
+use axum::{
+    body::Body,
+    extract::{self, Json, Path},
+    http::{HeaderMap, StatusCode},
+};
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
-use tide::{error::ResultExt, response, Context, EndpointResult};
 use uuid::Uuid as UUID;
 
-use crate::state::State;
+use std::{io, path::Path as FsPath};
 
-#[derive(Debug, Deserialize)]
-struct RequestUploadStart {
-    file_name: String,
-    file_size: usize,
-    chunk_size: usize,
+use crate::{audit::Uploader, error::Error, extract::Extracted, state::State};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RequestUploadStart {
+    pub file_name: String,
+    pub file_size: usize,
+    pub chunk_size: usize,
+    /// Hex-encoded SHA-256 digest of the whole file, to skip the upload if the content is
+    /// already present
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseUploadStart {
+    pub ok: bool,
+    pub file_token: Option<String>,
+    /// Seconds after which the pending file expires if no chunks are received
+    pub ttl: Option<u64>,
+    /// Whether the content is already present, in which case there is nothing to upload
+    #[serde(default)]
+    pub present: bool,
+    pub error: Option<String>,
+}
+
+/// What clients may do when uploading, as configured by the server.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseUploadOptions {
+    /// Whether the upload is skipped if the SHA-256 digest passed to `/upload/start` matches
+    /// content already present, without which hashing files beforehand is pointless
+    pub present: bool,
 }
 
-#[derive(Debug, Serialize)]
-struct ResponseUploadStart {
-    ok: bool,
-    file_token: Option<String>,
-    error: Option<String>,
+pub async fn handle_upload_options(
+    extract::State(state): extract::State<State>,
+) -> Json<ResponseUploadOptions> {
+    let config = state.config();
+    Json(ResponseUploadOptions {
+        present: config.dedup.is_some() && config.dedup_present,
+    })
 }
 
-pub async fn handle_upload_start(mut ctx: Context<State>) -> EndpointResult {
-    let req: RequestUploadStart = ctx.body_json().await.client_err()?;
-    match ctx
-        .state()
-        .start_upload(req.file_name, req.file_size, req.chunk_size)
+pub async fn handle_upload_start(
+    extract::State(state): extract::State<State>,
+    uploader: Uploader,
+    Json(req): Json<RequestUploadStart>,
+) -> (StatusCode, Json<ResponseUploadStart>) {
+    let failed = |e: Error| {
+        (
+            e.status_code(),
+            Json(ResponseUploadStart {
+                ok: false,
+                file_token: None,
+                ttl: None,
+                present: false,
+                error: Some(e.to_string()),
+            }),
+        )
+    };
+    if let Some(ref sha256) = req.sha256 {
+        let result = state
+            .put_present(
+                req.file_name.clone(),
+                req.file_size,
+                sha256,
+                uploader.clone(),
+            )
+            .await;
+        match result {
+            // the name of the identical file is not told, which may be uploaded by others
+            Ok(Some(_)) => {
+                return (
+                    StatusCode::OK,
+                    Json(ResponseUploadStart {
+                        ok: true,
+                        file_token: None,
+                        ttl: None,
+                        present: true,
+                        error: None,
+                    }),
+                )
+            }
+            Ok(None) => (),
+            Err(e) => return failed(e),
+        }
+    }
+    match state
+        .start_upload(req.file_name, req.file_size, req.chunk_size, uploader)
         .await
     {
         Ok(token) => {
-            debug!("Upload starts with UUID: {}", token.to_hyphenated());
-            Ok(response::json(ResponseUploadStart {
-                ok: true,
-                file_token: Some(token.to_hyphenated().to_string()),
-                error: None,
-            }))
+            debug!("Upload starts with UUID: {}", token.hyphenated());
+            (
+                StatusCode::OK,
+                Json(ResponseUploadStart {
+                    ok: true,
+                    file_token: Some(token.hyphenated().to_string()),
+                    ttl: state.ttl(token).await.ok().map(|ttl| ttl.as_secs()),
+                    present: false,
+                    error: None,
+                }),
+            )
         }
-        Err(e) => Ok(response::json(ResponseUploadStart {
-            ok: false,
-            file_token: None,
-            error: Some(e.to_string()),
-        })),
+        Err(e) => failed(e),
     }
 }
 
@@ -48,55 +123,102 @@ struct RequestUploadChunk {
     chunk_size: usize,
 } */
 
-#[derive(Debug, Serialize)]
-struct ResponseUploadChunk {
-    ok: bool,
-    error: Option<String>,
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseUploadChunk {
+    pub ok: bool,
+    /// Seconds after which the pending file expires if no more chunks are received
+    pub ttl: Option<u64>,
+    pub error: Option<String>,
 }
 
-pub async fn handle_upload_chunk(mut ctx: Context<State>) -> EndpointResult {
-    let file_token: UUID = ctx.param("file").client_err()?;
-    let chunk_index: usize = ctx.param("chunk").client_err()?;
-    let data = ctx.take_body();
-    Ok(response::json(
-        match ctx.state().put_chunk(file_token, chunk_index, data).await {
-            Ok(_) => ResponseUploadChunk {
+pub async fn handle_upload_chunk(
+    extract::State(state): extract::State<State>,
+    Path((file_token, chunk_index)): Path<(UUID, usize)>,
+    body: Body,
+) -> (StatusCode, Json<ResponseUploadChunk>) {
+    let result = state
+        .put_chunk(file_token, chunk_index, body_stream(body))
+        .await;
+    // the file may be still pending even if the chunk fails
+    let ttl = state.ttl(file_token).await.ok().map(|ttl| ttl.as_secs());
+    match result {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(ResponseUploadChunk {
                 ok: true,
+                ttl,
                 error: None,
-            },
-            Err(e) => ResponseUploadChunk {
+            }),
+        ),
+        Err(e) => (
+            e.status_code(),
+            Json(ResponseUploadChunk {
                 ok: false,
+                ttl,
                 error: Some(e.to_string()),
-            },
-        },
-    ))
+            }),
+        ),
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct RequestUploadFinish {
-    file_token: UUID,
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RequestUploadFinish {
+    pub file_token: UUID,
 }
 
-#[derive(Debug, Serialize)]
-struct ResponseUploadFinish {
-    ok: bool,
-    error: Option<String>,
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseUploadFinish {
+    pub ok: bool,
+    /// The size of the file received
+    pub size: Option<u64>,
+    /// The size of the stored file if compressed
+    pub compressed_size: Option<u64>,
+    /// Whether the file is deduplicated against an identical one stored before
+    #[serde(default)]
+    pub deduplicated: bool,
+    /// The name of the identical file if deduplicated
+    pub existing: Option<String>,
+    /// The entries extracted if the file is an archive to extract
+    #[serde(default)]
+    pub extracted: Option<Extracted>,
+    /// The reason why the archive is not extracted if failed
+    #[serde(default)]
+    pub extract_error: Option<String>,
+    pub error: Option<String>,
 }
 
-pub async fn handle_upload_finish(mut ctx: Context<State>) -> EndpointResult {
-    let req: RequestUploadFinish = ctx.body_json().await.client_err()?;
-    Ok(response::json(
-        match ctx.state().finish_upload(req.file_token).await {
-            Ok(_) => ResponseUploadFinish {
+pub async fn handle_upload_finish(
+    extract::State(state): extract::State<State>,
+    Json(req): Json<RequestUploadFinish>,
+) -> (StatusCode, Json<ResponseUploadFinish>) {
+    match state.finish_upload(req.file_token).await {
+        Ok(finished) => (
+            StatusCode::OK,
+            Json(ResponseUploadFinish {
                 ok: true,
+                size: Some(finished.size),
+                compressed_size: finished.compressed_size,
+                deduplicated: finished.duplicate_of.is_some(),
+                existing: finished.duplicate_of.as_deref().and_then(stored_name),
+                extracted: finished.extracted,
+                extract_error: finished.extract_error,
                 error: None,
-            },
-            Err(e) => ResponseUploadFinish {
+            }),
+        ),
+        Err(e) => (
+            e.status_code(),
+            Json(ResponseUploadFinish {
                 ok: false,
+                size: None,
+                compressed_size: None,
+                deduplicated: false,
+                existing: None,
+                extracted: None,
+                extract_error: None,
                 error: Some(e.to_string()),
-            },
-        },
-    ))
+            }),
+        ),
+    }
 }
 
 /* #[derive(Debug, Deserialize)]
@@ -106,42 +228,105 @@ struct RequestUploadChunk {
     chunk_size: usize,
 } */
 
-#[derive(Debug, Serialize)]
-struct ResponseUploadFull {
-    ok: bool,
-    written: Option<usize>,
-    error: Option<String>,
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseUploadFull {
+    pub ok: bool,
+    pub written: Option<usize>,
+    /// The size of the stored file if compressed
+    pub compressed_size: Option<u64>,
+    /// Whether the file is deduplicated against an identical one stored before
+    #[serde(default)]
+    pub deduplicated: bool,
+    /// The name of the identical file if deduplicated
+    pub existing: Option<String>,
+    /// The entries extracted if the file is an archive to extract
+    #[serde(default)]
+    pub extracted: Option<Extracted>,
+    /// The reason why the archive is not extracted if failed
+    #[serde(default)]
+    pub extract_error: Option<String>,
+    pub error: Option<String>,
 }
 
 // TODO: redundant trivial functions calling
-pub async fn handle_upload_full_unnamed(ctx: Context<State>) -> EndpointResult {
-    handle_upload_full(ctx, String::from("")).await
+pub async fn handle_upload_full_unnamed(
+    state: extract::State<State>,
+    uploader: Uploader,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<(StatusCode, Json<ResponseUploadFull>), StatusCode> {
+    handle_upload_full(state, uploader, String::from(""), headers, body).await
 }
 
-pub async fn handle_upload_full_named(ctx: Context<State>) -> EndpointResult {
-    let file_name = ctx.param("name").unwrap_or_else(|_| String::from(""));
-    handle_upload_full(ctx, file_name).await
+pub async fn handle_upload_full_named(
+    state: extract::State<State>,
+    uploader: Uploader,
+    Path(file_name): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<(StatusCode, Json<ResponseUploadFull>), StatusCode> {
+    handle_upload_full(state, uploader, file_name, headers, body).await
 }
 
-pub async fn handle_upload_full(mut ctx: Context<State>, file_name: String) -> EndpointResult {
+pub async fn handle_upload_full(
+    extract::State(state): extract::State<State>,
+    uploader: Uploader,
+    file_name: String,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<(StatusCode, Json<ResponseUploadFull>), StatusCode> {
     // TODO: Cow <str>
-    let size: Option<usize> = match ctx.headers().get("Content-Length") {
-        Some(v) => Some(v.to_str().client_err()?.parse().client_err()?),
+    let size: Option<usize> = match headers.get("Content-Length") {
+        Some(v) => Some(
+            v.to_str()
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .ok_or(StatusCode::BAD_REQUEST)?,
+        ),
         None => None,
     };
-    let data = ctx.take_body();
-    Ok(response::json(
-        match ctx.state().put_full(file_name, size, data).await {
-            Ok(written) => ResponseUploadFull {
-                ok: true,
-                written: Some(written),
-                error: None,
-            },
-            Err(e) => ResponseUploadFull {
-                ok: false,
-                written: None,
-                error: Some(e.to_string()),
-            },
+    Ok(
+        match state
+            .put_full(file_name, size, body_stream(body), uploader)
+            .await
+        {
+            Ok(finished) => (
+                StatusCode::OK,
+                Json(ResponseUploadFull {
+                    ok: true,
+                    written: Some(finished.size as usize),
+                    compressed_size: finished.compressed_size,
+                    deduplicated: finished.duplicate_of.is_some(),
+                    existing: finished.duplicate_of.as_deref().and_then(stored_name),
+                    extracted: finished.extracted,
+                    extract_error: finished.extract_error,
+                    error: None,
+                }),
+            ),
+            Err(e) => (
+                e.status_code(),
+                Json(ResponseUploadFull {
+                    ok: false,
+                    written: None,
+                    compressed_size: None,
+                    deduplicated: false,
+                    existing: None,
+                    extracted: None,
+                    extract_error: None,
+                    error: Some(e.to_string()),
+                }),
+            ),
         },
-    ))
+    )
+}
+
+/// Get the name of the stored file at the path to report to clients.
+fn stored_name(path: &FsPath) -> Option<String> {
+    Some(path.file_name()?.to_string_lossy().into_owned())
+}
+
+/// Convert the request body into a stream of data frames as expected by `State`.
+fn body_stream(body: Body) -> impl futures::Stream<Item = io::Result<impl AsRef<[u8]>>> + Unpin {
+    body.into_data_stream()
+        .map(|frame| frame.map_err(io::Error::other))
 }
@@ -0,0 +1,180 @@
+use axum::{extract::FromRequestParts, http::request::Parts};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    convert::Infallible,
+    fs::{rename, File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use crate::{auth::AuthUser, proxy::ClientAddr};
+
+/// Who uploads a file, extracted from the request as set by the auth and proxy middlewares.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Uploader {
+    /// The user authenticated by HTTP Basic Auth, if enabled
+    pub user: Option<String>,
+    /// The IP address of the client, if known
+    pub client: Option<IpAddr>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Uploader {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Uploader {
+            user: parts
+                .extensions
+                .get::<AuthUser>()
+                .map(|AuthUser(user)| user.clone()),
+            client: parts
+                .extensions
+                .get::<ClientAddr>()
+                .and_then(|ClientAddr(client)| *client),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Finished,
+    Failed,
+    Expired,
+    Canceled,
+}
+
+/// A record of the audit log, written as a line of JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// The time in RFC 3339
+    pub time: String,
+    #[serde(flatten)]
+    pub uploader: Uploader,
+    /// The file name provided by the uploader
+    pub name: String,
+    /// The size in bytes, either received or declared by the uploader
+    pub size: Option<u64>,
+    /// Hex-encoded SHA-256 digest of the file, only for finished uploads
+    pub sha256: Option<String>,
+    /// The path of the stored file, only for finished uploads
+    pub path: Option<PathBuf>,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new(uploader: Uploader, name: impl Into<String>, outcome: Outcome) -> Self {
+        AuditRecord {
+            time: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            uploader,
+            name: name.into(),
+            size: None,
+            sha256: None,
+            path: None,
+            outcome,
+            error: None,
+        }
+    }
+}
+
+/// An append-only log of JSON lines recording every upload, rotated by size.
+///
+/// Once the log exceeds `max_size`, it is renamed with the suffix `.1`, shifting older ones to
+/// `.2`, `.3` and so on, of which at most `keep` are kept.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    /// The file opened lazily along with its size
+    file: Mutex<Option<(File, u64)>>,
+}
+
+impl AuditLog {
+    pub fn new(path: impl Into<PathBuf>, max_size: u64, keep: usize) -> Self {
+        AuditLog {
+            path: path.into(),
+            max_size,
+            keep,
+            file: Mutex::new(None),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append the record to the log synchronously, rotating the log if needed.
+    pub fn append(&self, record: &AuditRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        if let Some((_, size)) = *file {
+            if size > 0 && size + line.len() as u64 > self.max_size {
+                *file = None;
+                self.rotate()?;
+            }
+        }
+        if file.is_none() {
+            let handle = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            let size = handle.metadata()?.len();
+            *file = Some((handle, size));
+        }
+        let (handle, size) = file.as_mut().unwrap();
+        handle.write_all(&line)?;
+        *size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        let rotated = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+        if self.keep == 0 {
+            return std::fs::remove_file(&self.path);
+        }
+        for n in (1..self.keep).rev() {
+            match rename(rotated(n), rotated(n + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+        rename(&self.path, rotated(1))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AuditLog, AuditRecord, Outcome, Uploader};
+    use std::fs::read_to_string;
+
+    #[test]
+    fn test_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let log = AuditLog::new(&path, 300, 2);
+        let mut record = AuditRecord::new(Uploader::default(), "a.txt", Outcome::Finished);
+        record.size = Some(1);
+        for _ in 0..7 {
+            log.append(&record).unwrap();
+        }
+        let line = read_to_string(&path).unwrap();
+        let parsed: AuditRecord = serde_json::from_str(line.lines().next().unwrap()).unwrap();
+        assert_eq!(parsed.outcome, Outcome::Finished);
+        assert!(dir.path().join("audit.log.1").exists());
+        assert!(dir.path().join("audit.log.2").exists());
+        assert!(!dir.path().join("audit.log.3").exists());
+        let size = |name| std::fs::metadata(dir.path().join(name)).unwrap().len();
+        assert!(size("audit.log") <= 300 && size("audit.log.1") <= 300);
+    }
+}
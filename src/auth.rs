@@ -1,71 +1,80 @@
-use futures::future::BoxFuture;
-// use log::trace;
-
-use base64::decode as base64_decode;
-use tide::{
-    http::{response::Builder as ResponseBuilder, StatusCode, HeaderValue},
-    middleware::{Middleware, Next},
-    Context, Response,
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header::WWW_AUTHENTICATE, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
 };
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+use std::sync::Arc;
 
-use crate::{opt::OPT, web::Assets};
+use crate::{config::Config, metrics::Metrics, web::Assets};
 
 pub type HTTPBasicAuth = SimplisticHTTPBasicAuth;
 
+/// The name of the user authenticated, attached to requests by `SimplisticHTTPBasicAuth`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthUser(pub String);
+
 /// Middleware for HTTP Basic Authentication as defined in [RFC 2617](https://tools.ietf.org/html/rfc2617) and
 /// [RFC 7617](https://tools.ietf.org/html/rfc7617) (simplistic implementation).
-#[derive(Default)]
-pub struct SimplisticHTTPBasicAuth;
+pub struct SimplisticHTTPBasicAuth {
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+}
 
 impl SimplisticHTTPBasicAuth {
-    /// Construct a new instance with an empty list of headers.
-    pub fn new() -> Self {
-        Default::default()
+    /// Construct a new instance accepting the credentials in the config, counting failures into
+    /// the metrics.
+    pub fn new(config: Arc<Config>, metrics: Arc<Metrics>) -> Self {
+        SimplisticHTTPBasicAuth { config, metrics }
     }
 
     /// Match the provided credentials against all the credentials specified, return true if any matches.
     fn authenticate(&self, credentials: impl AsRef<str>) -> bool {
-        OPT.credentials_match(credentials)
+        self.config.credentials_match(credentials)
     }
 
     /// Generate a HTTP 401 Unauthorized response.
     fn unauthorized(&self) -> Response {
-        ResponseBuilder::new()
+        Response::builder()
             .header(
-                "WWW-Authenticate",
-                format!("Basic realm=\"{}\", charset=\"UTF-8\"", &OPT.auth_realm),
+                WWW_AUTHENTICATE,
+                format!(
+                    "Basic realm=\"{}\", charset=\"UTF-8\"",
+                    &self.config.auth_realm
+                ),
             )
             .status(StatusCode::UNAUTHORIZED)
-            .body(
-                Assets::get("401.html")
-                    .expect("HTTP 401 Error Page")
-                    .as_ref()
-                    .into(),
-            )
+            .body(Body::from(
+                Assets::get("401.html").expect("HTTP 401 Error Page").data,
+            ))
             .unwrap()
     }
-    // pub fn auth(&self, cre)
-}
 
-impl<State: Send + Sync + 'static> Middleware<State> for SimplisticHTTPBasicAuth {
-    fn handle<'a>(&'a self, cx: Context<State>, next: Next<'a, State>) -> BoxFuture<'a, Response> {
-        let credentials = cx.headers().get("Authorization").and_then(|value| {
+    /// Handle a request as a middleware, to be used with `axum::middleware::from_fn`.
+    pub async fn handle(self: Arc<Self>, mut req: Request, next: Next) -> Response {
+        let credentials = req.headers().get("Authorization").and_then(|value| {
             let (_type, credentials) = parse_authorization(value)?;
             if _type.eq_ignore_ascii_case("Basic") {
-                Some(String::from_utf8(base64_decode(credentials).ok()?).ok()?)
+                String::from_utf8(BASE64.decode(credentials).ok()?).ok()
             } else {
                 None
             }
         });
-        Box::pin(async move {
         match credentials {
             Some(ref credentials) if self.authenticate(credentials) => {
                 trace!("An request is authenticated with {} .", credentials);
-                next.run(cx).await
+                let user = credentials.split(':').next().unwrap_or_default().to_owned();
+                req.extensions_mut().insert(AuthUser(user));
+                next.run(req).await
+            }
+            _ => {
+                self.metrics.auth_failures.inc();
+                self.unauthorized()
             }
-            _ => self.unauthorized(),
         }
-        })
     }
 }
 
@@ -74,4 +83,4 @@ fn parse_authorization(header_value: &HeaderValue) -> Option<(&str, &str)> {
     // A trailing space is expected to be in `t`.
     let (_type, credentials) = value.split_at(value.find(' ')?);
     Some((_type.trim(), credentials.trim()))
-}
\ No newline at end of file
+}
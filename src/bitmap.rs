@@ -4,12 +4,16 @@ pub trait BitMap {
     fn get_bit(&self, n: usize) -> bool;
     /// Set the bit at the index `n`.
     fn set_bit(&mut self, n: usize);
+    /// Unset the bit at the index `n`.
+    fn unset_bit(&mut self, n: usize);
     /// Check whether all bits of which the indices are *less than* `n` (exclusive) are set.
+    #[allow(dead_code)]
     fn is_set_up_to(&self, n: usize) -> bool;
     /// Get the index of the first bit that is unset.
     fn first_unset(&self) -> usize;
     /// Truncate the BitMap: shrink the underlying storage as much as possible and make all bits of
     /// which the indices are greater than `n` unset.
+    #[allow(dead_code)]
     fn truncate_to_bit(&mut self, n: usize);
 }
 
@@ -32,6 +36,15 @@ impl BitMap for Vec<u8> {
         self[offset_by_byte] |= 1 << offset_in_byte;
     }
 
+    fn unset_bit(&mut self, n: usize) {
+        let offset_by_byte = n / 8;
+        if offset_by_byte >= self.len() {
+            return;
+        }
+        let offset_in_byte = n % 8;
+        self[offset_by_byte] &= !(1 << offset_in_byte);
+    }
+
     fn is_set_up_to(&self, n: usize) -> bool {
         if n == 0 {
             return false;
@@ -90,6 +103,18 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_unset() {
+        let mut bitmap: Vec<u8> = vec![];
+        bitmap.set_bit(3);
+        bitmap.set_bit(4);
+        bitmap.unset_bit(3);
+        bitmap.unset_bit(1024);
+        assert!(!bitmap.get_bit(3));
+        assert!(bitmap.get_bit(4));
+        assert_eq!(bitmap.len(), 1);
+    }
+
     #[test]
     fn test_is_set_up_to() {
         let mut bitmap: Vec<u8> = vec![];
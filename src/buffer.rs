@@ -0,0 +1,163 @@
+use std::{mem, ops::Range};
+
+/// A buffer coalescing small pieces of incoming data into larger ones before they are written to
+/// the underlying file at the position they belong to.
+///
+/// Buffered data is expected to be taken out and written whenever the buffer `is_full`, which happens when the end
+/// of the buffered data reaches a multiple of the capacity in the file, so that all writes except
+/// the first and the last ones are aligned to the capacity.
+#[derive(Debug)]
+pub struct WriteBuffer {
+    buf: Vec<u8>,
+    capacity: usize,
+    /// The position in the file where the buffered data starts
+    pos: u64,
+}
+
+impl WriteBuffer {
+    /// Construct a new buffer of which the data is to be written starting from `pos`.
+    pub fn new(capacity: usize, pos: u64) -> Self {
+        assert!(capacity > 0, "Capacity of WriteBuffer must be positive");
+        WriteBuffer {
+            buf: Vec::with_capacity(capacity),
+            capacity,
+            pos,
+        }
+    }
+
+    /// Copy as much data as possible into the buffer until it is full, return the number of bytes
+    /// copied.
+    pub fn fill(&mut self, data: &[u8]) -> usize {
+        let end = self.pos + self.buf.len() as u64;
+        let room = self.capacity - (end % self.capacity as u64) as usize;
+        let n = room.min(data.len());
+        self.buf.extend_from_slice(&data[..n]);
+        n
+    }
+
+    /// Check whether the buffer is due to be flushed.
+    pub fn is_full(&self) -> bool {
+        !self.buf.is_empty()
+            && (self.pos + self.buf.len() as u64).is_multiple_of(self.capacity as u64)
+    }
+
+    /// Check whether there is no buffered data.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Take the buffered data out along with the position where it should be written to,
+    /// advancing the position accordingly.
+    pub fn take(&mut self) -> (Vec<u8>, u64) {
+        let pos = self.pos;
+        self.pos += self.buf.len() as u64;
+        (mem::take(&mut self.buf), pos)
+    }
+
+    /// Give a buffer taken out before back after it is written, so that its allocation is reused.
+    pub fn recycle(&mut self, mut buf: Vec<u8>) {
+        if self.buf.is_empty() && self.buf.capacity() < buf.capacity() {
+            buf.clear();
+            self.buf = buf;
+        }
+    }
+}
+
+/// A buffer of a fixed size filled by pieces of data written at any positions in any order, which
+/// tells when it is filled up.
+#[derive(Debug)]
+pub struct SparseBuffer {
+    data: Vec<u8>,
+    /// The disjoint ranges of `data` written so far, sorted by their starts
+    written: Vec<Range<usize>>,
+}
+
+impl SparseBuffer {
+    pub fn new(len: usize) -> Self {
+        SparseBuffer {
+            data: vec![0; len],
+            written: vec![],
+        }
+    }
+
+    /// Get the size of the data written contiguously from the start.
+    pub fn filled(&self) -> usize {
+        match self.written.first() {
+            Some(range) if range.start == 0 => range.end,
+            _ => 0,
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.filled() == self.data.len()
+    }
+
+    /// Copy the piece into the buffer at `pos`, overwriting what is written there if any, return
+    /// whether the buffer is filled up.
+    pub fn write(&mut self, pos: usize, piece: &[u8]) -> bool {
+        let mut range = pos..pos + piece.len();
+        self.data[range.clone()].copy_from_slice(piece);
+        self.written.retain(|written| {
+            let apart = written.end < range.start || written.start > range.end;
+            if !apart {
+                range.start = range.start.min(written.start);
+                range.end = range.end.max(written.end);
+            }
+            apart
+        });
+        let at = self
+            .written
+            .partition_point(|written| written.start < range.start);
+        self.written.insert(at, range);
+        self.is_full()
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SparseBuffer, WriteBuffer};
+
+    #[test]
+    fn test_sparse_buffer() {
+        let mut buffer = SparseBuffer::new(10);
+        assert!(!buffer.write(6, b"6789"));
+        assert_eq!(buffer.filled(), 0);
+        assert!(!buffer.write(0, b"012"));
+        assert_eq!(buffer.filled(), 3);
+        // overlapping pieces are merged
+        assert!(!buffer.write(2, b"23"));
+        assert_eq!(buffer.filled(), 4);
+        assert!(buffer.write(4, b"456"));
+        assert_eq!(buffer.into_inner(), b"0123456789");
+    }
+
+    #[test]
+    fn test_fill_aligned() {
+        let mut buffer = WriteBuffer::new(8, 0);
+        assert_eq!(buffer.fill(&[0; 5]), 5);
+        assert!(!buffer.is_full());
+        assert_eq!(buffer.fill(&[0; 5]), 3);
+        assert!(buffer.is_full());
+        let (buf, pos) = buffer.take();
+        assert_eq!((buf.len(), pos), (8, 0));
+        assert!(buffer.is_empty());
+        buffer.recycle(buf);
+        assert_eq!(buffer.fill(&[0; 5]), 5);
+        assert_eq!(buffer.take().1, 8);
+    }
+
+    #[test]
+    fn test_fill_unaligned() {
+        // starting from the middle of the capacity, the first write ends at the next boundary
+        let mut buffer = WriteBuffer::new(8, 13);
+        assert_eq!(buffer.fill(&[0; 16]), 3);
+        assert!(buffer.is_full());
+        assert_eq!(buffer.take(), (vec![0; 3], 13));
+        assert_eq!(buffer.fill(&[0; 16]), 8);
+        assert_eq!(buffer.take(), (vec![0; 8], 16));
+    }
+}
@@ -0,0 +1,157 @@
+use flate2::{write::GzEncoder, Compression};
+use futures::{stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    io::{self, Write},
+    str::FromStr,
+};
+
+/// The codec to compress stored files with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Zstd,
+    Gzip,
+}
+
+impl Codec {
+    /// The extension appended to the names of compressed files.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Codec::Zstd => "zst",
+            Codec::Gzip => "gz",
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zstd" => Ok(Codec::Zstd),
+            "gzip" => Ok(Codec::Gzip),
+            _ => Err(format!("Unknown compression codec {:?}", s)),
+        }
+    }
+}
+
+/// Check whether the file named `name` is to be compressed by the extensions included and
+/// excluded, e.g. `log` or `tar.gz`, which are matched case-insensitively.
+///
+/// All files are included if `include` is empty, while `exclude` takes precedence.
+pub fn is_included(name: &str, include: &[String], exclude: &[String]) -> bool {
+    let name = name.to_lowercase();
+    let matches = |ext: &String| {
+        let ext = ext.trim_start_matches('.').to_lowercase();
+        name.strip_suffix(ext.as_str())
+            .is_some_and(|stem| stem.len() > 1 && stem.ends_with('.'))
+    };
+    (include.is_empty() || include.iter().any(matches)) && !exclude.iter().any(matches)
+}
+
+/// A streaming encoder writing the compressed data into an in-memory buffer.
+enum Encoder {
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(codec: Codec) -> io::Result<Self> {
+        Ok(match codec {
+            Codec::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(vec![], 0)?),
+            Codec::Gzip => Encoder::Gzip(GzEncoder::new(vec![], Compression::default())),
+        })
+    }
+
+    /// Compress the data, return the compressed data produced so far, which may be empty.
+    fn update(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Zstd(encoder) => {
+                encoder.write_all(data)?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(data)?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+        }
+    }
+
+    /// End the compression, return the rest of the compressed data.
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Gzip(encoder) => encoder.finish(),
+        }
+    }
+}
+
+/// Compress the data stream on the fly.
+///
+/// The stream ends after the first error, if any.
+pub fn compress_stream<T: AsRef<[u8]>>(
+    codec: Codec,
+    data: impl Stream<Item = io::Result<T>> + Unpin,
+) -> impl Stream<Item = io::Result<Vec<u8>>> + Unpin {
+    let state = Some((Encoder::new(codec), data));
+    Box::pin(stream::unfold(state, |state| async move {
+        let (encoder, mut data) = state?;
+        let mut encoder = match encoder {
+            Ok(encoder) => encoder,
+            Err(e) => return Some((Err(e), None)),
+        };
+        loop {
+            match data.next().await {
+                Some(Ok(bytes)) => match encoder.update(bytes.as_ref()) {
+                    Ok(compressed) if compressed.is_empty() => continue,
+                    Ok(compressed) => return Some((Ok(compressed), Some((Ok(encoder), data)))),
+                    Err(e) => return Some((Err(e), None)),
+                },
+                Some(Err(e)) => return Some((Err(e), None)),
+                None => return Some((encoder.finish(), None)),
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::{executor::block_on, TryStreamExt};
+
+    #[test]
+    fn test_is_included() {
+        let rules = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert!(is_included("a.log", &[], &[]));
+        assert!(is_included("a.LOG", &rules(&["log", ".csv"]), &[]));
+        assert!(is_included("b.csv", &rules(&["log", ".csv"]), &[]));
+        assert!(!is_included("a.txt", &rules(&["log", "csv"]), &[]));
+        assert!(!is_included("log", &rules(&["log"]), &[]));
+        assert!(!is_included("a.tar.gz", &[], &rules(&["tar.gz"])));
+        assert!(is_included("a.gz", &[], &rules(&["tar.gz"])));
+    }
+
+    #[test]
+    fn test_compress_stream() {
+        let original: Vec<u8> = (0..100).flat_map(|i| vec![i as u8; 1000]).collect();
+        for codec in [Codec::Zstd, Codec::Gzip] {
+            let data = stream::iter(original.chunks(1000).map(Ok::<_, io::Error>));
+            let chunks: Vec<Vec<u8>> =
+                block_on(compress_stream(codec, data).try_collect()).unwrap();
+            let compressed = chunks.concat();
+            assert!(compressed.len() < original.len());
+            let decompressed = match codec {
+                Codec::Zstd => zstd::decode_all(&compressed[..]).unwrap(),
+                Codec::Gzip => {
+                    let mut decoder = flate2::read::GzDecoder::new(&compressed[..]);
+                    let mut out = vec![];
+                    io::Read::read_to_end(&mut decoder, &mut out).unwrap();
+                    out
+                }
+            };
+            assert_eq!(decompressed, original);
+        }
+    }
+}
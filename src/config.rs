@@ -0,0 +1,184 @@
+use std::{path::PathBuf, time::Duration};
+
+use crate::{
+    compress::{is_included, Codec},
+    dedup::DedupMode,
+    encrypt::Recipient,
+    extract::{ArchiveFormat, ExtractLimits},
+    filter::is_type_allowed,
+    proxy::IpNet,
+    retention::RetentionPolicy,
+};
+
+/// Configuration of an intray instance, shared by `State` and the middlewares.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Directory to store received files
+    pub dir: PathBuf,
+    /// Credentials for HTTP Basic Auth in the format "USERNAME:PASSWD", empty to disable
+    /// authentication
+    pub auth_credentials: Vec<String>,
+    /// Realm to send in `WWW-Authenticate` HTTP header for HTTP Basic Auth
+    pub auth_realm: String,
+    /// Size of the buffer (in bytes) to coalesce received data into before writing to disk, which
+    /// must be positive
+    pub write_buffer_size: usize,
+    /// The period after which a pending upload expires if no more chunks are received
+    pub pending_timeout: Duration,
+    /// The period after which a pending upload expires regardless of activity, `None` for unlimited
+    pub pending_max_lifetime: Option<Duration>,
+    /// The interval at which expired pending uploads are swept
+    pub sweep_interval: Duration,
+    /// Path prefix under which all routes are mounted, normalized by `normalize_base_path`
+    pub base_path: String,
+    /// Networks of reverse proxies whose `Forwarded`/`X-Forwarded-For` headers are trusted
+    pub trusted_proxies: Vec<IpNet>,
+    /// Whether to expose Prometheus metrics at `/metrics` along with other routes
+    pub metrics: bool,
+    /// Whether to stream upload events at `/events`
+    pub events: bool,
+    /// Whether to serve `/history` and `/events` even if authentication is disabled, to anyone
+    pub public_activity: bool,
+    /// The total size (in bytes) of the files in the directory beyond which the instance is not
+    /// considered ready, `None` for unlimited
+    pub quota: Option<u64>,
+    /// Path of the audit log recording every upload, `None` to disable
+    pub audit_log: Option<PathBuf>,
+    /// The size (in bytes) beyond which the audit log is rotated
+    pub audit_log_max_size: u64,
+    /// The number of rotated audit logs to keep
+    pub audit_log_keep: usize,
+    /// Path of the history of completed uploads, queried by `/history`, `None` to disable
+    pub history: Option<PathBuf>,
+    /// Users allowed to access the admin dashboard at `/admin`, among those in `auth_credentials`
+    pub admins: Vec<String>,
+    /// How completed uploads identical to files stored before are deduplicated, `None` to disable
+    pub dedup: Option<DedupMode>,
+    /// Whether clients may skip uploading content already present by passing its digest, which
+    /// tells them whether the content is stored and lets them store it without proving they have it
+    pub dedup_present: bool,
+    /// The codec to compress stored files with, `None` to disable
+    pub compress: Option<Codec>,
+    /// Extensions of the files to compress, e.g. `log`, empty for all
+    pub compress_include: Vec<String>,
+    /// Extensions of the files not to compress, which take precedence over `compress_include`
+    pub compress_exclude: Vec<String>,
+    /// The recipient to encrypt stored files to, `None` to store them in plaintext
+    pub encrypt_to: Option<Recipient>,
+    /// Formats of the archives to extract once uploaded, empty to disable
+    pub extract: Vec<ArchiveFormat>,
+    /// The total size (in bytes) of the files extracted from an archive beyond which the
+    /// extraction fails
+    pub extract_max_size: u64,
+    /// The number of entries in an archive beyond which the extraction fails
+    pub extract_max_entries: usize,
+    /// Extensions of the files allowed to upload, e.g. `pdf`, empty for all
+    pub allowed_extensions: Vec<String>,
+    /// Extensions of the files not allowed to upload, which take precedence over
+    /// `allowed_extensions`
+    pub denied_extensions: Vec<String>,
+    /// MIME types of the content allowed to upload, e.g. `image/*`, as sniffed from the first bytes,
+    /// empty for all
+    pub allowed_types: Vec<String>,
+    /// MIME types of the content not allowed to upload, which take precedence over `allowed_types`
+    pub denied_types: Vec<String>,
+    /// The policy by which stored files are cleaned up periodically
+    pub retention: RetentionPolicy,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            dir: PathBuf::from("./"),
+            auth_credentials: vec![],
+            auth_realm: String::from("Intray"),
+            write_buffer_size: 1024 * 1024,
+            pending_timeout: Duration::from_secs(30),
+            pending_max_lifetime: None,
+            sweep_interval: Duration::from_secs(30),
+            base_path: String::new(),
+            trusted_proxies: vec![],
+            metrics: false,
+            events: false,
+            public_activity: false,
+            quota: None,
+            audit_log: None,
+            audit_log_max_size: 10 * 1024 * 1024,
+            audit_log_keep: 5,
+            history: None,
+            admins: vec![],
+            dedup: None,
+            dedup_present: false,
+            compress: None,
+            compress_include: vec![],
+            compress_exclude: vec![],
+            encrypt_to: None,
+            extract: vec![],
+            extract_max_size: 1024 * 1024 * 1024,
+            extract_max_entries: 10_000,
+            allowed_extensions: vec![],
+            denied_extensions: vec![],
+            allowed_types: vec![],
+            denied_types: vec![],
+            retention: RetentionPolicy::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn is_auth_enabled(&self) -> bool {
+        !self.auth_credentials.is_empty()
+    }
+
+    pub fn credentials_match(&self, credentials: impl AsRef<str>) -> bool {
+        let credentials = credentials.as_ref();
+        self.auth_credentials.iter().any(|c| c == credentials)
+    }
+
+    /// Get the codec to compress the file named `name` with if it is to be compressed.
+    pub fn codec_for(&self, name: &str) -> Option<Codec> {
+        self.compress.filter(|_| {
+            !name.is_empty() && is_included(name, &self.compress_include, &self.compress_exclude)
+        })
+    }
+
+    /// Whether the file named `name` is allowed to upload by its extension.
+    pub fn is_name_allowed(&self, name: &str) -> bool {
+        is_included(name, &self.allowed_extensions, &self.denied_extensions)
+    }
+
+    /// Whether the content of files is sniffed to check its type.
+    pub fn filters_types(&self) -> bool {
+        !(self.allowed_types.is_empty() && self.denied_types.is_empty())
+    }
+
+    /// Whether the content of the MIME type is allowed to upload.
+    pub fn is_type_allowed(&self, mime: &str) -> bool {
+        is_type_allowed(mime, &self.allowed_types, &self.denied_types)
+    }
+
+    /// Get the limits of extracting an archive.
+    pub fn extract_limits(&self) -> ExtractLimits {
+        ExtractLimits {
+            max_size: self.extract_max_size,
+            max_entries: self.extract_max_entries,
+        }
+    }
+
+    /// Whether the authenticated user is allowed to access the admin dashboard.
+    pub fn is_admin(&self, user: impl AsRef<str>) -> bool {
+        let user = user.as_ref();
+        self.admins.iter().any(|admin| admin == user)
+    }
+}
+
+/// Normalize a base path to have a leading slash and no trailing slash (e.g. `/intray`), or to be
+/// empty for the root.
+pub fn normalize_base_path(path: &str) -> String {
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", path)
+    }
+}
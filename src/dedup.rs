@@ -0,0 +1,221 @@
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use crate::storage::{Object, Storage};
+
+/// How a completed upload identical to a file stored before is deduplicated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupMode {
+    /// Replace the upload with a hard link to the file, so that both names are kept
+    Link,
+    /// Remove the upload, so that only the file is kept
+    Drop,
+}
+
+impl FromStr for DedupMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "link" => Ok(DedupMode::Link),
+            "drop" => Ok(DedupMode::Drop),
+            _ => Err(format!("Unknown dedup mode {:?}", s)),
+        }
+    }
+}
+
+/// The digest of an object along with its size and modification time when computed.
+#[derive(Debug)]
+struct CachedDigest {
+    size: u64,
+    modified: Option<SystemTime>,
+    sha256: String,
+}
+
+/// A content-addressed index of the stored objects, which finds the object identical to a new one
+/// by comparing the SHA-256 digests of those of the same size.
+///
+/// Digests are computed lazily without holding any lock and cached until the objects are modified,
+/// so that an object is rarely hashed more than once.
+#[derive(Debug, Default)]
+pub struct DedupIndex {
+    /// Digests by the paths of the objects listed as stored
+    digests: Mutex<HashMap<PathBuf, CachedDigest>>,
+    /// The sizes and the digests of the objects just stored and kept, by their paths, which are
+    /// not listed as stored until settled, so that identical uploads finished concurrently are
+    /// deduplicated against each other as well
+    settling: Arc<Mutex<Settlings>>,
+}
+
+/// The objects settling, along with the number of those settled so far.
+#[derive(Debug, Default)]
+struct Settlings {
+    objects: HashMap<PathBuf, (u64, String)>,
+    settled: u64,
+}
+
+/// A guard keeping an object kept by `DedupIndex::deduplicate` known to the index until dropped.
+#[derive(Debug)]
+pub struct Settling {
+    settling: Arc<Mutex<Settlings>>,
+    path: PathBuf,
+}
+
+impl Drop for Settling {
+    fn drop(&mut self) {
+        let mut settling = self.settling.lock().unwrap();
+        settling.objects.remove(&self.path);
+        settling.settled += 1;
+    }
+}
+
+impl DedupIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Find an object among `objects` listed as stored, or those settling, of which the size is
+    /// `size` and the digest is `sha256`.
+    pub async fn find(
+        &self,
+        storage: &dyn Storage,
+        objects: Vec<Object>,
+        size: u64,
+        sha256: &str,
+    ) -> io::Result<Option<PathBuf>> {
+        if let Some(path) = self.find_settling(None, size, sha256) {
+            return Ok(Some(path));
+        }
+        self.lookup(storage, objects, size, sha256).await
+    }
+
+    /// Deduplicate the object just stored at `path`, of which the size is `size` and the digest is
+    /// `sha256`, against the objects listed as stored by `list` and those settling, return the
+    /// path of the identical object if deduplicated.
+    ///
+    /// Otherwise the object is kept, and the guard returned keeps it as settling, i.e. a
+    /// candidate for the others, until dropped, which must last until it is listed as stored.
+    pub async fn deduplicate<F>(
+        &self,
+        storage: &dyn Storage,
+        mode: DedupMode,
+        list: impl Fn() -> F,
+        path: &Path,
+        size: u64,
+        sha256: &str,
+    ) -> io::Result<(Option<PathBuf>, Option<Settling>)>
+    where
+        F: Future<Output = io::Result<Vec<Object>>>,
+    {
+        let original = loop {
+            let listed_at = self.settling.lock().unwrap().settled;
+            let objects = list().await?;
+            if let Some(original) = self.lookup(storage, objects, size, sha256).await? {
+                break original;
+            }
+            // checked again along with the registration, so that only one of identical objects
+            // settling concurrently is kept
+            let mut settling = self.settling.lock().unwrap();
+            if let Some(original) = find_settling(&settling, Some(path), size, sha256) {
+                break original;
+            }
+            // an object settled since listed may be missing from both, so list them again
+            if settling.settled != listed_at {
+                continue;
+            }
+            settling
+                .objects
+                .insert(path.to_owned(), (size, sha256.to_owned()));
+            let guard = Settling {
+                settling: self.settling.clone(),
+                path: path.to_owned(),
+            };
+            return Ok((None, Some(guard)));
+        };
+        match mode {
+            DedupMode::Link => storage.link(&original, path).await?,
+            DedupMode::Drop => storage.remove(path).await?,
+        }
+        Ok((Some(original), None))
+    }
+
+    fn find_settling(&self, own: Option<&Path>, size: u64, sha256: &str) -> Option<PathBuf> {
+        find_settling(&self.settling.lock().unwrap(), own, size, sha256)
+    }
+
+    /// Look up the object of the size and the digest among `objects`, hashing those of the size
+    /// whose digests are not cached, and forget the digests of the objects no longer listed.
+    async fn lookup(
+        &self,
+        storage: &dyn Storage,
+        objects: Vec<Object>,
+        size: u64,
+        sha256: &str,
+    ) -> io::Result<Option<PathBuf>> {
+        {
+            let listed: HashSet<&Path> =
+                objects.iter().map(|object| object.path.as_path()).collect();
+            let mut digests = self.digests.lock().unwrap();
+            digests.retain(|path, _| listed.contains(path.as_path()));
+        }
+        for object in objects.into_iter().filter(|object| object.size == size) {
+            let cached = self
+                .digests
+                .lock()
+                .unwrap()
+                .get(&object.path)
+                .filter(|digest| digest.size == object.size && digest.modified == object.modified)
+                .map(|digest| digest.sha256.clone());
+            let digest = match cached {
+                Some(digest) => digest,
+                None => match storage.sha256(&object.path).await {
+                    Ok(digest) => {
+                        self.digests.lock().unwrap().insert(
+                            object.path.clone(),
+                            CachedDigest {
+                                size: object.size,
+                                modified: object.modified,
+                                sha256: digest.clone(),
+                            },
+                        );
+                        digest
+                    }
+                    Err(e) => {
+                        warn!("Failed to digest {:?}: {}", object.path, e);
+                        continue;
+                    }
+                },
+            };
+            if digest == sha256 {
+                return Ok(Some(object.path));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Find the settling object of the size and the digest other than `own`.
+fn find_settling(
+    settling: &Settlings,
+    own: Option<&Path>,
+    size: u64,
+    sha256: &str,
+) -> Option<PathBuf> {
+    settling
+        .objects
+        .iter()
+        .find(|(path, (settling_size, digest))| {
+            Some(path.as_path()) != own && *settling_size == size && digest == sha256
+        })
+        .map(|(path, _)| path.clone())
+}
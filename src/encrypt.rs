@@ -0,0 +1,412 @@
+//! Encryption at rest in the [age](https://age-encryption.org/v1) format, so that stored files can
+//! only be decrypted by the holder of the identity of the recipient, e.g. by `age -d -i KEY`.
+//!
+//! The payload is encrypted in segments of 64 KiB, each of which is sealed independently at a
+//! position determined by its index once all of its plaintext has been written. So chunks of a
+//! file of any size are still encrypted and written concurrently, without the plaintext ever being
+//! written. A segment is sealed only once, since its nonce is determined by its index, so writing
+//! into a sealed segment again, e.g. by retrying a chunk, is refused. The format itself is
+//! implemented by `age_v1`.
+use bytes::Bytes;
+use futures::{future::BoxFuture, stream::BoxStream};
+
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    age_v1::{segment_count, segment_position, PayloadCipher, TAG_SIZE},
+    bitmap::BitMap,
+    buffer::SparseBuffer,
+    storage::{Object, Staging, Storage},
+};
+
+pub use crate::age_v1::{Recipient, SEGMENT_SIZE};
+
+/// Storage encrypting every file stored into the inner one to a recipient, appending ".age" to
+/// their names.
+///
+/// Files are stored as is otherwise, so they are listed, opened or digested in their encrypted
+/// form.
+#[derive(Debug)]
+pub struct EncryptedStorage {
+    inner: Arc<dyn Storage>,
+    recipient: Recipient,
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: Arc<dyn Storage>, recipient: Recipient) -> Self {
+        EncryptedStorage { inner, recipient }
+    }
+}
+
+impl Storage for EncryptedStorage {
+    /// Since the encrypted chunks are not aligned with those uploaded, the inner storage is
+    /// requested to stage the file without chunks, but written in any order nonetheless.
+    fn create<'a>(
+        &'a self,
+        name: &'a str,
+        size: Option<u64>,
+        _chunk_size: Option<u64>,
+    ) -> BoxFuture<'a, io::Result<Arc<dyn Staging>>> {
+        Box::pin(async move {
+            let (cipher, prefix) = PayloadCipher::new(&self.recipient)?;
+            let payload_offset = prefix.len() as u64;
+            let encrypted_size =
+                size.map(|size| payload_offset + size + TAG_SIZE * segment_count(size));
+            let name = format!("{}.age", name);
+            let inner = self.inner.create(&name, encrypted_size, None).await?;
+            inner.write_at(prefix, 0).await?;
+            Ok(Arc::new(EncryptedStaging {
+                inner,
+                cipher,
+                payload_offset,
+                size,
+                segments: Mutex::new(Segments::default()),
+            }) as Arc<dyn Staging>)
+        })
+    }
+
+    fn list(&self) -> BoxFuture<'_, io::Result<Vec<Object>>> {
+        self.inner.list()
+    }
+
+    fn open<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<BoxStream<'static, io::Result<Bytes>>>> {
+        self.inner.open(path)
+    }
+
+    fn sha256<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<String>> {
+        self.inner.sha256(path)
+    }
+
+    fn available_space(&self) -> BoxFuture<'_, io::Result<Option<u64>>> {
+        self.inner.available_space()
+    }
+
+    fn remove<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.remove(path)
+    }
+
+    fn link<'a>(&'a self, original: &'a Path, link: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.link(original, link)
+    }
+}
+
+/// A segment of plaintext to be sealed, i.e. its index, data and whether it is the last one.
+type Segment = (u64, Vec<u8>, bool);
+
+/// The segments of a file being written.
+#[derive(Default)]
+struct Segments {
+    /// The plaintext of the segments not sealed yet by their indices
+    partial: BTreeMap<u64, SparseBuffer>,
+    /// The indices of the segments sealed, which are never written again
+    sealed: Vec<u8>,
+}
+
+/// A file being encrypted segment by segment into the inner staging object.
+///
+/// Each segment is buffered until filled up and then sealed. If the size of the file is unknown,
+/// it must be written sequentially, and a filled segment is held until the next one is started or
+/// the file is finalized, since the last segment is sealed differently.
+struct EncryptedStaging {
+    inner: Arc<dyn Staging>,
+    cipher: PayloadCipher,
+    /// The size of the header and the nonce preceding the payload
+    payload_offset: u64,
+    size: Option<u64>,
+    segments: Mutex<Segments>,
+}
+
+impl fmt::Debug for EncryptedStaging {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EncryptedStaging")
+            .field("inner", &self.inner)
+            .field("size", &self.size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EncryptedStaging {
+    /// Get the size of the plaintext of the segment at `index`.
+    fn segment_len(&self, index: u64) -> io::Result<u64> {
+        match self.size {
+            Some(size) if index >= segment_count(size) => Err(exceeding()),
+            Some(size) => Ok((size - index * SEGMENT_SIZE).min(SEGMENT_SIZE)),
+            None => Ok(SEGMENT_SIZE),
+        }
+    }
+
+    /// Check the data of `len` bytes written at `offset` against the segments, without changing
+    /// them, so that a refused write leaves them intact.
+    fn check(&self, segments: &Segments, offset: u64, len: u64) -> io::Result<()> {
+        let end = offset + len;
+        if self.size.is_some_and(|size| end > size) {
+            return Err(exceeding());
+        }
+        let first = offset / SEGMENT_SIZE;
+        for index in first..end.div_ceil(SEGMENT_SIZE) {
+            if segments.sealed.get_bit(index as usize) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "The segment has been encrypted already.",
+                ));
+            }
+        }
+        if self.size.is_none() {
+            // only the first segment may be partially written already
+            let pos = offset - first * SEGMENT_SIZE;
+            let previous_partial = pos == 0
+                && first > 0
+                && segments
+                    .partial
+                    .get(&(first - 1))
+                    .is_some_and(|previous| !previous.is_full());
+            let filled = segments
+                .partial
+                .get(&first)
+                .map_or(0, |segment| segment.filled() as u64);
+            if previous_partial || filled < pos {
+                return Err(not_sequential());
+            }
+        }
+        Ok(())
+    }
+
+    /// Buffer the data written at `offset`, return the segments ready to be sealed.
+    fn fill(&self, mut offset: u64, mut data: &[u8]) -> io::Result<Vec<Segment>> {
+        let mut segments = self.segments.lock().unwrap();
+        if data.is_empty() {
+            return Ok(vec![]);
+        }
+        self.check(&segments, offset, data.len() as u64)?;
+        let Segments { partial, sealed } = &mut *segments;
+        let mut ready = vec![];
+        while !data.is_empty() {
+            let index = offset / SEGMENT_SIZE;
+            let pos = (offset - index * SEGMENT_SIZE) as usize;
+            let len = self.segment_len(index)? as usize;
+            if self.size.is_none() && pos == 0 && index > 0 {
+                if let Some(previous) = partial.remove(&(index - 1)) {
+                    sealed.set_bit(index as usize - 1);
+                    ready.push((index - 1, previous.into_inner(), false))
+                }
+            }
+            let segment = partial
+                .entry(index)
+                .or_insert_with(|| SparseBuffer::new(len));
+            let n = data.len().min(len - pos);
+            if segment.write(pos, &data[..n]) && self.size.is_some() {
+                let segment = partial.remove(&index).unwrap();
+                let last = Some(index + 1) == self.size.map(segment_count);
+                sealed.set_bit(index as usize);
+                ready.push((index, segment.into_inner(), last));
+            }
+            offset += n as u64;
+            data = &data[n..];
+        }
+        Ok(ready)
+    }
+
+    /// Seal the segments and write them into the inner staging object, coalescing those adjacent.
+    async fn seal(&self, segments: Vec<Segment>) -> io::Result<()> {
+        let mut pending: Option<(u64, Vec<u8>)> = None;
+        for (index, mut data, last) in segments {
+            self.cipher.seal(index, last, &mut data)?;
+            let at = self.payload_offset + segment_position(index);
+            match pending {
+                Some((pos, ref mut buf)) if pos + buf.len() as u64 == at => {
+                    buf.extend_from_slice(&data)
+                }
+                _ => {
+                    if let Some((pos, buf)) = pending.replace((at, data)) {
+                        self.inner.write_at(buf, pos).await?;
+                    }
+                }
+            }
+        }
+        if let Some((pos, buf)) = pending {
+            self.inner.write_at(buf, pos).await?;
+        }
+        Ok(())
+    }
+}
+
+fn exceeding() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "The data exceeds the size of the file.",
+    )
+}
+
+fn not_sequential() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "The segment is not written sequentially.",
+    )
+}
+
+impl Staging for EncryptedStaging {
+    fn path(&self) -> &Path {
+        self.inner.path()
+    }
+
+    fn write_at(&self, buf: Vec<u8>, offset: u64) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        Box::pin(async move {
+            let ready = self.fill(offset, &buf)?;
+            self.seal(ready).await?;
+            Ok(buf)
+        })
+    }
+
+    fn finalize(&self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            let last = {
+                let mut segments = self.segments.lock().unwrap();
+                let Segments { partial, sealed } = &mut *segments;
+                let incomplete = || {
+                    io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "The file is not written fully.",
+                    )
+                };
+                match self.size {
+                    // the only segment of an empty file is empty
+                    Some(0) | None if partial.is_empty() && sealed.first_unset() == 0 => {
+                        sealed.set_bit(0);
+                        Some((0, vec![], true))
+                    }
+                    None if partial.len() == 1 => {
+                        // written sequentially, so filled up to where it is written
+                        let (index, segment) = partial.pop_first().unwrap();
+                        let filled = segment.filled();
+                        let mut data = segment.into_inner();
+                        data.truncate(filled);
+                        sealed.set_bit(index as usize);
+                        Some((index, data, true))
+                    }
+                    Some(size) if sealed.first_unset() as u64 >= segment_count(size) => None,
+                    _ => return Err(incomplete()),
+                }
+            };
+            self.seal(last.into_iter().collect()).await?;
+            self.inner.finalize().await
+        })
+    }
+
+    fn abort(&self) -> BoxFuture<'_, io::Result<()>> {
+        self.inner.abort()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::LocalStorage;
+    use std::io::Read;
+
+    fn decrypt(identity: &age::x25519::Identity, encrypted: &[u8]) -> Vec<u8> {
+        let decryptor = age::Decryptor::new(encrypted).unwrap();
+        let mut reader = decryptor
+            .decrypt(std::iter::once(identity as &dyn age::Identity))
+            .unwrap();
+        let mut decrypted = vec![];
+        reader.read_to_end(&mut decrypted).unwrap();
+        decrypted
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_staging() {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = age::x25519::Identity::generate();
+        let recipient = identity.to_public().to_string().parse().unwrap();
+        let storage = EncryptedStorage::new(Arc::new(LocalStorage::new(dir.path())), recipient);
+        let original: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        for (size, chunk_size) in [
+            (Some(0), None),
+            (None, None),
+            (Some(300_000), Some(2 * SEGMENT_SIZE)),
+            (Some(2 * SEGMENT_SIZE), Some(SEGMENT_SIZE)),
+            (Some(300_000), Some(100_000)),
+            (Some(300_000), Some(1000)),
+            (None, Some(SEGMENT_SIZE)),
+        ] {
+            let data = &original[..size.unwrap_or(original.len() as u64) as usize];
+            let file = storage.create("a.bin", size, chunk_size).await.unwrap();
+            assert!(file.path().to_string_lossy().ends_with(".age"));
+            let chunk_size = chunk_size.unwrap_or(data.len() as u64).max(1) as usize;
+            // chunks are written in the reverse order, in pieces not aligned with segments
+            let mut chunks: Vec<_> = data.chunks(chunk_size).enumerate().collect();
+            if size.is_some() {
+                chunks.reverse();
+            }
+            for (i, chunk) in chunks {
+                for (j, piece) in chunk.chunks(10_000).enumerate() {
+                    let offset = i * chunk_size + j * 10_000;
+                    file.write_at(piece.to_vec(), offset as u64).await.unwrap();
+                }
+            }
+            file.finalize().await.unwrap();
+            let encrypted = std::fs::read(file.path()).unwrap();
+            assert_eq!(decrypt(&identity, &encrypted), data);
+            std::fs::remove_file(file.path()).unwrap();
+        }
+        let file = storage.create("b.bin", Some(10), None).await.unwrap();
+        file.write_at(vec![0; 5], 0).await.unwrap();
+        assert!(file.write_at(vec![0; 5], 6).await.is_err());
+        assert!(file.finalize().await.is_err());
+
+        // a sealed segment is never sealed again, even with the same data
+        let data = &original[..100_000];
+        let file = storage
+            .create("c.bin", Some(100_000), Some(1000))
+            .await
+            .unwrap();
+        file.write_at(data[..SEGMENT_SIZE as usize].to_vec(), 0)
+            .await
+            .unwrap();
+        let err = file.write_at(data[60_000..70_000].to_vec(), 60_000).await;
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        // overlapping writes into a segment not sealed yet are fine
+        file.write_at(data[65_536..90_000].to_vec(), 65_536)
+            .await
+            .unwrap();
+        file.write_at(data[80_000..].to_vec(), 80_000)
+            .await
+            .unwrap();
+        file.finalize().await.unwrap();
+        let encrypted = std::fs::read(file.path()).unwrap();
+        assert_eq!(decrypt(&identity, &encrypted), data);
+
+        // a write refused for touching a sealed segment leaves the others intact
+        let segment = SEGMENT_SIZE as usize;
+        let data = &original[..3 * segment];
+        let file = storage
+            .create("d.bin", Some(data.len() as u64), None)
+            .await
+            .unwrap();
+        file.write_at(data[segment..2 * segment].to_vec(), segment as u64)
+            .await
+            .unwrap();
+        file.write_at(data[..segment - 10].to_vec(), 0)
+            .await
+            .unwrap();
+        let across = data[segment - 10..segment + 10].to_vec();
+        assert!(file.write_at(across, segment as u64 - 10).await.is_err());
+        file.write_at(data[segment - 10..segment].to_vec(), segment as u64 - 10)
+            .await
+            .unwrap();
+        file.write_at(data[2 * segment..].to_vec(), 2 * segment as u64)
+            .await
+            .unwrap();
+        file.finalize().await.unwrap();
+        let encrypted = std::fs::read(file.path()).unwrap();
+        assert_eq!(decrypt(&identity, &encrypted), data);
+    }
+}
@@ -1,25 +1,39 @@
+use axum::http::StatusCode;
+use thiserror::Error;
+
 use std::io;
 
-#[derive(Debug, Fail)]
+#[derive(Debug, Error)]
 pub enum Error {
-    #[fail(display = "I/O Error: {}", _0)]
-    Io(#[fail(cause)] io::Error),
-    #[fail(display = "The file token is invalid.")]
+    #[error("I/O Error: {0}")]
+    Io(#[source] io::Error),
+    #[error("The file token is invalid.")]
     InvalidFileToken,
-    #[fail(display = "The chunk index is invalid.")]
+    #[error("The file has expired.")]
+    FileExpired,
+    #[error("The chunk index is invalid.")]
     InvalidChunkIndex,
-    #[fail(display = "The chunk has already been written up.")]
+    #[error("The chunk has already been written up.")]
     ChunkAlreadyWritten,
-    #[fail(
-        display = "The file has not been finished, first unfilled chunk: {}.",
-        _0
-    )]
+    #[error("The chunk is being written by another request.")]
+    ChunkBeingWritten,
+    #[error("The file has not been finished, first unfilled chunk: {0}.")]
     FileNotFilledUp(usize),
-    #[fail(
-        display = "Data does not fit in the file or the chunk, current position: {}.",
-        _0
-    )]
+    #[error("Data does not fit in the file or the chunk, current position: {0}.")]
     DataNotFitIn(usize),
+    #[error("The type of the file is not allowed: {0}.")]
+    TypeNotAllowed(String),
+}
+
+impl Error {
+    /// The HTTP status code to respond with, which is 200 OK along with the error in the body
+    /// unless the file is rejected for its type.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Error::TypeNotAllowed(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            _ => StatusCode::OK,
+        }
+    }
 }
 
 impl From<io::Error> for Error {
@@ -0,0 +1,104 @@
+use axum::{
+    extract,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures::{stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use uuid::Uuid as UUID;
+
+use std::{convert::Infallible, path::PathBuf};
+
+use crate::{audit::Uploader, state::State};
+
+/// The capacity of the channel of events, beyond which slow subscribers miss events.
+pub const EVENTS_CAPACITY: usize = 1024;
+
+/// An event of the lifecycle of an upload, broadcast by `State`.
+///
+/// Full uploads are assigned a token as well, but only emit `Start` and then `Finish` or
+/// `Cancel`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum UploadEvent {
+    Start {
+        token: UUID,
+        name: String,
+        /// The size declared by the uploader, if any
+        size: Option<u64>,
+        #[serde(flatten)]
+        uploader: Uploader,
+        /// The bytes received so far, which is non-zero only in the snapshot sent to new
+        /// subscribers
+        received: u64,
+    },
+    Chunk {
+        token: UUID,
+        index: usize,
+        /// The bytes received by the token so far
+        received: u64,
+    },
+    Finish {
+        token: UUID,
+        size: u64,
+        path: PathBuf,
+    },
+    Expire {
+        token: UUID,
+    },
+    Cancel {
+        token: UUID,
+        error: String,
+    },
+}
+
+impl UploadEvent {
+    /// The name of the event as sent in the `event` field of SSE.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            UploadEvent::Start { .. } => "start",
+            UploadEvent::Chunk { .. } => "chunk",
+            UploadEvent::Finish { .. } => "finish",
+            UploadEvent::Expire { .. } => "expire",
+            UploadEvent::Cancel { .. } => "cancel",
+        }
+    }
+}
+
+/// Stream upload events as Server-Sent Events, starting with `start` events of the pending files.
+pub async fn handle_events(
+    extract::State(state): extract::State<State>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    // subscribe before taking the snapshot so that no events are missed in between
+    let receiver = state.subscribe();
+    let snapshot = state
+        .pending_uploads()
+        .await
+        .into_iter()
+        .map(|upload| UploadEvent::Start {
+            token: upload.token,
+            name: upload.name,
+            size: Some(upload.size),
+            uploader: upload.uploader,
+            received: upload.received,
+        });
+    let live = BroadcastStream::new(receiver).filter_map(|event| async move {
+        match event {
+            Ok(event) => Some(event),
+            Err(BroadcastStreamRecvError::Lagged(count)) => {
+                warn!(
+                    "An events subscriber lagged behind, {} events missed.",
+                    count
+                );
+                None
+            }
+        }
+    });
+    let events = stream::iter(snapshot).chain(live).map(|event| {
+        Ok(Event::default()
+            .event(event.kind())
+            .json_data(&event)
+            .expect("Serialize event"))
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
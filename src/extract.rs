@@ -0,0 +1,293 @@
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+
+use std::{
+    fs::{create_dir, create_dir_all, remove_dir_all, File},
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::storage::candidate_names;
+
+/// The format of archives to extract once uploaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ArchiveFormat {
+    #[serde(rename = "zip")]
+    Zip,
+    #[serde(rename = "tar")]
+    Tar,
+    #[serde(rename = "tar.gz")]
+    TarGz,
+}
+
+impl ArchiveFormat {
+    /// The extensions of the archives in the format, matched case-insensitively.
+    fn extensions(self) -> &'static [&'static str] {
+        match self {
+            ArchiveFormat::Zip => &[".zip"],
+            ArchiveFormat::Tar => &[".tar"],
+            ArchiveFormat::TarGz => &[".tar.gz", ".tgz"],
+        }
+    }
+
+    /// Detect the format of the archive named `name` among the formats by its extension, return
+    /// the format along with the name stripped of the extension.
+    pub fn detect(name: &str, formats: &[ArchiveFormat]) -> Option<(ArchiveFormat, String)> {
+        formats.iter().find_map(|&format| {
+            format.extensions().iter().find_map(|ext| {
+                let stem_len = name.len().checked_sub(ext.len()).filter(|&len| len > 0)?;
+                let suffix = name.get(stem_len..)?;
+                suffix
+                    .eq_ignore_ascii_case(ext)
+                    .then(|| (format, name[..stem_len].to_owned()))
+            })
+        })
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zip" => Ok(ArchiveFormat::Zip),
+            "tar" => Ok(ArchiveFormat::Tar),
+            "tar.gz" => Ok(ArchiveFormat::TarGz),
+            _ => Err(format!("Unknown archive format {:?}", s)),
+        }
+    }
+}
+
+/// The limits of an extraction, beyond which it fails, to guard against archive bombs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtractLimits {
+    /// The total size (in bytes) of the files extracted
+    pub max_size: u64,
+    /// The number of entries, including directories
+    pub max_entries: usize,
+}
+
+/// The outcome of an extraction.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Extracted {
+    /// The name of the directory extracted into, which is beside the archive
+    pub dir: String,
+    /// The paths of the files extracted, relative to the directory and separated by `/`
+    pub entries: Vec<String>,
+}
+
+/// Asynchronously create a new directory beside the archive at `path` named `dir_name` to extract
+/// it into, suffixing the name if it is taken.
+pub async fn create_extract_dir(path: PathBuf, dir_name: String) -> io::Result<PathBuf> {
+    spawn_blocking(move || {
+        let parent = path.parent().unwrap_or_else(|| Path::new("."));
+        create_new_dir(parent, &dir_name)
+    })
+    .await?
+}
+
+/// Asynchronously extract the archive at `path` into the directory `dir` created by
+/// `create_extract_dir`.
+///
+/// Symbolic links and other special entries are skipped. On failure, e.g. if an entry escapes the
+/// directory or the limits are exceeded, the directory is removed along with what is extracted.
+pub async fn extract(
+    path: PathBuf,
+    format: ArchiveFormat,
+    dir: PathBuf,
+    limits: ExtractLimits,
+) -> io::Result<Extracted> {
+    spawn_blocking(move || {
+        let mut extractor = Extractor {
+            dir: &dir,
+            limits,
+            size: 0,
+            count: 0,
+            entries: vec![],
+        };
+        let file = File::open(&path)?;
+        let result = match format {
+            ArchiveFormat::Zip => extractor.zip(file),
+            ArchiveFormat::Tar => extractor.tar(file),
+            ArchiveFormat::TarGz => extractor.tar(GzDecoder::new(file)),
+        };
+        match result {
+            Ok(()) => Ok(Extracted {
+                dir: dir.file_name().unwrap_or_default().to_string_lossy().into(),
+                entries: extractor.entries,
+            }),
+            Err(e) => {
+                if let Err(e) = remove_dir_all(&dir) {
+                    warn!("Failed to remove {:?} after failed to extract: {}", dir, e);
+                }
+                Err(e)
+            }
+        }
+    })
+    .await?
+}
+
+/// Create a new directory named `name` under `parent`, suffixing the name if it is taken.
+fn create_new_dir(parent: &Path, name: &str) -> io::Result<PathBuf> {
+    for name in candidate_names(name) {
+        let path = parent.join(name);
+        match create_dir(&path) {
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(e),
+            Ok(()) => return Ok(path),
+        }
+    }
+    unreachable!("Candidate names are endless")
+}
+
+/// Get the relative path of an entry named `name` if it stays within the directory, i.e. has no
+/// root, prefix or parent components, `None` otherwise.
+fn enclosed_path(name: &Path) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => (),
+            Component::RootDir | Component::Prefix(_) | Component::ParentDir => return None,
+        }
+    }
+    Some(path)
+}
+
+struct Extractor<'a> {
+    dir: &'a Path,
+    limits: ExtractLimits,
+    /// The total size of the files extracted so far
+    size: u64,
+    /// The number of entries extracted so far
+    count: usize,
+    entries: Vec<String>,
+}
+
+impl Extractor<'_> {
+    /// Check the name of a new entry, return the path to extract it to.
+    fn enter(&mut self, name: &Path) -> io::Result<PathBuf> {
+        self.count += 1;
+        if self.count > self.limits.max_entries {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "The archive has more than {} entries.",
+                    self.limits.max_entries
+                ),
+            ));
+        }
+        let relative = enclosed_path(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("The entry {:?} escapes the directory.", name),
+            )
+        })?;
+        Ok(self.dir.join(relative))
+    }
+
+    fn extract_dir(&mut self, name: &Path) -> io::Result<()> {
+        let path = self.enter(name)?;
+        create_dir_all(path)
+    }
+
+    /// Extract a file, counting the data actually read instead of the size claimed by the entry.
+    fn extract_file(&mut self, name: &Path, data: &mut impl Read) -> io::Result<()> {
+        let path = self.enter(name)?;
+        if path == self.dir {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("The entry {:?} is not a file.", name),
+            ));
+        }
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        let remaining = self.limits.max_size - self.size;
+        let mut file = File::create(&path)?;
+        let written = io::copy(&mut data.take(remaining.saturating_add(1)), &mut file)?;
+        if written > remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "The archive expands to more than {} bytes.",
+                    self.limits.max_size
+                ),
+            ));
+        }
+        self.size += written;
+        let relative = path.strip_prefix(self.dir).unwrap_or(&path);
+        self.entries.push(
+            relative
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/"),
+        );
+        Ok(())
+    }
+
+    fn zip(&mut self, file: File) -> io::Result<()> {
+        let mut archive = zip::ZipArchive::new(file)?;
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i)?;
+            let name = PathBuf::from(entry.name());
+            if entry.is_dir() {
+                self.extract_dir(&name)?;
+            } else if entry.is_symlink() {
+                debug!("Skipped the symbolic link {:?} in the archive", name);
+            } else {
+                self.extract_file(&name, &mut entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn tar(&mut self, data: impl Read) -> io::Result<()> {
+        let mut archive = tar::Archive::new(data);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let name = entry.path()?.into_owned();
+            match entry.header().entry_type() {
+                tar::EntryType::Directory => self.extract_dir(&name)?,
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    self.extract_file(&name, &mut entry)?
+                }
+                other => debug!("Skipped the entry {:?} of type {:?}", name, other),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        let formats = [ArchiveFormat::Zip, ArchiveFormat::TarGz];
+        assert_eq!(
+            ArchiveFormat::detect("a.ZIP", &formats),
+            Some((ArchiveFormat::Zip, "a".to_owned()))
+        );
+        assert_eq!(
+            ArchiveFormat::detect("b.c.tgz", &formats),
+            Some((ArchiveFormat::TarGz, "b.c".to_owned()))
+        );
+        assert_eq!(ArchiveFormat::detect("a.tar", &formats), None);
+        assert_eq!(ArchiveFormat::detect(".zip", &formats), None);
+    }
+
+    #[test]
+    fn test_enclosed_path() {
+        assert_eq!(
+            enclosed_path(Path::new("./a/b.txt")),
+            Some(PathBuf::from("a/b.txt"))
+        );
+        assert_eq!(enclosed_path(Path::new("a/../../b.txt")), None);
+        assert_eq!(enclosed_path(Path::new("/etc/passwd")), None);
+    }
+}
@@ -0,0 +1,96 @@
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+
+use std::io;
+
+/// The number of the first bytes of a file to sniff the type of its content from.
+pub const SNIFF_SIZE: usize = 8192;
+
+/// The MIME type of content not recognized by its magic bytes.
+pub const UNKNOWN_TYPE: &str = "application/octet-stream";
+
+/// Get the MIME type of the content by the magic bytes at its start, `UNKNOWN_TYPE` if not
+/// recognized.
+pub fn sniff_type(head: &[u8]) -> &'static str {
+    infer::get(head).map_or(UNKNOWN_TYPE, |kind| kind.mime_type())
+}
+
+/// Check whether the MIME type is allowed by the patterns allowed and denied, e.g.
+/// `application/pdf` or `image/*`, which are matched case-insensitively.
+///
+/// All types are allowed if `allow` is empty, while `deny` takes precedence.
+pub fn is_type_allowed(mime: &str, allow: &[String], deny: &[String]) -> bool {
+    let matches = |pattern: &String| match pattern.strip_suffix("/*") {
+        Some(top) => mime
+            .split_once('/')
+            .is_some_and(|(mime_top, _)| mime_top.eq_ignore_ascii_case(top)),
+        None => pattern.eq_ignore_ascii_case(mime),
+    };
+    (allow.is_empty() || allow.iter().any(matches)) && !deny.iter().any(matches)
+}
+
+/// A piece of data, either read ahead to be sniffed or passed through as received.
+pub enum Piece<T> {
+    Head(Vec<u8>),
+    Rest(T),
+}
+
+impl<T: AsRef<[u8]>> AsRef<[u8]> for Piece<T> {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            Piece::Head(head) => head,
+            Piece::Rest(rest) => rest.as_ref(),
+        }
+    }
+}
+
+/// Sniff the MIME type of the data by reading ahead `SNIFF_SIZE` bytes unless it ends earlier,
+/// return the type if `enabled` along with the data intact.
+pub async fn sniff<T: AsRef<[u8]>>(
+    mut data: impl Stream<Item = io::Result<T>> + Unpin,
+    enabled: bool,
+) -> io::Result<(
+    Option<&'static str>,
+    impl Stream<Item = io::Result<Piece<T>>> + Unpin,
+)> {
+    let mut head = vec![];
+    while enabled && head.len() < SNIFF_SIZE {
+        match data.next().await {
+            Some(bytes) => head.extend_from_slice(bytes?.as_ref()),
+            None => break,
+        }
+    }
+    let mime = enabled.then(|| sniff_type(&head));
+    let head = (!head.is_empty()).then_some(Ok(Piece::Head(head)));
+    Ok((mime, stream::iter(head).chain(data.map_ok(Piece::Rest))))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_type_allowed() {
+        let rules = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let allow = rules(&["application/pdf", "image/*"]);
+        assert!(is_type_allowed("application/pdf", &allow, &[]));
+        assert!(is_type_allowed("image/png", &allow, &[]));
+        assert!(!is_type_allowed("application/zip", &allow, &[]));
+        assert!(!is_type_allowed(
+            "image/svg+xml",
+            &allow,
+            &rules(&["image/SVG+XML"])
+        ));
+        assert!(is_type_allowed(
+            UNKNOWN_TYPE,
+            &[],
+            &rules(&["application/x-*"])
+        ));
+    }
+
+    #[test]
+    fn test_sniff_type() {
+        assert_eq!(sniff_type(b"%PDF-1.7\n"), "application/pdf");
+        assert_eq!(sniff_type(b"\x89PNG\r\n\x1a\n"), "image/png");
+        assert_eq!(sniff_type(b"plain text"), UNKNOWN_TYPE);
+    }
+}
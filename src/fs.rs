@@ -0,0 +1,127 @@
+use sha2::{Digest, Sha256};
+use tokio::task::spawn_blocking;
+use uuid::Uuid as UUID;
+
+use std::{
+    fs::File as StdFile,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::storage::Object;
+
+/// The mark in the names of the temporary files of intray, which are never taken as received files.
+pub const TEMP_MARK: &str = ".intray-";
+
+/// Write the whole buffer at the position `offset` of the file, without altering the cursor of the
+/// file (i.e. `pwrite`), so that disjoint regions of one file can be written concurrently.
+#[cfg(unix)]
+pub fn write_all_at(file: &StdFile, buf: &[u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(buf, offset)
+}
+
+/// Write the whole buffer at the position `offset` of the file.
+///
+/// Note that on Windows, the cursor of the file is altered anyway.
+#[cfg(windows)]
+pub fn write_all_at(file: &StdFile, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::{io::ErrorKind, os::windows::fs::FileExt};
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    ErrorKind::WriteZero,
+                    "failed to write whole buffer",
+                ))
+            }
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Asynchronously write the whole buffer at the position `offset` of the file by running
+/// `write_all_at` on the blocking threads, return the buffer back for reuse.
+pub async fn write_at(file: Arc<StdFile>, buf: Vec<u8>, offset: u64) -> io::Result<Vec<u8>> {
+    spawn_blocking(move || write_all_at(&file, &buf, offset).map(|_| buf)).await?
+}
+
+/// Asynchronously sync the data of the file to disk.
+pub async fn sync_data(file: Arc<StdFile>) -> io::Result<()> {
+    spawn_blocking(move || file.sync_data()).await?
+}
+
+/// Asynchronously get the space available to the current user on the file system containing the
+/// path.
+pub async fn available_space(path: PathBuf) -> io::Result<u64> {
+    spawn_blocking(move || fs4::available_space(path)).await?
+}
+
+/// Asynchronously list the regular files under the directory, recursively.
+pub async fn list_files(path: PathBuf) -> io::Result<Vec<Object>> {
+    fn walk(path: &Path, files: &mut Vec<Object>) -> io::Result<()> {
+        for entry in std::fs::read_dir(path)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                walk(&entry.path(), files)?;
+            } else if file_type.is_file() {
+                let metadata = entry.metadata()?;
+                files.push(Object {
+                    path: entry.path(),
+                    size: metadata.len(),
+                    modified: metadata.modified().ok(),
+                });
+            }
+        }
+        Ok(())
+    }
+    spawn_blocking(move || {
+        let mut files = vec![];
+        walk(&path, &mut files).map(|_| files)
+    })
+    .await?
+}
+
+/// Asynchronously remove the directories containing the path up to `base` (exclusively) as long
+/// as they are empty.
+pub async fn remove_empty_dirs(path: &Path, base: &Path) {
+    for dir in path.ancestors().skip(1) {
+        if !dir.starts_with(base) || dir == base || tokio::fs::remove_dir(dir).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Asynchronously replace the file at `link` with a hard link to `original`.
+///
+/// The link is created aside under a temporary name and then renamed over the file, so that the
+/// file is never missing.
+pub async fn replace_with_link(original: PathBuf, link: PathBuf) -> io::Result<()> {
+    spawn_blocking(move || {
+        let mut temp = link.clone().into_os_string();
+        temp.push(format!("{}link-{}", TEMP_MARK, UUID::new_v4().simple()));
+        std::fs::hard_link(&original, &temp)?;
+        std::fs::rename(&temp, &link).inspect_err(|_| {
+            let _ = std::fs::remove_file(&temp);
+        })
+    })
+    .await?
+}
+
+/// Asynchronously compute the hex-encoded SHA-256 digest of the file.
+pub async fn sha256_file(path: PathBuf) -> io::Result<String> {
+    spawn_blocking(move || {
+        let mut hasher = Sha256::new();
+        io::copy(&mut StdFile::open(path)?, &mut hasher)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    })
+    .await?
+}
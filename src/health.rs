@@ -0,0 +1,94 @@
+use axum::{
+    extract::{self, Json},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid as UUID;
+
+use std::io;
+
+use crate::{fs::TEMP_MARK, state::State};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseHealth {
+    pub ok: bool,
+}
+
+/// Liveness probe, which succeeds as long as the process is able to serve requests.
+pub async fn handle_healthz() -> Json<ResponseHealth> {
+    Json(ResponseHealth { ok: true })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseReadiness {
+    pub ok: bool,
+    pub checks: Vec<Check>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Check {
+    pub name: String,
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+impl Check {
+    fn new(name: &str, result: Result<(), String>) -> Self {
+        Check {
+            name: name.to_owned(),
+            ok: result.is_ok(),
+            error: result.err(),
+        }
+    }
+}
+
+/// Readiness probe, which fails with HTTP 503 if the storage is not writable, the quota is
+/// exceeded or pending files are no longer being expired.
+///
+/// The usage compared with the quota is cached, see `State::dir_usage`.
+pub async fn handle_readyz(
+    extract::State(state): extract::State<State>,
+) -> (StatusCode, Json<ResponseReadiness>) {
+    let mut checks = vec![Check::new(
+        "storage",
+        check_writable(&state)
+            .await
+            .map_err(|e| format!("The storage is not writable: {}", e)),
+    )];
+    if let Some(quota) = state.config().quota {
+        let result = match state.dir_usage().await {
+            Ok(usage) if usage < quota => Ok(()),
+            Ok(usage) => Err(format!(
+                "The quota is exceeded: {} of {} bytes.",
+                usage, quota
+            )),
+            Err(e) => Err(format!("Failed to get the usage of the directory: {}", e)),
+        };
+        checks.push(Check::new("quota", result));
+    }
+    checks.push(Check::new(
+        "expiration",
+        if state.is_expiring() {
+            Ok(())
+        } else {
+            Err(String::from("The expiration task is not running."))
+        },
+    ));
+    let ok = checks.iter().all(|check| check.ok);
+    if !ok {
+        warn!("Not ready: {:?}", checks);
+    }
+    let status = if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(ResponseReadiness { ok, checks }))
+}
+
+/// Check that the storage is writable by staging a probe object in it, which is aborted then.
+async fn check_writable(state: &State) -> io::Result<()> {
+    let name = format!("{}probe-{}", TEMP_MARK, UUID::new_v4().simple());
+    let probe = state.storage().create(&name, Some(0), None).await?;
+    probe.abort().await
+}
@@ -0,0 +1,378 @@
+use axum::{
+    extract::{self, Json, Query},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use crate::{audit::Uploader, state::State};
+
+/// The default number of entries per page.
+const DEFAULT_LIMIT: usize = 50;
+/// The maximum number of entries per page.
+const MAX_LIMIT: usize = 1000;
+
+/// A completed upload, stored as a line of JSON.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// The time of completion in RFC 3339
+    pub time: String,
+    #[serde(flatten)]
+    pub uploader: Uploader,
+    /// The file name provided by the uploader
+    pub name: String,
+    pub size: u64,
+    /// The size of the stored file if compressed
+    pub compressed_size: Option<u64>,
+    /// Hex-encoded SHA-256 digest of the file
+    pub sha256: Option<String>,
+    /// The path of the stored file
+    pub path: PathBuf,
+}
+
+/// Filters and pagination of a history query, matched against entries from the newest.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct HistoryQuery {
+    /// The user who uploaded the file
+    pub user: Option<String>,
+    /// The inclusive start of the period in RFC 3339, e.g. `2020-01-01T00:00:00Z`
+    pub since: Option<String>,
+    /// The exclusive end of the period in RFC 3339
+    pub until: Option<String>,
+    /// Wildcard pattern of the file name, where `*` matches any sequence and `?` matches any
+    /// character
+    pub name: Option<String>,
+    /// The number of matched entries to skip
+    pub offset: Option<usize>,
+    /// The maximum number of entries to return
+    pub limit: Option<usize>,
+}
+
+/// A persistent record of completed uploads, appended as JSON lines and queried by scanning.
+#[derive(Debug)]
+pub struct HistoryStore {
+    path: PathBuf,
+    /// The file opened lazily for appending
+    file: Mutex<Option<File>>,
+}
+
+impl HistoryStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        HistoryStore {
+            path: path.into(),
+            file: Mutex::new(None),
+        }
+    }
+
+    /// Append the entry to the store synchronously.
+    pub fn append(&self, entry: &HistoryEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        if file.is_none() {
+            *file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            );
+        }
+        file.as_mut().unwrap().write_all(&line)
+    }
+
+    /// Query the store synchronously, return the page of matched entries requested from the
+    /// newest, and whether more entries are matched beyond it.
+    ///
+    /// The store is read backwards and only until the page is filled.
+    pub fn query(&self, query: &HistoryQuery) -> io::Result<(Vec<HistoryEntry>, bool)> {
+        let parse_time = |time: &Option<String>| {
+            time.as_deref()
+                .map(humantime::parse_rfc3339_weak)
+                .transpose()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+        };
+        let (since, until) = (parse_time(&query.since)?, parse_time(&query.until)?);
+        let in_period = |time: SystemTime| {
+            since.is_none_or(|since| time >= since) && until.is_none_or(|until| time < until)
+        };
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok((vec![], false)),
+            Err(e) => return Err(e),
+        };
+        let mut skip = query.offset.unwrap_or(0);
+        let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+        let mut page = vec![];
+        for line in ReverseLines::new(file)? {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let entry: HistoryEntry = match serde_json::from_slice(&line) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    // e.g. a line partially written when crashing
+                    warn!(
+                        "Skipping malformed history entry {:?}: {}",
+                        String::from_utf8_lossy(&line),
+                        e
+                    );
+                    continue;
+                }
+            };
+            if query
+                .user
+                .as_ref()
+                .is_some_and(|user| entry.uploader.user.as_ref() != Some(user))
+            {
+                continue;
+            }
+            if query
+                .name
+                .as_ref()
+                .is_some_and(|pattern| !wildcard_match(pattern, &entry.name))
+            {
+                continue;
+            }
+            if since.is_some() || until.is_some() {
+                match humantime::parse_rfc3339_weak(&entry.time) {
+                    Ok(time) if in_period(time) => (),
+                    _ => continue,
+                }
+            }
+            if skip > 0 {
+                skip -= 1;
+            } else if page.len() < limit {
+                page.push(entry);
+            } else {
+                return Ok((page, true));
+            }
+        }
+        Ok((page, false))
+    }
+}
+
+/// The size of the blocks in which a file is read backwards.
+const REVERSE_BLOCK_SIZE: u64 = 64 * 1024;
+
+/// The lines of a file read backwards from the end, without their line feeds.
+struct ReverseLines {
+    file: File,
+    /// The position up to which the file has been read
+    pos: u64,
+    /// The data read but not returned as lines yet, which ends at the end of a line
+    buf: Vec<u8>,
+}
+
+impl ReverseLines {
+    fn new(mut file: File) -> io::Result<Self> {
+        let pos = file.seek(SeekFrom::End(0))?;
+        Ok(ReverseLines {
+            file,
+            pos,
+            buf: vec![],
+        })
+    }
+
+    fn read_block(&mut self) -> io::Result<()> {
+        let n = self.pos.min(REVERSE_BLOCK_SIZE);
+        self.pos -= n;
+        self.file.seek(SeekFrom::Start(self.pos))?;
+        let mut block = vec![0; n as usize];
+        self.file.read_exact(&mut block)?;
+        block.append(&mut self.buf);
+        self.buf = block;
+        Ok(())
+    }
+}
+
+impl Iterator for ReverseLines {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(i) = self.buf.iter().rposition(|&b| b == b'\n') {
+                let line = self.buf.split_off(i + 1);
+                self.buf.truncate(i);
+                return Some(Ok(line));
+            }
+            if self.pos == 0 {
+                return (!self.buf.is_empty()).then(|| Ok(std::mem::take(&mut self.buf)));
+            }
+            if let Err(e) = self.read_block() {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// Match the text against the pattern, where `*` matches any sequence and `?` matches any
+/// character.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    // the position of the last `*` in the pattern and the text position it is matched up to
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseHistory {
+    pub ok: bool,
+    /// Whether more entries are matched beyond the page
+    pub more: bool,
+    pub entries: Vec<HistoryEntry>,
+    pub error: Option<String>,
+}
+
+pub async fn handle_history(
+    extract::State(state): extract::State<State>,
+    Query(query): Query<HistoryQuery>,
+) -> (StatusCode, Json<ResponseHistory>) {
+    match state.query_history(query).await {
+        Ok((entries, more)) => (
+            StatusCode::OK,
+            Json(ResponseHistory {
+                ok: true,
+                more,
+                entries,
+                error: None,
+            }),
+        ),
+        Err(e) => (
+            if e.kind() == io::ErrorKind::InvalidInput {
+                StatusCode::BAD_REQUEST
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            },
+            Json(ResponseHistory {
+                ok: false,
+                more: false,
+                entries: vec![],
+                error: Some(e.to_string()),
+            }),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{wildcard_match, HistoryEntry, HistoryQuery, HistoryStore, ReverseLines};
+    use crate::audit::Uploader;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("*.pdf", "report.pdf"));
+        assert!(wildcard_match("r?port*", "report.pdf"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("a*b*c", "aXbYbZc"));
+        assert!(!wildcard_match("*.pdf", "report.pdf.exe"));
+        assert!(!wildcard_match("a?", "a"));
+    }
+
+    #[test]
+    fn test_reverse_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lines");
+        // lines spanning the blocks
+        let lines: Vec<Vec<u8>> = (b'a'..=b'e').map(|c| vec![c; 50_000]).collect();
+        std::fs::write(&path, lines.join(&b'\n')).unwrap();
+        let read: Vec<_> = ReverseLines::new(std::fs::File::open(&path).unwrap())
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert!(read.into_iter().eq(lines.into_iter().rev()));
+    }
+
+    #[test]
+    fn test_query() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = HistoryStore::new(dir.path().join("history.jsonl"));
+        let entry = |time: &str, user: &str, name: &str| HistoryEntry {
+            time: time.to_owned(),
+            uploader: Uploader {
+                user: Some(user.to_owned()),
+                client: None,
+            },
+            name: name.to_owned(),
+            size: 1,
+            compressed_size: None,
+            sha256: None,
+            path: PathBuf::from(name),
+        };
+        assert!(store.query(&HistoryQuery::default()).unwrap().0.is_empty());
+        store
+            .append(&entry("2020-01-01T00:00:00Z", "a", "1.pdf"))
+            .unwrap();
+        store
+            .append(&entry("2020-01-02T00:00:00Z", "b", "2.txt"))
+            .unwrap();
+        store
+            .append(&entry("2020-01-03T00:00:00Z", "a", "3.pdf"))
+            .unwrap();
+
+        let (page, more) = store
+            .query(&HistoryQuery {
+                user: Some("a".into()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!((page.len(), more), (2, false));
+        // from the newest
+        assert_eq!(page[0].name, "3.pdf");
+        let (page, more) = store
+            .query(&HistoryQuery {
+                since: Some("2020-01-02T00:00:00Z".into()),
+                name: Some("*.pdf".into()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!((page.len(), more), (1, false));
+        let (page, more) = store
+            .query(&HistoryQuery {
+                offset: Some(1),
+                limit: Some(1),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!((page[0].name.as_str(), more), ("2.txt", true));
+        let (page, more) = store
+            .query(&HistoryQuery {
+                offset: Some(2),
+                limit: Some(1),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!((page[0].name.as_str(), more), ("1.pdf", false));
+        assert!(store
+            .query(&HistoryQuery {
+                until: Some("yesterday".into()),
+                ..Default::default()
+            })
+            .is_err());
+    }
+}
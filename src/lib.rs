@@ -0,0 +1,61 @@
+//! InTray 📥 is a lightweight and simplistic tool with a clean Web UI to facilitate collecting
+//! files over HTTP.
+//!
+//! Besides the `intray` CLI, the upload engine can be embedded into other services:
+//!
+//! ```no_run
+//! # async fn run() -> std::io::Result<()> {
+//! let server = intray::Server::builder()
+//!     .dir("/srv/inbox")
+//!     .credentials(vec!["user:passwd"])
+//!     .build();
+//! let listener = tokio::net::TcpListener::bind("127.0.0.1:8080").await?;
+//! server.serve(listener).await
+//! # }
+//! ```
+#[macro_use]
+extern crate rust_embed;
+#[macro_use]
+extern crate log;
+
+pub mod admin;
+mod age_v1;
+pub mod api;
+pub mod audit;
+pub mod auth;
+mod bitmap;
+mod buffer;
+pub mod compress;
+pub mod config;
+pub mod dedup;
+pub mod encrypt;
+pub mod error;
+pub mod events;
+pub mod extract;
+pub mod filter;
+mod fs;
+pub mod health;
+pub mod history;
+pub mod listen;
+mod logger;
+pub mod metrics;
+pub mod proxy;
+pub mod retention;
+pub mod s3;
+pub mod server;
+pub mod state;
+pub mod storage;
+#[cfg(unix)]
+pub mod systemd;
+mod web;
+
+pub use crate::{
+    config::Config,
+    error::Error,
+    listen::{ListenAddr, Listener},
+    metrics::Metrics,
+    proxy::IpNet,
+    server::{Server, ServerBuilder},
+    state::State,
+    storage::{LocalStorage, Storage},
+};
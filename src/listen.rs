@@ -0,0 +1,110 @@
+use serde::Deserialize;
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+use std::{convert::TryFrom, fmt, io, net::SocketAddr, path::PathBuf, str::FromStr};
+
+/// An address to listen on, either `IP:PORT` or `unix:/PATH/TO/SOCKET`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if !cfg!(unix) {
+                return Err(String::from(
+                    "Unix domain sockets are not supported on this platform",
+                ));
+            }
+            if path.is_empty() {
+                return Err(String::from("The path of the Unix domain socket is empty"));
+            }
+            Ok(ListenAddr::Unix(PathBuf::from(path)))
+        } else {
+            s.parse()
+                .map(ListenAddr::Tcp)
+                .map_err(|e| format!("Invalid address {:?}: {}", s, e))
+        }
+    }
+}
+
+impl TryFrom<String> for ListenAddr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl ListenAddr {
+    /// Bind a listener on the address.
+    ///
+    /// For Unix domain sockets, a stale socket file left over is removed before binding and the
+    /// permissions of the new one are set to `unix_socket_mode` if any.
+    pub async fn bind(&self, unix_socket_mode: Option<u32>) -> io::Result<Listener> {
+        match self {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                use std::{
+                    fs::{remove_file, set_permissions, symlink_metadata, Permissions},
+                    os::unix::fs::{FileTypeExt, PermissionsExt},
+                };
+                if symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+                    debug!("Removing the stale socket file: {:?}", path);
+                    remove_file(path)?;
+                }
+                let listener = UnixListener::bind(path)?;
+                if let Some(mode) = unix_socket_mode {
+                    set_permissions(path, Permissions::from_mode(mode))?;
+                }
+                Ok(Listener::Unix(listener))
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => {
+                let _ = unix_socket_mode;
+                Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Unix domain sockets are not supported on this platform",
+                ))
+            }
+        }
+    }
+}
+
+/// A bound listener to serve on.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Listener::Unix(listener)
+    }
+}
@@ -0,0 +1,46 @@
+use axum::{extract::Request, middleware::Next, response::Response};
+use log::Level;
+
+use std::time::Instant;
+
+use crate::proxy::ClientAddr;
+
+/// Middleware logging the client address, method, path, status and elapsed time of every request,
+/// to be used with `axum::middleware::from_fn`.
+///
+/// The fields are also attached to the log record as key-values for structured logging.
+pub async fn log_request(req: Request, next: Next) -> Response {
+    let client = req
+        .extensions()
+        .get::<ClientAddr>()
+        .copied()
+        .unwrap_or(ClientAddr(None));
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let start = Instant::now();
+    let res = next.run(req).await;
+    let status = res.status();
+    let elapsed = start.elapsed();
+    let level = if status.is_server_error() {
+        Level::Error
+    } else if status.is_client_error() {
+        Level::Warn
+    } else {
+        Level::Info
+    };
+    log!(
+        level,
+        client:% = client,
+        method:% = method,
+        path = path.as_str(),
+        status = status.as_u16(),
+        elapsed_ms = elapsed.as_secs_f64() * 1000.0;
+        "{} {} {} {} {:?}",
+        client,
+        method,
+        path,
+        status.as_u16(),
+        elapsed
+    );
+    res
+}
@@ -1,74 +1,212 @@
-extern crate futures;
-extern crate tide;
-extern crate tokio;
-#[macro_use]
-extern crate rust_embed;
-extern crate bytes;
-extern crate mime_guess;
-extern crate uuid;
 #[macro_use]
 extern crate log;
-extern crate env_logger;
-extern crate structopt;
-#[macro_use]
-extern crate lazy_static;
-#[macro_use]
-extern crate failure;
-extern crate base64;
 
-use futures::{compat::Executor01CompatExt, future::FutureExt, task::SpawnExt};
-use tide::{middleware::RequestLogger, App, Context, EndpointResult};
-use tokio::{prelude::Future as Future01, runtime::Runtime};
+use env_logger::fmt::{hidden_kv_format, Formatter};
+use intray::{s3::S3Storage, ListenAddr, Listener, Server};
+use log::{
+    kv::{self, Key, VisitSource},
+    Record,
+};
+use serde_json::{Map, Value};
 
-use std::env;
+use std::{
+    env,
+    fs::remove_file,
+    io::{self, Write},
+    path::PathBuf,
+    process,
+    sync::Arc,
+};
 
-mod api;
-mod auth;
-mod bitmap;
-mod error;
 mod opt;
-mod state;
-mod web;
 
-use crate::{api::*, auth::HTTPBasicAuth, opt::OPT, state::State, web::serve_embedded_file};
+use crate::opt::{LogFormat, Opt};
 
-async fn handle_index(_ctx: Context<State>) -> EndpointResult {
-    Ok(serve_embedded_file("/index.html"))
+// The environment is only modified here, before the runtime spawns its worker threads.
+fn main() {
+    if env::var("RUST_LOG").is_err() {
+        env::set_var("RUST_LOG", "intray=info");
+    }
+    #[cfg(unix)]
+    let listen_env = intray::systemd::ListenEnv::take();
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Build the tokio runtime")
+        .block_on(run(
+            #[cfg(unix)]
+            listen_env,
+        ));
 }
 
-async fn handle_assets(ctx: Context<State>) -> EndpointResult {
-    let path = ctx.uri().path();
-    Ok(serve_embedded_file(&path))
+async fn run(#[cfg(unix)] listen_env: intray::systemd::ListenEnv) {
+    let opt = Opt::load();
+    init_logger(opt.as_ref().map_or(LogFormat::Text, Opt::log_format));
+    // validated only after the logger is set up, so that the warnings are logged
+    let opt = opt.and_then(|opt| opt.validate().map(|_| opt));
+    let opt = opt.unwrap_or_else(|problems| {
+        for problem in problems {
+            error!("{}", problem);
+        }
+        process::exit(1);
+    });
+
+    let server = match opt.s3_config() {
+        Some(s3) => {
+            info!(
+                "Storing files into the S3 bucket {} at {}.",
+                s3.bucket, s3.endpoint
+            );
+            let storage = S3Storage::new(s3).expect("Construct S3 storage");
+            Server::with_storage(opt.config(), Arc::new(storage))
+        }
+        None => Server::new(opt.config()),
+    };
+    let mut listeners = inherited_listeners(
+        #[cfg(unix)]
+        listen_env,
+    );
+    let mut socket_files = vec![];
+    let mut metrics_listeners = vec![];
+    for addr in opt.metrics_listen_addrs() {
+        metrics_listeners.push(bind(addr, opt.unix_socket_mode(), &mut socket_files).await);
+        info!("Serving metrics at {}...", addr);
+    }
+    if !listeners.is_empty() {
+        info!(
+            "Running with {} sockets passed by systemd, ignoring addresses specified.",
+            listeners.len()
+        );
+    } else {
+        for addr in opt.listen_addrs() {
+            listeners.push(bind(&addr, opt.unix_socket_mode(), &mut socket_files).await);
+            info!("Running at {}...", addr);
+        }
+    }
+    notify_systemd("READY=1");
+    server
+        .metrics_listeners(metrics_listeners)
+        .serve_all_until(listeners, shutdown_signal())
+        .await
+        .expect("Serve app");
+    for path in socket_files {
+        let _ = remove_file(path);
+    }
+    info!("Stopped.");
 }
 
-fn main() {
-    if env::var("RUST_LOG").is_err() {
-        env::set_var("RUST_LOG", "intray=info");
+fn init_logger(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    match format {
+        // the key-values are already included in the messages
+        LogFormat::Text => builder.format_key_values(hidden_kv_format),
+        LogFormat::Json => builder.format(format_json),
+    };
+    builder.init();
+}
+
+/// Format the log record as a line of JSON, with key-values as fields.
+fn format_json(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    struct Fields(Map<String, Value>);
+
+    impl<'kvs> VisitSource<'kvs> for Fields {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+            let value = if let Some(v) = value.to_u64() {
+                Value::from(v)
+            } else if let Some(v) = value.to_i64() {
+                Value::from(v)
+            } else if let Some(v) = value.to_f64() {
+                Value::from(v)
+            } else if let Some(v) = value.to_bool() {
+                Value::from(v)
+            } else {
+                Value::from(value.to_string())
+            };
+            self.0.insert(key.to_string(), value);
+            Ok(())
+        }
     }
-    env_logger::init();
-    OPT.warn_if_invalid();
 
-    let app_state = State::new();
-    let expiration_task = app_state.expire();
-    let mut app = App::with_state(app_state);
-    app.middleware(RequestLogger::new());
-    if OPT.is_auth_enabled() {
-        app.middleware(HTTPBasicAuth::new());
+    let mut fields = Fields(Map::new());
+    fields
+        .0
+        .insert("time".into(), buf.timestamp_millis().to_string().into());
+    fields
+        .0
+        .insert("level".into(), record.level().as_str().into());
+    fields.0.insert("target".into(), record.target().into());
+    fields
+        .0
+        .insert("message".into(), record.args().to_string().into());
+    record
+        .key_values()
+        .visit(&mut fields)
+        .map_err(io::Error::other)?;
+    writeln!(buf, "{}", Value::Object(fields.0))
+}
+
+/// Listen on the address or exit on failure, recording the socket file to be removed on exit.
+async fn bind(
+    addr: &ListenAddr,
+    unix_socket_mode: Option<u32>,
+    socket_files: &mut Vec<PathBuf>,
+) -> Listener {
+    match addr.bind(unix_socket_mode).await {
+        Ok(listener) => {
+            if let ListenAddr::Unix(path) = addr {
+                socket_files.push(path.clone());
+            }
+            listener
+        }
+        Err(e) => {
+            error!("Failed to listen on {}: {}", addr, e);
+            process::exit(1);
+        }
     }
-    app.at("/").get(handle_index);
-    app.at("/assets/*path").get(handle_assets);
-    app.at("/upload/start").post(handle_upload_start);
-    app.at("/upload/:file/:chunk").post(handle_upload_chunk);
-    app.at("/upload/finish").post(handle_upload_finish);
-    app.at("/upload/full").post(handle_upload_full_unnamed);
-    app.at("/upload/full/:name").post(handle_upload_full_named);
-    let app_task = app.serve(OPT.socket_addr());
+}
 
-    let runtime = Runtime::new().expect("runtime");
-    let mut spawner = runtime.executor().compat();
-    spawner.spawn(app_task.map(|_| ())).expect("App task");
-    spawner.spawn(expiration_task).expect("Expiration task");
-    info!("Running at {}...", OPT.socket_addr());
-    runtime.shutdown_on_idle().wait().expect("Runtime shutdown");
-    // TODO: manually handle SIGINT to clean up resources gracefully
+/// Get the listening sockets passed by systemd (socket activation), if any.
+fn inherited_listeners(#[cfg(unix)] env: intray::systemd::ListenEnv) -> Vec<Listener> {
+    #[cfg(unix)]
+    return intray::systemd::listen_fds(env).unwrap_or_else(|e| {
+        error!("Failed to take sockets passed by systemd: {}", e);
+        process::exit(1);
+    });
+    #[cfg(not(unix))]
+    return vec![];
+}
+
+/// Notify systemd of the state change if run as a service with `Type=notify`.
+fn notify_systemd(state: &str) {
+    #[cfg(unix)]
+    if let Err(e) = intray::systemd::notify(state) {
+        warn!("Failed to notify systemd of {:?}: {}", state, e);
+    }
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+/// Wait for SIGINT or SIGTERM (on Unix) to shut down gracefully.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Install SIGINT handler");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        signal(SignalKind::terminate())
+            .expect("Install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = futures::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => (),
+        _ = terminate => (),
+    }
+    info!("Shutting down...");
+    notify_systemd("STOPPING=1");
 }
@@ -0,0 +1,132 @@
+use axum::{
+    extract,
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    core::Collector, exponential_buckets, Encoder, Histogram, HistogramOpts, IntCounter, IntGauge,
+    Registry, TextEncoder,
+};
+
+use std::time::Duration;
+
+use crate::state::State;
+
+/// Prometheus metrics of an intray instance.
+///
+/// Every instance has its own registry, so that multiple instances in one process are isolated.
+pub struct Metrics {
+    registry: Registry,
+    pub uploads_started: IntCounter,
+    pub uploads_finished: IntCounter,
+    pub uploads_expired: IntCounter,
+    pub uploads_cancelled: IntCounter,
+    pub bytes_received: IntCounter,
+    pub chunk_write_duration: Histogram,
+    pub auth_failures: IntCounter,
+    pending_files: IntGauge,
+    disk_free: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let counter = |name: &str, help: &str| IntCounter::new(name, help).unwrap();
+        let gauge = |name: &str, help: &str| IntGauge::new(name, help).unwrap();
+        let metrics = Metrics {
+            registry: Registry::new(),
+            uploads_started: counter(
+                "intray_uploads_started_total",
+                "Number of uploads started, either chunked or full.",
+            ),
+            uploads_finished: counter(
+                "intray_uploads_finished_total",
+                "Number of uploads finished successfully.",
+            ),
+            uploads_expired: counter(
+                "intray_uploads_expired_total",
+                "Number of pending uploads expired.",
+            ),
+            uploads_cancelled: counter(
+                "intray_uploads_cancelled_total",
+                "Number of uploads cancelled due to errors.",
+            ),
+            bytes_received: counter(
+                "intray_received_bytes_total",
+                "Number of bytes of file data received.",
+            ),
+            chunk_write_duration: Histogram::with_opts(
+                HistogramOpts::new(
+                    "intray_chunk_write_duration_seconds",
+                    "Time taken to receive and write a chunk.",
+                )
+                .buckets(exponential_buckets(0.005, 4.0, 8).unwrap()),
+            )
+            .unwrap(),
+            auth_failures: counter(
+                "intray_auth_failures_total",
+                "Number of requests rejected by HTTP Basic Auth.",
+            ),
+            pending_files: gauge(
+                "intray_pending_files",
+                "Number of pending uploads currently tracked.",
+            ),
+            disk_free: gauge(
+                "intray_disk_free_bytes",
+                "Space available in the directory to store received files.",
+            ),
+        };
+        let collectors: [Box<dyn Collector>; 9] = [
+            Box::new(metrics.uploads_started.clone()),
+            Box::new(metrics.uploads_finished.clone()),
+            Box::new(metrics.uploads_expired.clone()),
+            Box::new(metrics.uploads_cancelled.clone()),
+            Box::new(metrics.bytes_received.clone()),
+            Box::new(metrics.chunk_write_duration.clone()),
+            Box::new(metrics.auth_failures.clone()),
+            Box::new(metrics.pending_files.clone()),
+            Box::new(metrics.disk_free.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).unwrap();
+        }
+        metrics
+    }
+
+    pub fn observe_chunk_write(&self, elapsed: Duration) {
+        self.chunk_write_duration.observe(elapsed.as_secs_f64());
+    }
+
+    /// Encode all the metrics in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("Encode metrics");
+        String::from_utf8(buffer).expect("Metrics in UTF-8")
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub async fn handle_metrics(extract::State(state): extract::State<State>) -> Response {
+    let metrics = state.metrics();
+    metrics
+        .pending_files
+        .set(state.pending_count().await as i64);
+    match state.disk_free().await {
+        Ok(Some(free)) => metrics.disk_free.set(free as i64),
+        // e.g. object storage
+        Ok(None) => (),
+        Err(e) => warn!("Failed to get free space of the storage: {}", e),
+    }
+    (
+        StatusCode::OK,
+        [(CONTENT_TYPE, TextEncoder::new().format_type().to_owned())],
+        metrics.encode(),
+    )
+        .into_response()
+}
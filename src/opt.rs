@@ -1,85 +1,806 @@
+use serde::Deserialize;
 use std::{
     env::current_dir,
+    fs::read_to_string,
     io,
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    num::{NonZeroU64, NonZeroUsize},
     path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
 };
 
+use intray::{
+    compress::Codec,
+    config::normalize_base_path,
+    dedup::DedupMode,
+    encrypt::Recipient,
+    extract::ArchiveFormat,
+    retention::RetentionPolicy,
+    s3::{S3Config, S3Storage},
+    Config, IpNet, ListenAddr,
+};
 use structopt::{
     clap::AppSettings::{ColoredHelp, DeriveDisplayOrder},
     StructOpt,
 };
 
-#[derive(StructOpt, Debug)]
+static DEFAULT_IP_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+static DEFAULT_PORT: u16 = 8080;
+static DEFAULT_S3_REGION: &str = "us-east-1";
+
+// Options from CLI, which can also be loaded from a TOML config file specified by `--config`.
+//
+// Options are all optional here so that those absent in CLI can be filled up by the config file.
+// Defaults are only applied when the options are used.
+// (Not doc comments, O.W. they would override `about` in the help message.)
+#[derive(StructOpt, Deserialize, Debug, Default)]
 #[structopt(name = "intray", about = "An intray to facilitate collecting files.")]
 #[structopt(global_settings(&[ColoredHelp, DeriveDisplayOrder]))]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
 pub struct Opt {
-    /// IP address to bind on
-    #[structopt(short = "a", long = "ip-addr", default_value = "0.0.0.0")]
-    ip_addr: IpAddr,
+    /// TOML config file, of which the values are overridden by those specified in CLI
+    #[structopt(long = "config", parse(from_os_str))]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+
+    /// Addresses to listen on, either "IP:PORT" or "unix:/PATH/TO/SOCKET", overriding `--ip-addr`
+    /// and `PORT`
+    #[structopt(short = "l", long = "listen")]
+    listen: Vec<ListenAddr>,
+
+    /// Permissions of Unix domain sockets to listen on, in octal (e.g. 660)
+    #[structopt(long = "unix-socket-mode", parse(try_from_str = parse_mode))]
+    unix_socket_mode: Option<u32>,
 
-    /// Directory to store received files
-    #[structopt(short = "d", long = "dir", parse(from_os_str), default_value = "./")]
-    dir: PathBuf,
+    /// IP address to bind on [default: 0.0.0.0]
+    #[structopt(short = "a", long = "ip-addr")]
+    ip_addr: Option<IpAddr>,
+
+    /// Directory to store received files [default: ./]
+    #[structopt(short = "d", long = "dir", parse(from_os_str))]
+    dir: Option<PathBuf>,
 
     /// Credentials for HTTP Basic Auth in the format "USERNAME:PASSWD"
     #[structopt(short = "c", long = "credentials", env = "CREDENTIALS")]
+    #[serde(rename = "credentials")]
     auth_credentials: Vec<String>, // TODO: HashSet?
 
-    /// Realm to send in `WWW-Authenticate` HTTP header for HTTP Basic Auth
-    #[structopt(short = "r", long = "realm", default_value = "Intray")]
-    pub auth_realm: String,
+    /// Realm to send in `WWW-Authenticate` HTTP header for HTTP Basic Auth [default: Intray]
+    #[structopt(short = "r", long = "realm")]
+    #[serde(rename = "realm")]
+    auth_realm: Option<String>,
+
+    /// Size of the buffer (in bytes) to coalesce received data into before writing to disk
+    /// [default: 1048576]
+    #[structopt(long = "write-buffer-size")]
+    write_buffer_size: Option<NonZeroUsize>,
+
+    /// Seconds after which a pending upload expires if no more chunks are received [default: 30]
+    #[structopt(long = "pending-timeout")]
+    pending_timeout: Option<u64>,
+
+    /// Seconds after which a pending upload expires regardless of activity [default: unlimited]
+    #[structopt(long = "pending-max-lifetime")]
+    pending_max_lifetime: Option<u64>,
+
+    /// Interval in seconds at which expired pending uploads are swept [default: 30]
+    #[structopt(long = "sweep-interval")]
+    sweep_interval: Option<NonZeroU64>,
+
+    /// Path prefix under which the Web UI and the API are served, e.g. "/intray" [default: /]
+    #[structopt(long = "base-path")]
+    base_path: Option<String>,
+
+    /// Reverse proxies, as IP addresses or CIDR networks, whose "Forwarded"/"X-Forwarded-For"
+    /// headers are trusted to determine client addresses
+    #[structopt(long = "trusted-proxies")]
+    trusted_proxies: Vec<IpNet>,
+
+    /// Total size (in bytes) of the files in the directory beyond which "/readyz" reports not ready
+    /// [default: unlimited]
+    #[structopt(long = "quota")]
+    quota: Option<u64>,
+
+    /// Format of log messages, either "text" or "json" [default: text]
+    #[structopt(long = "log-format")]
+    log_format: Option<LogFormat>,
+
+    /// Path of the append-only audit log recording every upload as a line of JSON [default:
+    /// disabled]
+    #[structopt(long = "audit-log", parse(from_os_str))]
+    audit_log: Option<PathBuf>,
+
+    /// Size (in bytes) beyond which the audit log is rotated [default: 10485760]
+    #[structopt(long = "audit-log-max-size")]
+    audit_log_max_size: Option<NonZeroU64>,
+
+    /// Number of rotated audit logs to keep [default: 5]
+    #[structopt(long = "audit-log-keep")]
+    audit_log_keep: Option<usize>,
+
+    /// Path of the history of completed uploads stored as JSON lines, queried by "/history" behind
+    /// authentication and restricted to `--admins` if any [default: disabled]
+    #[structopt(long = "history", parse(from_os_str))]
+    history: Option<PathBuf>,
+
+    /// Users allowed to access the admin dashboard at "/admin", who must be among those in
+    /// `--credentials` [default: disabled]
+    #[structopt(long = "admins")]
+    admins: Vec<String>,
+
+    /// Endpoint URL of an S3-compatible object storage to store received files into instead of
+    /// the directory, e.g. "https://s3.us-east-1.amazonaws.com" [default: disabled]
+    #[structopt(long = "s3-endpoint")]
+    s3_endpoint: Option<String>,
+
+    /// Bucket of the S3-compatible object storage
+    #[structopt(long = "s3-bucket")]
+    s3_bucket: Option<String>,
+
+    /// Prefix of the keys of objects, e.g. "inbox/" [default: none]
+    #[structopt(long = "s3-prefix")]
+    s3_prefix: Option<String>,
+
+    /// Region of the S3-compatible object storage [default: us-east-1]
+    #[structopt(long = "s3-region")]
+    s3_region: Option<String>,
+
+    /// Access key ID of the S3-compatible object storage
+    #[structopt(long = "s3-access-key", env = "AWS_ACCESS_KEY_ID")]
+    s3_access_key: Option<String>,
+
+    /// Secret access key of the S3-compatible object storage
+    #[structopt(
+        long = "s3-secret-key",
+        env = "AWS_SECRET_ACCESS_KEY",
+        hide_env_values = true
+    )]
+    s3_secret_key: Option<String>,
+
+    /// Deduplicate completed uploads identical to files stored before, either by "link" (replacing
+    /// them with hard links) or "drop" (removing them) [default: disabled]
+    #[structopt(long = "dedup")]
+    dedup: Option<DedupMode>,
+
+    /// Let clients skip uploading content already present by its SHA-256 digest, which tells any
+    /// uploader whether some content is stored, only with `--dedup`
+    #[structopt(long = "dedup-present", overrides_with = "no-dedup-present")]
+    dedup_present: bool,
+
+    /// Disable `--dedup-present`, overriding the config file
+    #[structopt(long = "no-dedup-present", overrides_with = "dedup-present")]
+    #[serde(skip)]
+    no_dedup_present: bool,
+
+    /// Compress stored files on the fly, either by "zstd" or "gzip", appending ".zst" or ".gz" to
+    /// their names [default: disabled]
+    #[structopt(long = "compress")]
+    compress: Option<Codec>,
+
+    /// Extensions of the files to compress, e.g. "log" [default: all]
+    #[structopt(long = "compress-include")]
+    compress_include: Vec<String>,
+
+    /// Extensions of the files not to compress, e.g. "zip", taking precedence over
+    /// `--compress-include`
+    #[structopt(long = "compress-exclude")]
+    compress_exclude: Vec<String>,
 
-    /// Port to bind on
-    #[structopt(name = "PORT", default_value = "8080")]
-    port: u16,
+    /// Encrypt stored files to an age recipient, e.g. "age1...", appending ".age" to their names,
+    /// so that only the holder of the identity can decrypt them [default: disabled]
+    #[structopt(long = "encrypt-to")]
+    encrypt_to: Option<Recipient>,
+
+    /// Extract uploaded archives in the formats, e.g. "zip,tar,tar.gz", into directories beside
+    /// them named after them [default: disabled]
+    #[structopt(long = "extract", use_delimiter = true)]
+    extract: Vec<ArchiveFormat>,
+
+    /// Total size (in bytes) of the files extracted from an archive beyond which the extraction
+    /// fails [default: 1073741824]
+    #[structopt(long = "extract-max-size")]
+    extract_max_size: Option<u64>,
+
+    /// Number of entries in an archive beyond which the extraction fails [default: 10000]
+    #[structopt(long = "extract-max-entries")]
+    extract_max_entries: Option<usize>,
+
+    /// Extensions of the files allowed to upload, e.g. "pdf" [default: all]
+    #[structopt(long = "allow-ext")]
+    allow_ext: Vec<String>,
+
+    /// Extensions of the files not allowed to upload, e.g. "exe", taking precedence over
+    /// `--allow-ext`
+    #[structopt(long = "deny-ext")]
+    deny_ext: Vec<String>,
+
+    /// MIME types of the content allowed to upload as sniffed from the first bytes, e.g.
+    /// "application/pdf" or "image/*", with unrecognized content being "application/octet-stream"
+    /// [default: all]
+    #[structopt(long = "allow-type")]
+    allow_type: Vec<String>,
+
+    /// MIME types of the content not allowed to upload, e.g. "application/x-executable", taking
+    /// precedence over `--allow-type`
+    #[structopt(long = "deny-type")]
+    deny_type: Vec<String>,
+
+    /// Seconds after which stored files are cleaned up by the retention policy [default:
+    /// unlimited]
+    #[structopt(long = "retention-max-age")]
+    retention_max_age: Option<u64>,
+
+    /// Total size (in bytes) of the stored files beyond which the oldest ones are cleaned up by the
+    /// retention policy [default: unlimited]
+    #[structopt(long = "retention-max-size")]
+    retention_max_size: Option<u64>,
+
+    /// Directory to move the files cleaned up by the retention policy into, keeping their relative
+    /// paths, instead of deleting them [default: disabled]
+    #[structopt(long = "retention-move-to", parse(from_os_str))]
+    retention_move_to: Option<PathBuf>,
+
+    /// Only log the files the retention policy would clean up without touching them
+    #[structopt(long = "retention-dry-run", overrides_with = "no-retention-dry-run")]
+    retention_dry_run: bool,
+
+    /// Disable `--retention-dry-run`, overriding the config file
+    #[structopt(long = "no-retention-dry-run", overrides_with = "retention-dry-run")]
+    #[serde(skip)]
+    no_retention_dry_run: bool,
+
+    /// Interval in seconds at which the retention policy is applied [default: 3600]
+    #[structopt(long = "retention-interval")]
+    retention_interval: Option<NonZeroU64>,
+
+    /// Stream upload events as Server-Sent Events at "/events", behind authentication and restricted
+    /// to `--admins` if any
+    #[structopt(long = "events", overrides_with = "no-events")]
+    events: bool,
+
+    /// Disable `--events`, overriding the config file
+    #[structopt(long = "no-events", overrides_with = "events")]
+    #[serde(skip)]
+    no_events: bool,
+
+    /// Serve "/history" and "/events" even without authentication, telling anyone about every
+    /// upload
+    #[structopt(long = "public-activity", overrides_with = "no-public-activity")]
+    public_activity: bool,
+
+    /// Disable `--public-activity`, overriding the config file
+    #[structopt(long = "no-public-activity", overrides_with = "public-activity")]
+    #[serde(skip)]
+    no_public_activity: bool,
+
+    /// Expose Prometheus metrics at "/metrics" along with the Web UI, behind authentication if
+    /// enabled
+    #[structopt(long = "metrics", overrides_with = "no-metrics")]
+    metrics: bool,
+
+    /// Disable `--metrics`, overriding the config file
+    #[structopt(long = "no-metrics", overrides_with = "metrics")]
+    #[serde(skip)]
+    no_metrics: bool,
+
+    /// Addresses to serve Prometheus metrics on separately, without authentication, either
+    /// "IP:PORT" or "unix:/PATH/TO/SOCKET"
+    #[structopt(long = "metrics-listen")]
+    metrics_listen: Vec<ListenAddr>,
+
+    /// Port to bind on [default: 8080]
+    #[structopt(name = "PORT")]
+    port: Option<u16>,
 }
 
 impl Opt {
-    pub fn dir(&self) -> &Path {
-        self.dir.as_ref()
+    /// Parse options from CLI and merge them with the config file if any.
+    ///
+    /// The options are not validated yet, so that the logger can be set up by them before the
+    /// warnings of `validate` are logged.
+    pub fn load() -> Result<Opt, Vec<String>> {
+        let mut opt = Opt::from_args();
+        if let Some(path) = opt.config.clone() {
+            let file = Opt::from_file(&path)
+                .map_err(|e| vec![format!("Failed to load config file {:?}: {}", path, e)])?;
+            opt.merge(file);
+        }
+        Ok(opt)
     }
 
-    pub fn socket_addr(&self) -> SocketAddr {
-        SocketAddr::new(self.ip_addr, self.port)
+    fn from_file(path: impl AsRef<Path>) -> Result<Opt, String> {
+        let content = read_to_string(path).map_err(|e| e.to_string())?;
+        toml::from_str(&content).map_err(|e| e.to_string())
     }
 
-    pub fn is_auth_enabled(&self) -> bool {
-        return !self.auth_credentials.is_empty()
+    /// Fill up the options absent in `self` with those from `other`.
+    fn merge(&mut self, other: Opt) {
+        if self.listen.is_empty() {
+            self.listen = other.listen;
+        }
+        self.unix_socket_mode = self.unix_socket_mode.or(other.unix_socket_mode);
+        self.ip_addr = self.ip_addr.or(other.ip_addr);
+        self.dir = self.dir.take().or(other.dir);
+        if self.auth_credentials.is_empty() {
+            self.auth_credentials = other.auth_credentials;
+        }
+        self.auth_realm = self.auth_realm.take().or(other.auth_realm);
+        self.write_buffer_size = self.write_buffer_size.or(other.write_buffer_size);
+        self.pending_timeout = self.pending_timeout.or(other.pending_timeout);
+        self.pending_max_lifetime = self.pending_max_lifetime.or(other.pending_max_lifetime);
+        self.sweep_interval = self.sweep_interval.or(other.sweep_interval);
+        self.base_path = self.base_path.take().or(other.base_path);
+        if self.trusted_proxies.is_empty() {
+            self.trusted_proxies = other.trusted_proxies;
+        }
+        self.quota = self.quota.or(other.quota);
+        self.log_format = self.log_format.or(other.log_format);
+        self.history = self.history.take().or(other.history);
+        self.audit_log = self.audit_log.take().or(other.audit_log);
+        self.audit_log_max_size = self.audit_log_max_size.or(other.audit_log_max_size);
+        self.audit_log_keep = self.audit_log_keep.or(other.audit_log_keep);
+        if self.admins.is_empty() {
+            self.admins = other.admins;
+        }
+        self.s3_endpoint = self.s3_endpoint.take().or(other.s3_endpoint);
+        self.s3_bucket = self.s3_bucket.take().or(other.s3_bucket);
+        self.s3_prefix = self.s3_prefix.take().or(other.s3_prefix);
+        self.s3_region = self.s3_region.take().or(other.s3_region);
+        self.s3_access_key = self.s3_access_key.take().or(other.s3_access_key);
+        self.s3_secret_key = self.s3_secret_key.take().or(other.s3_secret_key);
+        self.dedup = self.dedup.or(other.dedup);
+        self.dedup_present = !self.no_dedup_present && (self.dedup_present || other.dedup_present);
+        self.compress = self.compress.or(other.compress);
+        if self.compress_include.is_empty() {
+            self.compress_include = other.compress_include;
+        }
+        if self.compress_exclude.is_empty() {
+            self.compress_exclude = other.compress_exclude;
+        }
+        self.encrypt_to = self.encrypt_to.or(other.encrypt_to);
+        if self.extract.is_empty() {
+            self.extract = other.extract;
+        }
+        self.extract_max_size = self.extract_max_size.or(other.extract_max_size);
+        self.extract_max_entries = self.extract_max_entries.or(other.extract_max_entries);
+        if self.allow_ext.is_empty() {
+            self.allow_ext = other.allow_ext;
+        }
+        if self.deny_ext.is_empty() {
+            self.deny_ext = other.deny_ext;
+        }
+        if self.allow_type.is_empty() {
+            self.allow_type = other.allow_type;
+        }
+        if self.deny_type.is_empty() {
+            self.deny_type = other.deny_type;
+        }
+        self.retention_max_age = self.retention_max_age.or(other.retention_max_age);
+        self.retention_max_size = self.retention_max_size.or(other.retention_max_size);
+        self.retention_move_to = self.retention_move_to.take().or(other.retention_move_to);
+        self.retention_dry_run =
+            !self.no_retention_dry_run && (self.retention_dry_run || other.retention_dry_run);
+        self.retention_interval = self.retention_interval.or(other.retention_interval);
+        self.events = !self.no_events && (self.events || other.events);
+        self.public_activity =
+            !self.no_public_activity && (self.public_activity || other.public_activity);
+        self.metrics = !self.no_metrics && (self.metrics || other.metrics);
+        if self.metrics_listen.is_empty() {
+            self.metrics_listen = other.metrics_listen;
+        }
+        self.port = self.port.or(other.port);
+    }
+
+    /// Get the addresses to listen on, falling back to `--ip-addr` and `PORT`.
+    pub fn listen_addrs(&self) -> Vec<ListenAddr> {
+        if self.listen.is_empty() {
+            vec![ListenAddr::Tcp(SocketAddr::new(
+                self.ip_addr.unwrap_or(DEFAULT_IP_ADDR),
+                self.port.unwrap_or(DEFAULT_PORT),
+            ))]
+        } else {
+            self.listen.clone()
+        }
     }
 
-    pub fn credentials_match(&self, credentials: impl AsRef<str>) -> bool {
-        let credentials = credentials.as_ref();
-        self.auth_credentials.iter().any(|c| c == credentials)
+    /// Get the addresses to serve metrics on separately.
+    pub fn metrics_listen_addrs(&self) -> &[ListenAddr] {
+        &self.metrics_listen
+    }
+
+    pub fn log_format(&self) -> LogFormat {
+        self.log_format.unwrap_or_default()
+    }
+
+    pub fn unix_socket_mode(&self) -> Option<u32> {
+        self.unix_socket_mode
+    }
+
+    /// Construct the config of the intray instance.
+    pub fn config(&self) -> Config {
+        let default = Config::default();
+        Config {
+            dir: self.dir.clone().unwrap_or(default.dir),
+            auth_credentials: self.auth_credentials.clone(),
+            auth_realm: self.auth_realm.clone().unwrap_or(default.auth_realm),
+            write_buffer_size: self
+                .write_buffer_size
+                .map_or(default.write_buffer_size, NonZeroUsize::get),
+            pending_timeout: self
+                .pending_timeout
+                .map_or(default.pending_timeout, Duration::from_secs),
+            pending_max_lifetime: self
+                .pending_max_lifetime
+                .map(Duration::from_secs)
+                .or(default.pending_max_lifetime),
+            sweep_interval: self.sweep_interval.map_or(default.sweep_interval, |secs| {
+                Duration::from_secs(secs.get())
+            }),
+            base_path: self
+                .base_path
+                .as_deref()
+                .map_or(default.base_path, normalize_base_path),
+            trusted_proxies: self.trusted_proxies.clone(),
+            metrics: self.metrics,
+            events: self.events,
+            public_activity: self.public_activity,
+            quota: self.quota,
+            audit_log: self.audit_log.clone(),
+            audit_log_max_size: self
+                .audit_log_max_size
+                .map_or(default.audit_log_max_size, NonZeroU64::get),
+            audit_log_keep: self.audit_log_keep.unwrap_or(default.audit_log_keep),
+            history: self.history.clone(),
+            admins: self.admins.clone(),
+            dedup: self.dedup,
+            dedup_present: self.dedup_present,
+            compress: self.compress,
+            compress_include: self.compress_include.clone(),
+            compress_exclude: self.compress_exclude.clone(),
+            encrypt_to: self.encrypt_to,
+            extract: self.extract.clone(),
+            extract_max_size: self.extract_max_size.unwrap_or(default.extract_max_size),
+            extract_max_entries: self
+                .extract_max_entries
+                .unwrap_or(default.extract_max_entries),
+            allowed_extensions: self.allow_ext.clone(),
+            denied_extensions: self.deny_ext.clone(),
+            allowed_types: self.allow_type.clone(),
+            denied_types: self.deny_type.clone(),
+            retention: self.retention(),
+        }
     }
 
-    pub fn warn_if_invalid(&self) {
-        // TODO: Integrate this fn to structopt validator (?)
-        if !self.dir.exists() {
-            warn!(
-                "{:?} does not exist.",
-                canonicalize_path(&self.dir).unwrap_or_else(|_| self.dir.clone())
-            );
-        } else if !self.dir.is_dir() {
-            warn!(
-                "{:?} is not a directory.",
-                canonicalize_path(&self.dir).unwrap_or_else(|_| self.dir.clone())
-            );
-            //, self.dir.canonicalize().unwrap_or_else(|_| self.dir.clone()));
+    fn retention(&self) -> RetentionPolicy {
+        let default = RetentionPolicy::default();
+        RetentionPolicy {
+            max_age: self.retention_max_age.map(Duration::from_secs),
+            max_size: self.retention_max_size,
+            move_to: self.retention_move_to.clone(),
+            dry_run: self.retention_dry_run,
+            interval: self
+                .retention_interval
+                .map_or(default.interval, |secs| Duration::from_secs(secs.get())),
         }
+    }
+
+    /// Construct the config of the S3-compatible object storage if enabled by `--s3-endpoint`.
+    pub fn s3_config(&self) -> Option<S3Config> {
+        let endpoint = self.s3_endpoint.clone()?;
+        Some(S3Config {
+            endpoint,
+            bucket: self.s3_bucket.clone().unwrap_or_default(),
+            prefix: self.s3_prefix.clone().unwrap_or_default(),
+            region: self
+                .s3_region
+                .clone()
+                .unwrap_or_else(|| DEFAULT_S3_REGION.to_owned()),
+            access_key: self.s3_access_key.clone().unwrap_or_default(),
+            secret_key: self.s3_secret_key.clone().unwrap_or_default(),
+        })
+    }
+
+    /// Check the options, return all the problems found if any.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = vec![];
+        let config = self.config();
         // Path::canonicalize is not proper here because it check the existence of the file of the path.
+        let dir = canonicalize_path(&config.dir).unwrap_or_else(|_| config.dir.clone());
+        if !config.dir.exists() {
+            problems.push(format!("{:?} does not exist.", dir));
+        } else if !config.dir.is_dir() {
+            problems.push(format!("{:?} is not a directory.", dir));
+        }
+
+        for credentials in config.auth_credentials.iter() {
+            if !credentials.contains(':') {
+                problems.push(format!(
+                    "Credentials {:?} are not in the format \"USERNAME:PASSWD\".",
+                    credentials
+                ));
+            }
+        }
+        if !self.listen.is_empty() && (self.ip_addr.is_some() || self.port.is_some()) {
+            warn!("Both `--listen` and `--ip-addr`/`PORT` are specified, the latter are ignored.")
+        }
+        if let Some(mode) = self.unix_socket_mode {
+            if mode > 0o7777 {
+                problems.push(format!("Unix socket mode {:o} is invalid.", mode));
+            }
+        }
 
-        if self.auth_credentials.len() >= 10 {
+        if config.auth_credentials.len() >= 10 {
             warn!("Too many authentication credentials specified. Intray may suffer from performance penalty.")
         }
+        if config.auth_realm.contains('"') {
+            problems.push(format!(
+                "Realm {:?} must not contain double quotes.",
+                config.auth_realm
+            ));
+        }
+
+        if config
+            .base_path
+            .contains(|c: char| "{}?#".contains(c) || c.is_whitespace())
+        {
+            problems.push(format!(
+                "Base path {:?} must not contain braces, '?', '#' or whitespaces.",
+                config.base_path
+            ));
+        }
+
+        for (what, path) in [
+            ("audit log", &config.audit_log),
+            ("history", &config.history),
+        ] {
+            if let Some(path) = path {
+                // a relative path without parent has an empty one, which is the current directory
+                match path.parent() {
+                    Some(parent) if !parent.as_os_str().is_empty() && !parent.is_dir() => {
+                        problems.push(format!(
+                            "The directory of the {} {:?} does not exist.",
+                            what, path
+                        ));
+                    }
+                    _ => (),
+                }
+                if path.is_dir() {
+                    problems.push(format!("The {} {:?} is a directory.", what, path));
+                }
+            }
+        }
+        for admin in config.admins.iter() {
+            let is_user = config
+                .auth_credentials
+                .iter()
+                .any(|credentials| credentials.split(':').next() == Some(admin.as_str()));
+            if !is_user {
+                problems.push(format!(
+                    "Admin {:?} is not a user in the credentials.",
+                    admin
+                ));
+            }
+        }
+        if let Some(s3) = self.s3_config() {
+            if s3.bucket.is_empty() {
+                problems.push(String::from("The S3 bucket is not specified."));
+            }
+            if s3.access_key.is_empty() || s3.secret_key.is_empty() {
+                problems.push(String::from(
+                    "The S3 access key or secret key is not specified.",
+                ));
+            }
+            if let Err(e) = S3Storage::new(s3) {
+                problems.push(e.to_string());
+            }
+            if config.dedup == Some(DedupMode::Link) {
+                problems.push(String::from(
+                    "Hard links are not supported by the S3 storage, use `--dedup drop` instead.",
+                ));
+            }
+            if config.encrypt_to.is_some() {
+                problems.push(String::from(
+                    "Encryption is not supported by the S3 storage.",
+                ));
+            }
+            if !config.extract.is_empty() {
+                problems.push(String::from(
+                    "Archives cannot be extracted in the S3 storage.",
+                ));
+            }
+        } else if self.s3_bucket.is_some() || self.s3_prefix.is_some() {
+            warn!("The S3 bucket or prefix is specified without `--s3-endpoint`, which is ignored.")
+        }
+        if config.compress.is_none()
+            && !(config.compress_include.is_empty() && config.compress_exclude.is_empty())
+        {
+            warn!("Compression rules are specified without `--compress`, which are ignored.")
+        }
+        if config.dedup_present && config.dedup.is_none() {
+            warn!("`--dedup-present` is specified without `--dedup`, which is ignored.")
+        }
+        if config.encrypt_to.is_some() && config.dedup.is_some() {
+            warn!("Encrypted files are never identical, so `--dedup` has no effect with `--encrypt-to`.")
+        }
+        if config.encrypt_to.is_some() && !config.extract.is_empty() {
+            warn!("Encrypted files are never extracted, so `--extract` has no effect with `--encrypt-to`.")
+        }
+        if let Some(move_to) = config.retention.move_to.as_ref() {
+            let move_to = canonicalize_path(move_to).unwrap_or_else(|_| move_to.clone());
+            if move_to.starts_with(&dir) {
+                problems.push(format!(
+                    "The retention directory {:?} must not be inside {:?}.",
+                    move_to, dir
+                ));
+            }
+        }
+        if !config.retention.is_enabled()
+            && (config.retention.move_to.is_some() || config.retention.dry_run)
+        {
+            warn!("The retention policy is specified without `--retention-max-age` or `--retention-max-size`, which is ignored.")
+        }
+        if (config.history.is_some() || config.events)
+            && !config.is_auth_enabled()
+            && !config.public_activity
+        {
+            warn!("\"/history\" and \"/events\" are not served without authentication unless `--public-activity` is specified.")
+        }
+        if config.public_activity && config.is_auth_enabled() {
+            warn!("`--public-activity` is specified with authentication, which is ignored.")
+        }
+
+        if config
+            .pending_max_lifetime
+            .is_some_and(|lifetime| lifetime < config.pending_timeout)
+        {
+            warn!("Pending max lifetime is shorter than pending timeout, the latter has no effect.")
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
     }
 }
 
-lazy_static! {
-    pub static ref OPT: Opt = Opt::from_args();
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format {:?}", s)),
+        }
+    }
+}
+
+fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8).map_err(|e| format!("Invalid octal mode {:?}: {}", s, e))
 }
 
 fn canonicalize_path(path: impl AsRef<Path>) -> io::Result<PathBuf> {
     // TODO: it is only joining without "all intermediate components normalized and symbolic links resolved".
     Ok(current_dir()?.join(path.as_ref()))
 }
+
+#[cfg(test)]
+mod test {
+    use super::Opt;
+    use intray::{extract::ArchiveFormat, ListenAddr};
+    use std::path::PathBuf;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_merge() {
+        let mut opt = Opt::from_iter(&["intray", "-d", "/", "-c", "a:b", "--", "8081"]);
+        let file: Opt = toml::from_str(
+            r#"
+            ip-addr = "127.0.0.1"
+            dir = "/nonexistent"
+            credentials = ["c:d"]
+            realm = "Inbox"
+            base-path = "intray/"
+            trusted-proxies = ["10.0.0.0/8", "::1"]
+            metrics = true
+            log-format = "json"
+            audit-log = "/var/log/intray/audit.log"
+            s3-endpoint = "http://127.0.0.1:9000"
+            s3-bucket = "inbox"
+            extract = ["zip", "tar.gz"]
+            port = 8082
+            "#,
+        )
+        .unwrap();
+        opt.merge(file);
+        let config = opt.config();
+        assert_eq!(opt.listen_addrs(), vec!["127.0.0.1:8081".parse().unwrap()]);
+        assert_eq!(config.dir.to_str(), Some("/"));
+        assert_eq!(config.auth_credentials, vec!["a:b"]);
+        assert_eq!(config.auth_realm, "Inbox");
+        assert_eq!(config.base_path, "/intray");
+        assert_eq!(config.trusted_proxies.len(), 2);
+        assert!(config.metrics);
+        assert_eq!(opt.log_format(), super::LogFormat::Json);
+        assert_eq!(config.audit_log_keep, 5);
+        assert_eq!(
+            config.extract,
+            vec![ArchiveFormat::Zip, ArchiveFormat::TarGz]
+        );
+        let s3 = opt.s3_config().unwrap();
+        assert_eq!(
+            (s3.bucket.as_str(), s3.region.as_str()),
+            ("inbox", "us-east-1")
+        );
+        opt.port = None;
+        opt.ip_addr = None;
+        opt.listen = vec![];
+        let mut file: Opt = toml::from_str(r#"listen = ["[::1]:8083", "unix:/a.sock"]"#).unwrap();
+        file.unix_socket_mode = Some(0o660);
+        opt.merge(file);
+        assert_eq!(
+            opt.listen_addrs(),
+            vec![
+                "[::1]:8083".parse().unwrap(),
+                ListenAddr::Unix(PathBuf::from("/a.sock"))
+            ]
+        );
+        assert_eq!(opt.unix_socket_mode(), Some(0o660));
+    }
+
+    #[test]
+    fn test_merge_flags() {
+        let file = || -> Opt {
+            toml::from_str("metrics = true\nevents = true\nretention-dry-run = true").unwrap()
+        };
+        // flags in the CLI are turned off by their `--no-…` counterparts, the last one winning
+        let mut opt = Opt::from_iter(&[
+            "intray",
+            "--no-metrics",
+            "--events",
+            "--no-events",
+            "--no-dedup-present",
+            "--dedup-present",
+        ]);
+        opt.merge(file());
+        let config = opt.config();
+        assert!(!config.metrics);
+        assert!(!config.events);
+        assert!(config.dedup_present);
+        assert!(config.retention.dry_run);
+        let mut opt = Opt::from_iter(&["intray", "--public-activity"]);
+        opt.merge(file());
+        let config = opt.config();
+        assert!(config.metrics && config.events && config.public_activity);
+    }
+
+    #[test]
+    fn test_validate() {
+        let opt = Opt::from_iter(&[
+            "intray",
+            "-d",
+            "/nonexistent",
+            "-r",
+            "\"",
+            "-c",
+            "ab",
+            "--base-path",
+            "/{x}",
+            "--admins",
+            "root",
+        ]);
+        assert_eq!(opt.validate().unwrap_err().len(), 5);
+        assert!(toml::from_str::<Opt>("unknown = 1").is_err());
+        assert!(toml::from_str::<Opt>("write-buffer-size = 0").is_err());
+    }
+}
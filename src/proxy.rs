@@ -0,0 +1,275 @@
+use axum::{
+    extract::{connect_info::Connected, ConnectInfo, Request},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+    serve::IncomingStream,
+};
+use serde::Deserialize;
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+use std::{
+    convert::TryFrom,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+
+use crate::config::Config;
+
+/// The peer of a connection, attached to requests as `ConnectInfo<Peer>` by `Server`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// A local process connected via a Unix domain socket
+    Unix,
+}
+
+impl Connected<IncomingStream<'_, TcpListener>> for Peer {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        Peer::Tcp(*stream.remote_addr())
+    }
+}
+
+#[cfg(unix)]
+impl Connected<IncomingStream<'_, UnixListener>> for Peer {
+    fn connect_info(_stream: IncomingStream<'_, UnixListener>) -> Self {
+        Peer::Unix
+    }
+}
+
+/// The IP address of the client, attached to requests by `resolve_client` and seen by all
+/// subsequent middlewares and handlers.
+///
+/// It is `None` if the client is unknown, e.g. connected via a Unix domain socket without
+/// forwarded headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientAddr(pub Option<IpAddr>);
+
+impl fmt::Display for ClientAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(addr) => write!(f, "{}", addr),
+            None => write!(f, "-"),
+        }
+    }
+}
+
+/// A network in CIDR notation (e.g. `10.0.0.0/8`) or a single IP address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                prefix_eq(&net.octets(), &addr.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_eq(&net.octets(), &addr.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_eq(a: &[u8], b: &[u8], prefix_len: u8) -> bool {
+    let (bytes, bits) = ((prefix_len / 8) as usize, prefix_len % 8);
+    if a[..bytes] != b[..bytes] {
+        return false;
+    }
+    bits == 0 || (a[bytes] ^ b[bytes]) >> (8 - bits) == 0
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| format!("Invalid network {:?}: {}", s, e))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|&len| len <= max_len)
+                .ok_or_else(|| format!("Invalid prefix length in {:?}", s))?,
+            None => max_len,
+        };
+        Ok(IpNet { addr, prefix_len })
+    }
+}
+
+impl TryFrom<String> for IpNet {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Parse the addresses in `Forwarded` ([RFC 7239](https://tools.ietf.org/html/rfc7239)) or,
+/// if absent, `X-Forwarded-For`, from the farthest to the nearest.
+///
+/// Unknown or obfuscated identifiers are `None`.
+fn forwarded_addrs(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let forwarded: Vec<_> = headers
+        .get_all("Forwarded")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_at(pair.find('=')?);
+                if key.trim().eq_ignore_ascii_case("for") {
+                    Some(parse_node(value[1..].trim().trim_matches('"')))
+                } else {
+                    None
+                }
+            })
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+    headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|node| parse_node(node.trim()))
+        .collect()
+}
+
+/// Parse a node identifier such as `192.0.2.60`, `192.0.2.60:4711` or `[2001:db8::17]:4711`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(addr) = node.parse::<IpAddr>() {
+        return Some(addr);
+    }
+    node.parse::<SocketAddr>()
+        .ok()
+        .map(|addr| addr.ip())
+        .or_else(|| {
+            // bracketed IPv6 without port
+            node.strip_prefix('[')?.strip_suffix(']')?.parse().ok()
+        })
+}
+
+/// Determine the IP address of the client from the peer and, if the peer is a trusted proxy, the
+/// forwarded headers.
+///
+/// Forwarded addresses are walked from the nearest to the farthest until one not trusted is met,
+/// which is considered to be the client. Peers connected via Unix domain sockets are always
+/// trusted, provided that there are any trusted proxies.
+pub fn resolve_client_addr(
+    peer: Option<Peer>,
+    headers: &HeaderMap,
+    trusted_proxies: &[IpNet],
+) -> Option<IpAddr> {
+    let is_trusted = |addr: IpAddr| trusted_proxies.iter().any(|net| net.contains(addr));
+    let mut client = match peer {
+        Some(Peer::Tcp(addr)) => {
+            let addr = addr.ip().to_canonical();
+            if !is_trusted(addr) {
+                return Some(addr);
+            }
+            Some(addr)
+        }
+        Some(Peer::Unix) if !trusted_proxies.is_empty() => None,
+        _ => return None,
+    };
+    for addr in forwarded_addrs(headers).into_iter().rev() {
+        match addr {
+            Some(addr) => {
+                client = Some(addr);
+                if !is_trusted(addr) {
+                    break;
+                }
+            }
+            // the hop is unknown, so is the client
+            None => break,
+        }
+    }
+    client
+}
+
+/// Middleware attaching `ClientAddr` to requests, to be used with `axum::middleware::from_fn`.
+pub async fn resolve_client(config: Arc<Config>, mut req: Request, next: Next) -> Response {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<Peer>>()
+        .map(|ConnectInfo(peer)| *peer);
+    let client = resolve_client_addr(peer, req.headers(), &config.trusted_proxies);
+    req.extensions_mut().insert(ClientAddr(client));
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::{resolve_client_addr, IpNet, Peer};
+    use axum::http::HeaderMap;
+
+    #[test]
+    fn test_ip_net() {
+        let net: IpNet = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!net.contains("11.0.0.1".parse().unwrap()));
+        let net: IpNet = "fd00::/7".parse().unwrap();
+        assert!(net.contains("fdab::1".parse().unwrap()));
+        assert!(!net.contains("fe80::1".parse().unwrap()));
+        let net: IpNet = "127.0.0.1".parse().unwrap();
+        assert!(net.contains("127.0.0.1".parse().unwrap()));
+        assert!(!net.contains("127.0.0.2".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn test_resolve_client_addr() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        let proxy = Some(Peer::Tcp("10.0.0.1:1234".parse().unwrap()));
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "X-Forwarded-For",
+            "1.1.1.1, 2.2.2.2, 10.0.0.2".parse().unwrap(),
+        );
+        // the spoofed 1.1.1.1 is ignored
+        let client = resolve_client_addr(proxy, &headers, &trusted);
+        assert_eq!(client, Some("2.2.2.2".parse().unwrap()));
+        // headers from untrusted peers are ignored
+        let peer = Some(Peer::Tcp("3.3.3.3:1234".parse().unwrap()));
+        let client = resolve_client_addr(peer, &headers, &trusted);
+        assert_eq!(client, Some("3.3.3.3".parse().unwrap()));
+
+        headers.insert(
+            "Forwarded",
+            r#"for=4.4.4.4;proto=http, for="[2001:db8::17]:4711""#
+                .parse()
+                .unwrap(),
+        );
+        let client = resolve_client_addr(proxy, &headers, &trusted);
+        assert_eq!(client, Some("2001:db8::17".parse().unwrap()));
+        let client = resolve_client_addr(Some(Peer::Unix), &headers, &trusted);
+        assert_eq!(client, Some("2001:db8::17".parse().unwrap()));
+        assert_eq!(resolve_client_addr(Some(Peer::Unix), &headers, &[]), None);
+        assert_eq!(resolve_client_addr(None, &headers, &trusted), None);
+    }
+}
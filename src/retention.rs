@@ -0,0 +1,187 @@
+use futures::StreamExt;
+use tokio::{fs::File, io::AsyncWriteExt};
+
+use std::{
+    fmt, io,
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::storage::{create_file, Object, Storage};
+
+/// The policy by which stored files are cleaned up periodically, either deleted or moved away.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// The age beyond which files are cleaned up, `None` for unlimited
+    pub max_age: Option<Duration>,
+    /// The total size (in bytes) of the files beyond which the oldest ones are cleaned up, `None`
+    /// for unlimited
+    pub max_size: Option<u64>,
+    /// The directory to move files into instead of deleting them, keeping their relative paths
+    pub move_to: Option<PathBuf>,
+    /// Whether to only log the files to clean up without touching them
+    pub dry_run: bool,
+    /// The interval at which the policy is applied, which must be positive
+    pub interval: Duration,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            max_age: None,
+            max_size: None,
+            move_to: None,
+            dry_run: false,
+            interval: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Why a stored file is cleaned up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// The file is older than the max age
+    Age,
+    /// The total size of the files exceeds the budget, of which the file is the oldest
+    Budget,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reason::Age => write!(f, "older than the max age"),
+            Reason::Budget => write!(f, "beyond the size budget"),
+        }
+    }
+}
+
+impl RetentionPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.max_age.is_some() || self.max_size.is_some()
+    }
+
+    /// Select the objects to clean up as of `now`, oldest first, i.e. those older than the max age
+    /// and then the oldest until the total size of the rest is within the budget.
+    ///
+    /// Objects of which the modification time is unknown are considered the newest.
+    pub fn select(&self, mut objects: Vec<Object>, now: SystemTime) -> Vec<(Object, Reason)> {
+        objects.sort_by_key(|object| (object.modified.is_none(), object.modified));
+        let mut total: u64 = objects.iter().map(|object| object.size).sum();
+        let mut selected = vec![];
+        for object in objects {
+            let age = object
+                .modified
+                .and_then(|modified| now.duration_since(modified).ok());
+            let reason = if self
+                .max_age
+                .is_some_and(|max_age| age.is_some_and(|age| age > max_age))
+            {
+                Reason::Age
+            } else if self.max_size.is_some_and(|max_size| total > max_size) {
+                Reason::Budget
+            } else {
+                continue;
+            };
+            total -= object.size;
+            selected.push((object, reason));
+        }
+        selected
+    }
+}
+
+/// Move the stored object at `path` out of the storage to the same relative path under `dir`
+/// (relative to `base` if under it), suffixing the name if it is taken, return the new path.
+///
+/// The object is copied over before removed, so that it can be moved across file systems or out
+/// of object storage.
+pub async fn move_object(
+    storage: &dyn Storage,
+    path: &Path,
+    base: &Path,
+    dir: &Path,
+) -> io::Result<PathBuf> {
+    let relative: PathBuf = path
+        .strip_prefix(base)
+        .unwrap_or(path)
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect();
+    let file_name = relative
+        .file_name()
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?
+        .to_string_lossy()
+        .into_owned();
+    let dir = match relative.parent() {
+        Some(parent) => dir.join(parent),
+        None => dir.to_owned(),
+    };
+    tokio::fs::create_dir_all(&dir).await?;
+    let mut data = storage.open(path).await?;
+    let (file, target) = create_file(&dir, &file_name).await?;
+    let mut file = File::from_std(file);
+    let copied = async {
+        while let Some(bytes) = data.next().await {
+            file.write_all(&bytes?).await?;
+        }
+        file.sync_all().await
+    }
+    .await;
+    if let Err(e) = copied {
+        let _ = tokio::fs::remove_file(&target).await;
+        return Err(e);
+    }
+    storage.remove(path).await?;
+    Ok(target)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_select() {
+        let now = SystemTime::now();
+        let object = |name: &str, size: u64, days: Option<u64>| Object {
+            path: PathBuf::from(name),
+            size,
+            modified: days.map(|days| now - Duration::from_secs(days * 24 * 60 * 60)),
+        };
+        let objects = vec![
+            object("new", 10, Some(1)),
+            object("unknown", 10, None),
+            object("old", 10, Some(100)),
+            object("older", 10, Some(200)),
+            object("middle", 10, Some(50)),
+        ];
+        let selected = |policy: &RetentionPolicy| {
+            policy
+                .select(objects.clone(), now)
+                .into_iter()
+                .map(|(object, reason)| (object.path.to_string_lossy().into_owned(), reason))
+                .collect::<Vec<_>>()
+        };
+        let mut policy = RetentionPolicy {
+            max_age: Some(Duration::from_secs(90 * 24 * 60 * 60)),
+            ..Default::default()
+        };
+        assert_eq!(
+            selected(&policy),
+            vec![
+                ("older".to_owned(), Reason::Age),
+                ("old".to_owned(), Reason::Age)
+            ]
+        );
+        policy.max_size = Some(25);
+        assert_eq!(
+            selected(&policy),
+            vec![
+                ("older".to_owned(), Reason::Age),
+                ("old".to_owned(), Reason::Age),
+                ("middle".to_owned(), Reason::Budget)
+            ]
+        );
+        policy.max_age = None;
+        policy.max_size = Some(0);
+        assert_eq!(selected(&policy).last().unwrap().0, "unknown");
+    }
+}
@@ -0,0 +1,498 @@
+use axum::{
+    middleware::from_fn,
+    response::Redirect,
+    routing::{get, post},
+    Router,
+};
+use futures::{
+    future::{pending, try_join_all},
+    Future,
+};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+use std::{io, path::PathBuf, sync::Arc, time::Duration};
+
+use crate::{
+    admin::{handle_admin, handle_admin_cancel, handle_admin_status, require_admin},
+    api::*,
+    auth::HTTPBasicAuth,
+    compress::Codec,
+    config::{normalize_base_path, Config},
+    dedup::DedupMode,
+    encrypt::Recipient,
+    events::handle_events,
+    extract::{ArchiveFormat, ExtractLimits},
+    health::{handle_healthz, handle_readyz},
+    history::handle_history,
+    listen::Listener,
+    logger::log_request,
+    metrics::handle_metrics,
+    proxy::{resolve_client, IpNet, Peer},
+    retention::RetentionPolicy,
+    state::State,
+    storage::Storage,
+    web::{handle_assets, handle_index},
+};
+
+/// Builder of `Server`.
+///
+/// By default, files are stored into the current directory and authentication is disabled.
+#[derive(Debug, Default)]
+pub struct ServerBuilder {
+    config: Config,
+    storage: Option<Arc<dyn Storage>>,
+}
+
+impl ServerBuilder {
+    /// Set the directory to store received files.
+    pub fn dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.dir = dir.into();
+        self
+    }
+
+    /// Set the storage to store received files into, instead of the directory.
+    ///
+    /// The directory is still used for probing by `/readyz`.
+    pub fn storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Set credentials for HTTP Basic Auth in the format "USERNAME:PASSWD".
+    ///
+    /// Authentication is enabled only if there are any credentials.
+    pub fn credentials(mut self, credentials: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.config.auth_credentials = credentials.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set the realm to send in `WWW-Authenticate` HTTP header for HTTP Basic Auth.
+    pub fn realm(mut self, realm: impl Into<String>) -> Self {
+        self.config.auth_realm = realm.into();
+        self
+    }
+
+    /// Set the size of the buffer (in bytes) to coalesce received data into before writing to disk.
+    ///
+    /// # Panics
+    /// Panics if `size` is zero.
+    pub fn write_buffer_size(mut self, size: usize) -> Self {
+        assert!(size > 0, "Write buffer size must be positive");
+        self.config.write_buffer_size = size;
+        self
+    }
+
+    /// Set the period after which a pending upload expires if no more chunks are received.
+    pub fn pending_timeout(mut self, timeout: Duration) -> Self {
+        self.config.pending_timeout = timeout;
+        self
+    }
+
+    /// Set the period after which a pending upload expires regardless of activity.
+    pub fn pending_max_lifetime(mut self, lifetime: Option<Duration>) -> Self {
+        self.config.pending_max_lifetime = lifetime;
+        self
+    }
+
+    /// Set the interval at which expired pending uploads are swept.
+    ///
+    /// # Panics
+    /// Panics if `interval` is zero.
+    pub fn sweep_interval(mut self, interval: Duration) -> Self {
+        assert!(!interval.is_zero(), "Sweep interval must be positive");
+        self.config.sweep_interval = interval;
+        self
+    }
+
+    /// Set the path prefix under which all routes are mounted, e.g. `/intray`.
+    pub fn base_path(mut self, path: impl AsRef<str>) -> Self {
+        self.config.base_path = normalize_base_path(path.as_ref());
+        self
+    }
+
+    /// Set the networks of reverse proxies whose forwarded headers are trusted to determine the
+    /// client address.
+    pub fn trusted_proxies(mut self, proxies: impl IntoIterator<Item = IpNet>) -> Self {
+        self.config.trusted_proxies = proxies.into_iter().collect();
+        self
+    }
+
+    /// Set the total size (in bytes) of the files in the directory beyond which the instance is
+    /// reported not ready by `/readyz`.
+    pub fn quota(mut self, quota: Option<u64>) -> Self {
+        self.config.quota = quota;
+        self
+    }
+
+    /// Set the path of the audit log recording every upload, `None` to disable.
+    pub fn audit_log(mut self, path: Option<PathBuf>) -> Self {
+        self.config.audit_log = path;
+        self
+    }
+
+    /// Set the size (in bytes) beyond which the audit log is rotated and the number of rotated
+    /// audit logs to keep.
+    ///
+    /// # Panics
+    /// Panics if `max_size` is zero.
+    pub fn audit_log_rotation(mut self, max_size: u64, keep: usize) -> Self {
+        assert!(max_size > 0, "Audit log max size must be positive");
+        self.config.audit_log_max_size = max_size;
+        self.config.audit_log_keep = keep;
+        self
+    }
+
+    /// Set the path of the history of completed uploads, `None` to disable.
+    ///
+    /// The history is queried by `/history` if enabled.
+    pub fn history(mut self, path: Option<PathBuf>) -> Self {
+        self.config.history = path;
+        self
+    }
+
+    /// Set whether to stream upload events as Server-Sent Events at `/events`.
+    pub fn events(mut self, enabled: bool) -> Self {
+        self.config.events = enabled;
+        self
+    }
+
+    /// Set whether to serve `/history` and `/events` even if authentication is disabled, which are
+    /// otherwise not served without authentication, since they tell everyone about every upload.
+    pub fn public_activity(mut self, enabled: bool) -> Self {
+        self.config.public_activity = enabled;
+        self
+    }
+
+    /// Set the users allowed to access the admin dashboard at `/admin`.
+    ///
+    /// The dashboard is enabled only if there are any admins, who must be authenticated by HTTP
+    /// Basic Auth.
+    pub fn admins(mut self, admins: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.config.admins = admins.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set how completed uploads identical to files stored before are deduplicated, `None` to
+    /// disable.
+    pub fn dedup(mut self, mode: Option<DedupMode>) -> Self {
+        self.config.dedup = mode;
+        self
+    }
+
+    /// Set whether clients may skip uploading content already present by passing its digest to
+    /// `/upload/start`, which has no effect unless deduplication is enabled.
+    ///
+    /// It tells any uploader whether some content is stored, and lets them store a copy of it
+    /// without proving they have it, so only enable it if the uploaders are trusted to know.
+    pub fn dedup_present(mut self, enabled: bool) -> Self {
+        self.config.dedup_present = enabled;
+        self
+    }
+
+    /// Set the codec to compress stored files with, `None` to disable.
+    pub fn compress(mut self, codec: Option<Codec>) -> Self {
+        self.config.compress = codec;
+        self
+    }
+
+    /// Set the extensions of the files to compress (empty for all) and of those not to, e.g.
+    /// `log`.
+    pub fn compress_rules(
+        mut self,
+        include: impl IntoIterator<Item = impl Into<String>>,
+        exclude: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.config.compress_include = include.into_iter().map(Into::into).collect();
+        self.config.compress_exclude = exclude.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set the recipient to encrypt stored files to, `None` to store them in plaintext.
+    ///
+    /// Chunks of any size are buffered into segments of 64 KiB, each of which is sealed only once,
+    /// so writing into a sealed segment again, e.g. by retrying a chunk, is refused.
+    pub fn encrypt_to(mut self, recipient: Option<Recipient>) -> Self {
+        self.config.encrypt_to = recipient;
+        self
+    }
+
+    /// Set the formats of the archives to extract once uploaded into directories beside them, empty
+    /// to disable.
+    pub fn extract(mut self, formats: impl IntoIterator<Item = ArchiveFormat>) -> Self {
+        self.config.extract = formats.into_iter().collect();
+        self
+    }
+
+    /// Set the limits of extracting an archive, beyond which the extraction fails.
+    pub fn extract_limits(mut self, limits: ExtractLimits) -> Self {
+        self.config.extract_max_size = limits.max_size;
+        self.config.extract_max_entries = limits.max_entries;
+        self
+    }
+
+    /// Set the extensions of the files allowed to upload (empty for all) and of those not, e.g.
+    /// `pdf`.
+    pub fn extension_rules(
+        mut self,
+        allow: impl IntoIterator<Item = impl Into<String>>,
+        deny: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.config.allowed_extensions = allow.into_iter().map(Into::into).collect();
+        self.config.denied_extensions = deny.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set the MIME types of the content allowed to upload (empty for all) and of those not, e.g.
+    /// `image/*`, which are checked against the type sniffed from the first bytes of files.
+    pub fn type_rules(
+        mut self,
+        allow: impl IntoIterator<Item = impl Into<String>>,
+        deny: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.config.allowed_types = allow.into_iter().map(Into::into).collect();
+        self.config.denied_types = deny.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set the policy by which stored files are cleaned up periodically, by the task returned by
+    /// `Server::retain` if enabled.
+    ///
+    /// # Panics
+    /// Panics if the interval of the policy is zero.
+    pub fn retention(mut self, policy: RetentionPolicy) -> Self {
+        assert!(
+            !policy.interval.is_zero(),
+            "Retention interval must be positive"
+        );
+        self.config.retention = policy;
+        self
+    }
+
+    /// Set whether to expose Prometheus metrics at `/metrics` along with other routes.
+    pub fn metrics(mut self, enabled: bool) -> Self {
+        self.config.metrics = enabled;
+        self
+    }
+
+    pub fn build(self) -> Server {
+        match self.storage {
+            Some(storage) => Server::with_storage(self.config, storage),
+            None => Server::new(self.config),
+        }
+    }
+}
+
+/// The collector serving the Web UI and the upload API over HTTP.
+pub struct Server {
+    config: Arc<Config>,
+    state: State,
+    metrics_listeners: Vec<Listener>,
+}
+
+impl Server {
+    pub fn new(config: Config) -> Self {
+        let config = Arc::new(config);
+        let state = State::new(config.clone());
+        Server {
+            config,
+            state,
+            metrics_listeners: vec![],
+        }
+    }
+
+    /// Construct the server storing files into the storage, instead of the directory in the
+    /// config.
+    pub fn with_storage(config: Config, storage: Arc<dyn Storage>) -> Self {
+        let config = Arc::new(config);
+        let state = State::with_storage(config.clone(), storage);
+        Server {
+            config,
+            state,
+            metrics_listeners: vec![],
+        }
+    }
+
+    pub fn builder() -> ServerBuilder {
+        Default::default()
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Serve Prometheus metrics on the listeners separately, without authentication, when serving
+    /// by `serve_all` or `serve_all_until`.
+    pub fn metrics_listeners(mut self, listeners: Vec<Listener>) -> Self {
+        self.metrics_listeners = listeners;
+        self
+    }
+
+    /// Build the router with all the routes and middlewares, which can be nested into other apps.
+    ///
+    /// Note that pending files never expire unless the future returned by `expire` is running.
+    ///
+    /// All routes are mounted under the base path. As the Web UI refers to assets and the API by
+    /// relative URLs, the base path without a trailing slash is redirected to the one with it.
+    ///
+    /// `/healthz` and `/readyz` are not subject to authentication, while `/admin` is restricted to
+    /// the admins further.
+    pub fn router(&self) -> Router {
+        let base = self.config.base_path.as_str();
+        let path = |path: &str| format!("{}{}", base, path);
+        let mut app = Router::new()
+            .route(&path("/"), get(handle_index))
+            .route(&path("/assets/{*path}"), get(handle_assets))
+            .route(&path("/upload/options"), get(handle_upload_options))
+            .route(&path("/upload/start"), post(handle_upload_start))
+            .route(&path("/upload/{file}/{chunk}"), post(handle_upload_chunk))
+            .route(&path("/upload/finish"), post(handle_upload_finish))
+            .route(&path("/upload/full"), post(handle_upload_full_unnamed))
+            .route(&path("/upload/full/{name}"), post(handle_upload_full_named));
+        if self.config.metrics {
+            app = app.route(&path("/metrics"), get(handle_metrics));
+        }
+        // the activity of every uploader is told only to the admins if any, and to nobody without
+        // authentication unless opted in
+        let mut activity = Router::new();
+        if self.config.is_auth_enabled() || self.config.public_activity {
+            if self.config.events {
+                activity = activity.route(&path("/events"), get(handle_events));
+            }
+            if self.config.history.is_some() {
+                activity = activity.route(&path("/history"), get(handle_history));
+            }
+        }
+        let mut app = if self.config.admins.is_empty() {
+            app.merge(activity).with_state(self.state.clone())
+        } else {
+            let config = self.config.clone();
+            let admin = Router::new()
+                .route(&path("/admin"), get(handle_admin))
+                .route(&path("/admin/status"), get(handle_admin_status))
+                .route(&path("/admin/events"), get(handle_events))
+                .route(&path("/admin/cancel/{token}"), post(handle_admin_cancel))
+                .merge(activity)
+                .with_state(self.state.clone())
+                .layer(from_fn(move |req, next| {
+                    require_admin(config.clone(), req, next)
+                }));
+            app.with_state(self.state.clone()).merge(admin)
+        };
+        if !base.is_empty() {
+            let index = path("/");
+            app = app.route(
+                base,
+                get(move || async move { Redirect::permanent(&index) }),
+            );
+        }
+        if self.config.is_auth_enabled() {
+            let auth = Arc::new(HTTPBasicAuth::new(
+                self.config.clone(),
+                self.state.metrics().clone(),
+            ));
+            app = app.layer(from_fn(move |req, next| auth.clone().handle(req, next)));
+        }
+        let probes = Router::new()
+            .route(&path("/healthz"), get(handle_healthz))
+            .route(&path("/readyz"), get(handle_readyz))
+            .with_state(self.state.clone());
+        let app = app.merge(probes);
+        let config = self.config.clone();
+        app.layer(from_fn(log_request))
+            .layer(from_fn(move |req, next| {
+                resolve_client(config.clone(), req, next)
+            }))
+    }
+
+    /// Build the router serving only Prometheus metrics at `/metrics`, without authentication.
+    pub fn metrics_router(&self) -> Router {
+        let config = self.config.clone();
+        Router::new()
+            .route("/metrics", get(handle_metrics))
+            .with_state(self.state.clone())
+            .layer(from_fn(log_request))
+            .layer(from_fn(move |req, next| {
+                resolve_client(config.clone(), req, next)
+            }))
+    }
+
+    /// Get the task that keeps expiring stale pending files.
+    pub fn expire(&self) -> impl Future<Output = ()> {
+        self.state.expire()
+    }
+
+    /// Get the task that keeps cleaning up stored files by the retention policy.
+    pub fn retain(&self) -> impl Future<Output = ()> {
+        self.state.retain()
+    }
+
+    /// Serve on the listener along with the expiration task until an error occurs.
+    pub async fn serve(self, listener: TcpListener) -> io::Result<()> {
+        self.serve_all(vec![Listener::Tcp(listener)]).await
+    }
+
+    /// Serve on all the listeners with the same state along with the expiration task, and the
+    /// retention task if enabled, until an error occurs on any of them.
+    pub async fn serve_all(self, listeners: Vec<Listener>) -> io::Result<()> {
+        self.serve_all_until(listeners, pending()).await
+    }
+
+    /// Serve on all the listeners like `serve_all` until the `shutdown` future completes, after
+    /// which all listeners stop accepting and ongoing requests are waited for.
+    pub async fn serve_all_until(
+        self,
+        listeners: Vec<Listener>,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> io::Result<()> {
+        let app = self.router();
+        let metrics_app = self.metrics_router();
+        let expiration_task = tokio::spawn(self.expire());
+        let retention_task = self
+            .config
+            .retention
+            .is_enabled()
+            .then(|| tokio::spawn(self.retain()));
+        let token = CancellationToken::new();
+        let _guard = token.clone().drop_guard();
+        {
+            let token = token.clone();
+            tokio::spawn(async move {
+                shutdown.await;
+                token.cancel();
+            });
+        }
+        let listeners = listeners
+            .into_iter()
+            .map(|listener| (listener, app.clone()))
+            .chain(
+                self.metrics_listeners
+                    .into_iter()
+                    .map(|listener| (listener, metrics_app.clone())),
+            );
+        let tasks = listeners.map(|(listener, app)| {
+            let shutdown = token.clone().cancelled_owned();
+            async move {
+                match listener {
+                    Listener::Tcp(listener) => {
+                        axum::serve(listener, app.into_make_service_with_connect_info::<Peer>())
+                            .with_graceful_shutdown(shutdown)
+                            .await
+                    }
+                    #[cfg(unix)]
+                    Listener::Unix(listener) => {
+                        axum::serve(listener, app.into_make_service_with_connect_info::<Peer>())
+                            .with_graceful_shutdown(shutdown)
+                            .await
+                    }
+                }
+            }
+        });
+        let result = try_join_all(tasks).await.map(|_| ());
+        expiration_task.abort();
+        if let Some(task) = retention_task {
+            task.abort();
+        }
+        result
+    }
+}
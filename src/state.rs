@@ -1,128 +1,130 @@
 use futures::{
-    compat::{Future01CompatExt, Stream01CompatExt},
-    lock::Mutex,
+    task::{noop_waker_ref, Context, Poll},
     Future, Stream, StreamExt,
 };
+use serde::{Deserialize, Serialize};
 use tokio::{
-    fs::{remove_file, File, OpenOptions},
-    io::{shutdown, write_all},
-    prelude::{future::poll_fn, Async as Async01, Future as Future01, Stream as Stream01},
-    timer::{delay_queue::Key as DQKey, DelayQueue, Interval},
+    sync::{broadcast, Mutex},
+    time::interval,
 };
+use tokio_stream::wrappers::IntervalStream;
+use tokio_util::time::{delay_queue::Key as DQKey, DelayQueue};
 use uuid::Uuid as UUID;
 
 use std::{
     cmp::min,
-    collections::HashMap,
-    ffi::{OsStr, OsString},
-    io::{self, SeekFrom},
-    ops::Drop,
-    path::PathBuf,
-    sync::Arc,
-    time::Duration,
+    collections::{HashMap, HashSet},
+    io,
+    path::{absolute, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-use crate::{bitmap::BitMap, error::Error, opt::OPT};
+use crate::{
+    audit::{AuditLog, AuditRecord, Outcome, Uploader},
+    bitmap::BitMap,
+    buffer::{SparseBuffer, WriteBuffer},
+    compress::{compress_stream, Codec},
+    config::Config,
+    dedup::{DedupIndex, DedupMode},
+    encrypt::EncryptedStorage,
+    error::Error,
+    events::{UploadEvent, EVENTS_CAPACITY},
+    extract::{create_extract_dir, extract, ArchiveFormat, Extracted},
+    filter::{sniff, sniff_type, Piece, SNIFF_SIZE},
+    fs::{remove_empty_dirs, TEMP_MARK},
+    history::{HistoryEntry, HistoryQuery, HistoryStore},
+    metrics::Metrics,
+    retention::move_object,
+    storage::{LocalStorage, Object, Staging, Storage},
+};
 
-static EXPIRATION_INTERVAL: Duration = Duration::from_secs(30);
+/// The age beyond which the cached usage of the storage is listed again.
+const USAGE_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
 
-macro_rules! try_finally {
-    ($expr:expr, $finally:expr) => {
-        match $expr {
-            Ok(val) => val,
-            Err(err) => {
-                $finally;
-                return Err(From::from(err));
-            }
-        }
-    };
+/// The information of a pending file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingUpload {
+    pub token: UUID,
+    pub name: String,
+    pub size: u64,
+    #[serde(flatten)]
+    pub uploader: Uploader,
+    /// The number of bytes of the chunks filled so far
+    pub received: u64,
 }
 
-#[allow(unused)]
-async fn create_file(
-    file_name: impl AsRef<OsStr>,
-    ext_hint: Option<impl AsRef<OsStr>>,
-) -> io::Result<(File, PathBuf)> {
-    // TODO: Create temporary file for pending task and then rename it
-    let path = PathBuf::from(file_name.as_ref());
-    let stem = path
-        .file_stem()
-        .unwrap_or_else(|| OsStr::new("UnnamedFile"));
-    let ext = path
-        .extension()
-        .or_else(|| ext_hint.as_ref().map(|i| i.as_ref()));
-
-    let mut count = 0;
-    loop {
-        let file_name = if count == 0 {
-            let mut s = OsString::from(stem);
-            if let Some(ext) = ext {
-                s.push(".");
-                s.push(ext);
-            }
-            s
-        } else {
-            let mut s = OsString::from(stem);
-            s.push("_");
-            s.push(count.to_string());
-            if let Some(ext) = ext {
-                s.push(".");
-                s.push(ext);
-            }
-            s
-        };
-        let path = OPT.dir().join(file_name);
-        let result = OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(path.clone())
-            .compat()
-            .await;
-        match result {
-            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => (),
-            Err(e) => return Err(e),
-            Ok(file) => return Ok((file, path)),
-        }
-        count += 1;
-    }
+/// The outcome of a finished upload.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Finished {
+    /// The number of bytes of the file received
+    pub size: u64,
+    /// The size of the stored file if compressed
+    pub compressed_size: Option<u64>,
+    /// The path of the identical file stored before if deduplicated against it
+    pub duplicate_of: Option<PathBuf>,
+    /// The entries extracted if the file is an archive to extract
+    pub extracted: Option<Extracted>,
+    /// The reason why the archive is not extracted if failed
+    pub extract_error: Option<String>,
 }
 
 #[derive(Debug)]
 struct PendingFile {
     token: UUID,
     name: String,
+    uploader: Uploader,
     size: usize,
     path: PathBuf,
-    handle: Option<File>,
+    /// The staging object shared by all the ongoing chunk writes, which is taken out once the file
+    /// is finished or canceled
+    handle: Option<Arc<dyn Staging>>,
     chunk_size: usize,
     /// Chunks bitmap
     chunks: Vec<u8>,
+    /// Bitmap of the chunks being written currently
+    writing: Vec<u8>,
     /// The number of filled chunks
     filled: usize,
+    /// The number of bytes of the filled chunks
+    received: u64,
+    /// The head of the file received so far in any chunks, if types are filtered
+    head: Option<SparseBuffer>,
+    /// Whether the type of the file has been sniffed from its head
+    sniffed: bool,
 }
 
 impl PendingFile {
     pub fn new(
         token: UUID,
         name: String,
+        uploader: Uploader,
         size: usize,
-        path: PathBuf,
-        handle: File,
+        handle: Arc<dyn Staging>,
         chunk_size: usize,
     ) -> Self {
-        //let file = await File
+        let path = handle.path().to_owned();
         let handle = Some(handle);
         let chunks = vec![];
+        let writing = vec![];
         let filled = 0;
         PendingFile {
             token,
             name,
+            uploader,
             size,
             path,
             handle,
             chunk_size,
             chunks,
+            writing,
             filled,
+            received: 0,
+            head: None,
+            sniffed: false,
         }
     }
 
@@ -136,257 +138,946 @@ impl PendingFile {
         }
     }
 
-    pub async fn write_chunk(
+    /// Mark the chunk as being written and return the handle along with the position and the size
+    /// of the chunk, so that the data can be written without holding the lock of the file.
+    ///
+    /// Either `commit_chunk` or `abort_chunk` is expected to be called after the write ends.
+    pub fn reserve_chunk(
         &mut self,
         chunk_index: usize,
-        mut data: impl Stream<Item = io::Result<impl AsRef<[u8]>>> + Unpin,
-    ) -> Result<usize, Error> {
+    ) -> Result<(Arc<dyn Staging>, usize, usize), Error> {
+        if chunk_index >= self.chunk_number() {
+            return Err(Error::InvalidChunkIndex);
+        }
         if self.chunks.get_bit(chunk_index) {
             return Err(Error::ChunkAlreadyWritten);
         }
-        let pos = self.chunk_size * chunk_index;
-        if pos > self.size {
-            return Err(Error::InvalidChunkIndex);
+        if self.writing.get_bit(chunk_index) {
+            return Err(Error::ChunkBeingWritten);
         }
-        let file = self.handle.take().unwrap();
+        // the handle is absent only if the file has been canceled by a failed write of another chunk
+        let handle = self
+            .handle
+            .clone()
+            .ok_or_else(|| Error::from(io::Error::from(io::ErrorKind::NotFound)))?;
+        self.writing.set_bit(chunk_index);
+        let pos = self.chunk_size * chunk_index;
         let size = min(self.chunk_size, self.size - pos);
-        let mut file = file.seek(SeekFrom::Start(pos as u64)).compat().await?.0;
-        let mut count = 0;
-        while let Some(bytes) = data.next().await {
-            let bytes = try_finally!(bytes, self.handle = Some(file));
-            count += bytes.as_ref().len();
-            if count > size {
-                self.handle = Some(file);
-                return Err(Error::DataNotFitIn(pos + count));
-            }
-            // TODO: it seems that write_all flushes by design, which may result in unbearable
-            // performance penalty
-            file = write_all(file, bytes).compat().await?.0;
-        }
-        self.handle = Some(file);
-        if count != size {
-            return Err(Error::DataNotFitIn(pos + count));
-        }
+        Ok((handle, pos, size))
+    }
+
+    /// Mark the reserved chunk as filled.
+    pub fn commit_chunk(&mut self, chunk_index: usize) {
+        debug_assert!(self.writing.get_bit(chunk_index));
+        self.writing.unset_bit(chunk_index);
         self.chunks.set_bit(chunk_index);
         self.filled += 1;
-        Ok(size)
+        let pos = self.chunk_size * chunk_index;
+        self.received += min(self.chunk_size, self.size - pos) as u64;
+    }
+
+    /// Copy the piece of the head of the file written at `pos`, return the type of the file
+    /// sniffed once the head has been received completely, which happens only once.
+    pub fn fill_head(&mut self, pos: usize, piece: &[u8]) -> Option<&'static str> {
+        if self.sniffed {
+            return None;
+        }
+        let len = min(SNIFF_SIZE, self.size);
+        let head = self.head.get_or_insert_with(|| SparseBuffer::new(len));
+        if pos < len {
+            head.write(pos, &piece[..min(piece.len(), len - pos)]);
+        }
+        if !head.is_full() {
+            return None;
+        }
+        self.sniffed = true;
+        let head = self.head.take().expect("Head being received").into_inner();
+        Some(sniff_type(&head))
+    }
+
+    /// Release the reserved chunk without marking it as filled, so that it can be written again.
+    pub fn abort_chunk(&mut self, chunk_index: usize) {
+        debug_assert!(self.writing.get_bit(chunk_index));
+        self.writing.unset_bit(chunk_index);
     }
 
     pub async fn finish(&mut self) -> Result<(), Error> {
-        // avoid async fn here to minimize possile contention (but is it really necessary?)
         debug_assert!(self.filled <= self.chunk_number());
         if self.filled < self.chunk_number() {
             return Err(Error::FileNotFilledUp(self.chunks.first_unset()));
         }
-        let mut file = self.handle.take().expect("Take file out");
-        poll_fn(|| file.poll_sync_data())
-            .map_err(|e| Error::from(e))
-            .compat()
-            .await?;
-        // self.handle = // Do not give back. O.W. the file will be removed when `self.drop`.
-        let _ = Some(shutdown(file).map_err(|e| Error::from(e)).compat().await?);
+        // all chunks are filled, so no other writes are ongoing
+        let file = self.handle.take().expect("Take file out");
+        // Do not give back even if failed, so that the staging object is aborted once dropped.
+        file.finalize().await?;
         info!("Uploaded file: {:?}", &self.path);
         Ok(())
-        // // TODO: move the temporary file (not implemented yet) to the final dest
-        // Ok(poll_fn(move || file.poll_sync_data(); file)
-        //     .and_then(|_| shutdown(file))
-        //     .map(|_| {
-        //         //info!("Uploaded file: {:?}", self.path.clone());
-        //         ()
-        //     })
-        //     .compat())
-    }
-
-    pub fn cancel(&mut self) -> impl Future<Output = io::Result<()>> {
-        // take the file out and drop it,
-        // then remove the file
-        let path = self.path.clone();
-        shutdown(self.handle.take().expect("Take file out"))
-            .then(|_| remove_file(path)) // TODO: map to chain error?
-            .compat()
+    }
+
+    pub async fn cancel(&mut self) -> io::Result<()> {
+        // take the staging object out and abort it
+        match self.handle.take() {
+            Some(file) => file.abort().await, // TODO: map to chain error?
+            // already canceled
+            None => Ok(()),
+        }
     }
 }
 
-// TODO: AsyncWrite::shutdown-list method for PendingFile
-impl Drop for PendingFile {
-    fn drop(&mut self) {
-        use std::fs::remove_file;
-        // synchronously remove the file if it is not taked by `cancel`
-        // TODO: Is it really dropped & closed here?
-        if let Some(_) = self.handle.take() {
-            trace!(
-                "Synchronously remove the file: {}, result: {:?}",
-                self.path.to_str().unwrap_or("INVALID_ENCODING_IN_PATH"),
-                remove_file(&self.path)
-            );
+/// Write the data of a chunk reserved by `PendingFile::reserve_chunk` to the position `pos` of the
+/// file.
+async fn write_chunk_at(
+    file: Arc<dyn Staging>,
+    pos: usize,
+    size: usize,
+    buffer_size: usize,
+    data: impl Stream<Item = io::Result<impl AsRef<[u8]>>> + Unpin,
+) -> Result<usize, Error> {
+    let count = write_stream_at(&*file, pos, Some(size), buffer_size, data).await?;
+    if count != size {
+        return Err(Error::DataNotFitIn(pos + count));
+    }
+    Ok(size)
+}
+
+/// Write all the data from the stream to the file starting from the position `pos`, return the
+/// number of bytes written.
+///
+/// Instead of issuing a write per frame, the data is coalesced by a `WriteBuffer` of the size
+/// `buffer_size`. `Error::DataNotFitIn` is returned once the data exceeds `limit`.
+async fn write_stream_at(
+    file: &dyn Staging,
+    pos: usize,
+    limit: Option<usize>,
+    buffer_size: usize,
+    mut data: impl Stream<Item = io::Result<impl AsRef<[u8]>>> + Unpin,
+) -> Result<usize, Error> {
+    let mut buffer = WriteBuffer::new(buffer_size, pos as u64);
+    let mut count = 0;
+    while let Some(bytes) = data.next().await {
+        let bytes = bytes?;
+        let mut bytes = bytes.as_ref();
+        count += bytes.len();
+        if let Some(limit) = limit {
+            if count > limit {
+                return Err(Error::DataNotFitIn(pos + count));
+            }
+        }
+        while !bytes.is_empty() {
+            let n = buffer.fill(bytes);
+            bytes = &bytes[n..];
+            if buffer.is_full() {
+                let (buf, at) = buffer.take();
+                buffer.recycle(file.write_at(buf, at).await?);
+            }
         }
     }
+    if !buffer.is_empty() {
+        let (buf, at) = buffer.take();
+        file.write_at(buf, at).await?;
+    }
+    Ok(count)
+}
+
+/// A pending file tracked by the queue.
+struct PendingEntry {
+    file: Arc<Mutex<PendingFile>>,
+    /// The key of its expiration, absent while the file is acquired
+    dqkey: Option<DQKey>,
+    /// The instant after which it expires regardless of activity
+    deadline: Option<Instant>,
+    /// The number of requests holding the file acquired, after which it is released
+    users: usize,
+    /// The information of the file kept up to date by the queue, so that pending files can be
+    /// listed without locking them
+    upload: PendingUpload,
+    path: PathBuf,
 }
 
 struct FileQueue {
-    pending_files: HashMap<UUID, (Arc<Mutex<PendingFile>>, Option<DQKey>)>,
+    pending_files: HashMap<UUID, PendingEntry>,
     expirations: DelayQueue<UUID>,
+    /// The period after which a pending file expires if no more chunks are received
+    pending_timeout: Duration,
+    /// The period after which a pending file expires regardless of activity
+    max_lifetime: Option<Duration>,
 }
 
-impl Default for FileQueue {
-    fn default() -> Self {
+impl FileQueue {
+    pub fn new(pending_timeout: Duration, max_lifetime: Option<Duration>) -> Self {
         FileQueue {
             pending_files: HashMap::default(),
             expirations: DelayQueue::with_capacity(0),
+            pending_timeout,
+            max_lifetime,
         }
     }
-}
 
-impl FileQueue {
-    pub async fn keep_expiring(this: Arc<Mutex<FileQueue>>) {
-        debug!(
-            "Pending files expiration task starts with interval {:?}",
-            EXPIRATION_INTERVAL
-        );
-        let mut interval = Interval::new_interval(EXPIRATION_INTERVAL).compat();
-        while let Some(Ok(instant)) = interval.next().await {
-            let expired = FileQueue::expire(&this).await;
-            debug!("{} pending files expired at {:?}", expired, instant);
-        }
-        error!("Pending files expiration task terminates unexpectedly!");
-    }
-
-    async fn expire(this: &Arc<Mutex<FileQueue>>) -> usize {
+    /// Remove the pending files expired from the queue, return them to be canceled.
+    fn take_expired(&mut self) -> Vec<Arc<Mutex<PendingFile>>> {
         let mut expired = vec![];
-        {
-            let mut file_queue = this.lock().await;
-            while let Some(entry) = match file_queue.expirations.poll() {
-                Ok(Async01::Ready(t)) => t,
-                // according to the doc of DelayQueue,
-                // NotReady indicates that there are some unexpired
-                Ok(Async01::NotReady) => None,
-                Err(e) => {
-                    warn!("Error when polling expirations: {}", e);
-                    None
-                }
-            } {
-                trace!("File expired: {}", entry.get_ref().to_hyphenated());
-                if let Some(file) = file_queue.pending_files.remove(entry.get_ref()) {
-                    expired.push(file.0);
-                } else {
-                    unreachable!(
-                        "File not found when expiring, UUID: {}",
-                        entry.get_ref().to_hyphenated()
-                    );
-                }
-            }
-            if file_queue.pending_files.len() > 0 {
-                debug!("Pending files: {}.", file_queue.pending_files.len());
+        // Polling with a no-op waker since the expirations are checked periodically anyway.
+        let mut cx = Context::from_waker(noop_waker_ref());
+        while let Some(entry) = match self.expirations.poll_expired(&mut cx) {
+            Poll::Ready(t) => t,
+            // according to the doc of DelayQueue,
+            // Pending indicates that there are some unexpired
+            Poll::Pending => None,
+        } {
+            trace!("File expired: {}", entry.get_ref().hyphenated());
+            if let Some(pending) = self.pending_files.remove(entry.get_ref()) {
+                expired.push(pending.file);
+            } else {
+                unreachable!(
+                    "File not found when expiring, UUID: {}",
+                    entry.get_ref().hyphenated()
+                );
             }
-            // the lock to file_queue gets released hereinafter
         }
-        let count = expired.len();
-        for file in expired.into_iter() {
-            file.try_lock()
-                .expect("Expired file not held elsewhere")
-                .cancel()
-                .await
-                .unwrap_or_else(|e| {
-                    error!("Error when remove a stale file: {}", e);
-                });
+        if !self.pending_files.is_empty() {
+            debug!("Pending files: {}.", self.pending_files.len());
         }
-        count
+        expired
     }
 
     pub fn add_file(
         &mut self,
         name: String,
+        uploader: Uploader,
         size: usize,
-        path: PathBuf,
-        handle: File,
+        handle: Arc<dyn Staging>,
         chunk_size: usize,
     ) -> UUID {
         let token = UUID::new_v4();
-        let delay = self.expirations.insert(token, EXPIRATION_INTERVAL);
+        let deadline = self.max_lifetime.map(|lifetime| Instant::now() + lifetime);
+        let delay = self.expirations.insert(token, self.ttl_until(deadline));
+        let upload = PendingUpload {
+            token,
+            name: name.clone(),
+            size: size as u64,
+            uploader: uploader.clone(),
+            received: 0,
+        };
+        let file = PendingFile::new(token, name, uploader, size, handle, chunk_size);
         self.pending_files.insert(
             token,
-            (
-                Arc::new(Mutex::new(PendingFile::new(
-                    token, name, size, path, handle, chunk_size,
-                ))),
-                Some(delay),
-            ),
+            PendingEntry {
+                path: file.path.clone(),
+                file: Arc::new(Mutex::new(file)),
+                dqkey: Some(delay),
+                deadline,
+                users: 0,
+                upload,
+            },
         );
         token
     }
 
+    /// Get the period after which a pending file with the `deadline` expires if it keeps idle
+    /// from now on.
+    fn ttl_until(&self, deadline: Option<Instant>) -> Duration {
+        match deadline {
+            Some(deadline) => min(
+                self.pending_timeout,
+                deadline.saturating_duration_since(Instant::now()),
+            ),
+            None => self.pending_timeout,
+        }
+    }
+
+    /// Get the period after which the pending file expires if it keeps idle from now on.
+    pub fn ttl(&self, token: UUID) -> Result<Duration, Error> {
+        let pending = self
+            .pending_files
+            .get(&token)
+            .ok_or(Error::InvalidFileToken)?;
+        Ok(self.ttl_until(pending.deadline))
+    }
+
+    /// Acquire the pending file, disabling its expiration until released by all who acquire it.
     pub fn acquire_file(&mut self, token: UUID) -> Result<Arc<Mutex<PendingFile>>, Error> {
-        let (file, dqkey) = self
+        let pending = self
             .pending_files
             .get_mut(&token)
             .ok_or(Error::InvalidFileToken)?;
+        if pending
+            .deadline
+            .is_some_and(|deadline| deadline <= Instant::now())
+        {
+            // to be removed in the next sweep if not acquired by others
+            return Err(Error::FileExpired);
+        }
+        pending.users += 1;
         // the contention won't make dqkey invalid
-        if let Some(dqkey) = dqkey.take() {
+        if let Some(dqkey) = pending.dqkey.take() {
             // if no others have disabled the expiration
             self.expirations.remove(&dqkey);
             trace!(
                 "File {} acquired with expiration disabled.",
-                token.to_hyphenated()
+                token.hyphenated()
             );
         } else {
             trace!(
                 "File {} acquired, expiration has already been disabled.",
-                token.to_hyphenated()
+                token.hyphenated()
             );
         }
-        Ok(file.clone())
-    }
-
-    pub fn release_file(&mut self, token: UUID) -> Result<bool, Error> {
-        // TODO: doc this function properly
-        // note: the file won't get expired
-        // assuming that Arc<Mutex<PendingFile>> won't be cloned during the execution of the function
-        if let Some((file, dqkey)) = self.pending_files.get_mut(&token) {
-            debug_assert!(dqkey.is_none());
-            // assuming that all unused references are dropped before
-            let ref_count = Arc::strong_count(file);
-            if ref_count == 1 {
-                *dqkey = Some(self.expirations.insert(token, EXPIRATION_INTERVAL));
-                trace!("File {} released.", token.to_hyphenated());
-                Ok(true)
-            } else {
-                trace!(
-                    "File {} not released with {} references holden.",
-                    token.to_hyphenated(),
-                    ref_count
-                );
-                Ok(false)
-            }
+        Ok(pending.file.clone())
+    }
+
+    /// Release the pending file acquired by `acquire_file`, updating the bytes received so far,
+    /// return whether its expiration is enabled again, i.e. it is not acquired by others.
+    pub fn release_file(&mut self, token: UUID, received: Option<u64>) -> Result<bool, Error> {
+        let ttl = self.ttl(token)?;
+        let pending = self
+            .pending_files
+            .get_mut(&token)
+            .ok_or(Error::InvalidFileToken)?;
+        debug_assert!(pending.dqkey.is_none() && pending.users > 0);
+        if let Some(received) = received {
+            // chunks may be committed in a different order than released
+            pending.upload.received = pending.upload.received.max(received);
+        }
+        pending.users -= 1;
+        if pending.users == 0 {
+            pending.dqkey = Some(self.expirations.insert(token, ttl));
+            trace!("File {} released.", token.hyphenated());
+            Ok(true)
         } else {
-            Err(Error::InvalidFileToken)
+            trace!(
+                "File {} not released with {} users.",
+                token.hyphenated(),
+                pending.users
+            );
+            Ok(false)
         }
     }
 
     /// Discard a file by removing it in the `pending_files` list
     ///
     /// Be sure to acquire_file before calling this.
-    pub fn discard(&mut self, token: UUID) -> Result<(), Error> {
-        let (_file, _dqkey) = self
+    pub fn discard(&mut self, token: UUID) -> Result<PathBuf, Error> {
+        let pending = self
             .pending_files
             .remove(&token)
             .ok_or(Error::InvalidFileToken)?;
-        assert!(_dqkey.is_none());
-        Ok(())
+        assert!(pending.dqkey.is_none());
+        Ok(pending.path)
+    }
+}
+
+/// The paths being written besides the pending files, i.e. the files received in full, compressed
+/// or linked and the directories extracted into, which are left alone by the retention policy.
+#[derive(Debug, Default)]
+struct Writing(std::sync::Mutex<HashSet<PathBuf>>);
+
+impl Writing {
+    /// Mark the path as being written until the guard returned is dropped.
+    fn hold(self: &Arc<Self>, path: PathBuf) -> WritingGuard {
+        self.0.lock().unwrap().insert(path.clone());
+        WritingGuard(self.clone(), path)
+    }
+
+    /// Whether the path is being written, or is under a directory being written.
+    fn covers(&self, path: &Path) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|held| path.starts_with(held))
     }
 }
 
-#[derive(Default)]
+struct WritingGuard(Arc<Writing>, PathBuf);
+
+impl Drop for WritingGuard {
+    fn drop(&mut self) {
+        self.0 .0.lock().unwrap().remove(&self.1);
+    }
+}
+
+/// The state of the upload engine shared by all requests, which keeps track of pending files.
+#[derive(Clone)]
 pub struct State {
     file_queue: Arc<Mutex<FileQueue>>,
+    config: Arc<Config>,
+    storage: Arc<dyn Storage>,
+    metrics: Arc<Metrics>,
+    /// Whether the task returned by `expire` is running
+    expiring: Arc<AtomicBool>,
+    audit_log: Option<Arc<AuditLog>>,
+    history: Option<Arc<HistoryStore>>,
+    events: broadcast::Sender<UploadEvent>,
+    dedup_index: Arc<DedupIndex>,
+    writing: Arc<Writing>,
+    /// The total size of the stored objects along with when it was listed, which is kept up to
+    /// date as files are stored or cleaned up in between
+    usage: Arc<Mutex<Option<(u64, Instant)>>>,
 }
 
 impl State {
-    pub fn new() -> Self {
-        Default::default()
+    /// Construct the state storing files into the directory in the config.
+    pub fn new(config: Arc<Config>) -> Self {
+        let storage = Arc::new(LocalStorage::new(config.dir.clone()));
+        Self::with_storage(config, storage)
+    }
+
+    /// Construct the state storing files into the storage, instead of the directory in the config.
+    ///
+    /// The storage is wrapped to encrypt files if enabled.
+    pub fn with_storage(config: Arc<Config>, storage: Arc<dyn Storage>) -> Self {
+        let storage = match config.encrypt_to {
+            Some(recipient) => Arc::new(EncryptedStorage::new(storage, recipient)),
+            None => storage,
+        };
+        let audit_log = config.audit_log.as_ref().map(|path| {
+            Arc::new(AuditLog::new(
+                path,
+                config.audit_log_max_size,
+                config.audit_log_keep,
+            ))
+        });
+        let history = config
+            .history
+            .as_ref()
+            .map(|path| Arc::new(HistoryStore::new(path)));
+        State {
+            file_queue: Arc::new(Mutex::new(FileQueue::new(
+                config.pending_timeout,
+                config.pending_max_lifetime,
+            ))),
+            config,
+            storage,
+            metrics: Arc::new(Metrics::new()),
+            expiring: Arc::new(AtomicBool::new(false)),
+            audit_log,
+            history,
+            events: broadcast::channel(EVENTS_CAPACITY).0,
+            dedup_index: Arc::new(DedupIndex::new()),
+            writing: Arc::new(Writing::default()),
+            usage: Arc::new(Mutex::new(None)),
+        }
     }
 
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn storage(&self) -> &Arc<dyn Storage> {
+        &self.storage
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Get the task that keeps expiring stale pending files.
     pub fn expire(&self) -> impl Future<Output = ()> {
-        FileQueue::keep_expiring(self.file_queue.clone())
+        self.clone().keep_expiring()
+    }
+
+    async fn keep_expiring(self) {
+        // reset the flag however the task ends, including being aborted or panicking
+        struct Running(Arc<AtomicBool>);
+        impl Drop for Running {
+            fn drop(&mut self) {
+                self.0.store(false, Ordering::SeqCst);
+            }
+        }
+        self.expiring.store(true, Ordering::SeqCst);
+        let _running = Running(self.expiring.clone());
+        let sweep_interval = self.config.sweep_interval;
+        debug!(
+            "Pending files expiration task starts with interval {:?}",
+            sweep_interval
+        );
+        let mut interval = IntervalStream::new(interval(sweep_interval));
+        while let Some(instant) = interval.next().await {
+            let expired = self.expire_once().await;
+            self.metrics.uploads_expired.inc_by(expired as u64);
+            debug!("{} pending files expired at {:?}", expired, instant);
+        }
+        error!("Pending files expiration task terminates unexpectedly!");
+    }
+
+    /// Cancel the pending files expired, return the number of them.
+    async fn expire_once(&self) -> usize {
+        // the lock to file_queue gets released immediately
+        let expired = self.file_queue.lock().await.take_expired();
+        let count = expired.len();
+        for file in expired.into_iter() {
+            let mut file = file.try_lock().expect("Expired file not held elsewhere");
+            file.cancel().await.unwrap_or_else(|e| {
+                error!("Error when remove a stale file: {}", e);
+            });
+            self.emit(UploadEvent::Expire { token: file.token });
+            let mut record =
+                AuditRecord::new(file.uploader.clone(), file.name.clone(), Outcome::Expired);
+            record.size = Some(file.size as u64);
+            self.audit(record).await;
+        }
+        count
+    }
+
+    /// Get the task that keeps cleaning up stored files by the retention policy.
+    pub fn retain(&self) -> impl Future<Output = ()> {
+        self.clone().keep_retaining()
+    }
+
+    async fn keep_retaining(self) {
+        let retention_interval = self.config.retention.interval;
+        debug!(
+            "Retention task starts with interval {:?}",
+            retention_interval
+        );
+        let mut interval = IntervalStream::new(interval(retention_interval));
+        while interval.next().await.is_some() {
+            match self.retain_once().await {
+                Ok(cleaned) if !cleaned.is_empty() => {
+                    info!("{} stored files cleaned up by retention", cleaned.len())
+                }
+                Ok(_) => (),
+                Err(e) => error!("Failed to apply the retention policy: {}", e),
+            }
+        }
+        error!("Retention task terminates unexpectedly!");
+    }
+
+    /// List the files received and stored, leaving out those pending or being written, and the
+    /// files of intray itself, i.e. the history, the audit log along with its rotated ones and
+    /// temporary files.
+    async fn list_stored(&self) -> io::Result<Vec<Object>> {
+        let pending = self.pending_paths().await;
+        let own_file = |path: &Option<PathBuf>| path.as_deref().and_then(|p| absolute(p).ok());
+        let (history, audit_log) = (
+            own_file(&self.config.history),
+            own_file(&self.config.audit_log),
+        );
+        let is_own = |path: &Path| {
+            let Ok(path) = absolute(path) else {
+                return false;
+            };
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            name.contains(TEMP_MARK)
+                || history.as_deref() == Some(&path)
+                || audit_log.as_ref().is_some_and(|log| {
+                    // rotated as `audit.log.1`, `audit.log.2` and so on
+                    let log_name = log.file_name().unwrap_or_default().to_string_lossy();
+                    path.parent() == log.parent()
+                        && name.strip_prefix(&*log_name).is_some_and(|suffix| {
+                            suffix.is_empty()
+                                || suffix
+                                    .strip_prefix('.')
+                                    .is_some_and(|n| n.parse::<usize>().is_ok())
+                        })
+                })
+        };
+        let objects = self.storage.list().await?;
+        Ok(objects
+            .into_iter()
+            .filter(|object| {
+                !(pending.contains(&object.path)
+                    || self.writing.covers(&object.path)
+                    || is_own(&object.path))
+            })
+            .collect())
+    }
+
+    /// Clean up the stored files by the retention policy once, except those pending or being
+    /// written and the files of intray itself, return the paths of those cleaned up, or of those
+    /// to clean up in the dry-run mode.
+    ///
+    /// The directories left empty are removed as well.
+    pub async fn retain_once(&self) -> io::Result<Vec<PathBuf>> {
+        let policy = &self.config.retention;
+        if !policy.is_enabled() {
+            return Ok(vec![]);
+        }
+        let objects = self
+            .list_stored()
+            .await?
+            .into_iter()
+            .filter(|object| {
+                !policy
+                    .move_to
+                    .as_ref()
+                    .is_some_and(|dir| object.path.starts_with(dir))
+            })
+            .collect();
+        let action = if policy.move_to.is_some() {
+            "move"
+        } else {
+            "delete"
+        };
+        let mut cleaned = vec![];
+        for (object, reason) in policy.select(objects, SystemTime::now()) {
+            let (path, size) = (object.path, object.size);
+            if policy.dry_run {
+                info!("Would {} {:?} ({} bytes), {}", action, path, size, reason);
+                cleaned.push(path);
+                continue;
+            }
+            let result = match policy.move_to {
+                Some(ref dir) => move_object(&*self.storage, &path, &self.config.dir, dir)
+                    .await
+                    .map(|target| {
+                        info!(
+                            "Moved {:?} ({} bytes) to {:?}, {}",
+                            path, size, target, reason
+                        )
+                    }),
+                None => self
+                    .storage
+                    .remove(&path)
+                    .await
+                    .map(|_| info!("Deleted {:?} ({} bytes), {}", path, size, reason)),
+            };
+            match result {
+                Ok(()) => {
+                    self.account_usage(-(size as i64)).await;
+                    remove_empty_dirs(&path, &self.config.dir).await;
+                    cleaned.push(path);
+                }
+                Err(e) => warn!("Failed to {} {:?}: {}", action, path, e),
+            }
+        }
+        Ok(cleaned)
+    }
+
+    /// Check whether pending files are being expired by the task returned by `expire`.
+    pub fn is_expiring(&self) -> bool {
+        self.expiring.load(Ordering::SeqCst)
+    }
+
+    /// Get the number of pending files currently tracked.
+    pub async fn pending_count(&self) -> usize {
+        self.file_queue.lock().await.pending_files.len()
+    }
+
+    /// Get the space (in bytes) available in the storage, `None` if unknown or unlimited.
+    pub async fn disk_free(&self) -> io::Result<Option<u64>> {
+        self.storage.available_space().await
+    }
+
+    /// Get the total size (in bytes) of the objects in the storage.
+    ///
+    /// Since it takes listing all the objects, the size is cached and only listed again once it
+    /// is older than `USAGE_REFRESH_INTERVAL`, while the files stored or cleaned up by intray are
+    /// accounted for meanwhile.
+    pub async fn dir_usage(&self) -> io::Result<u64> {
+        // locked while listing, so that the storage is listed once at a time
+        let mut usage = self.usage.lock().await;
+        match *usage {
+            Some((bytes, listed)) if listed.elapsed() < USAGE_REFRESH_INTERVAL => Ok(bytes),
+            _ => {
+                let objects = self.storage.list().await?;
+                let bytes = objects.iter().map(|object| object.size).sum();
+                *usage = Some((bytes, Instant::now()));
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Account for an object of the size stored, or removed if negative, in the cached usage.
+    async fn account_usage(&self, delta: i64) {
+        if let Some((bytes, _)) = self.usage.lock().await.as_mut() {
+            *bytes = bytes.saturating_add_signed(delta);
+        }
+    }
+
+    /// Subscribe to the events of uploads emitted from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<UploadEvent> {
+        self.events.subscribe()
+    }
+
+    fn emit(&self, event: UploadEvent) {
+        // fails only if there are no subscribers
+        let _ = self.events.send(event);
+    }
+
+    /// Get the information of all the pending files, with the bytes received so far.
+    ///
+    /// The files are not locked, so the listing never holds up the uploads or their expiration.
+    pub async fn pending_uploads(&self) -> Vec<PendingUpload> {
+        let file_queue = self.file_queue.lock().await;
+        file_queue
+            .pending_files
+            .values()
+            .map(|pending| pending.upload.clone())
+            .collect()
+    }
+
+    /// Get the paths of all the pending files, without locking them like `pending_uploads`.
+    async fn pending_paths(&self) -> HashSet<PathBuf> {
+        let file_queue = self.file_queue.lock().await;
+        file_queue
+            .pending_files
+            .values()
+            .map(|pending| pending.path.clone())
+            .collect()
+    }
+
+    /// Cancel the pending file by an administrator, removing the partial file and revoking the
+    /// token.
+    pub async fn cancel_upload(&self, file_token: UUID) -> Result<(), Error> {
+        let file = {
+            let mut file_queue = self.file_queue.lock().await;
+            let file = file_queue.acquire_file(file_token)?;
+            file_queue.discard(file_token)?;
+            file
+        };
+        let mut file = file.lock().await;
+        file.cancel().await?;
+        info!("Upload canceled: {:?}", file.path);
+        self.metrics.uploads_cancelled.inc();
+        let error = String::from("The upload has been canceled by an administrator.");
+        self.emit(UploadEvent::Cancel {
+            token: file_token,
+            error: error.clone(),
+        });
+        let mut record =
+            AuditRecord::new(file.uploader.clone(), file.name.clone(), Outcome::Canceled);
+        record.size = Some(file.size as u64);
+        record.error = Some(error);
+        self.audit(record).await;
+        Ok(())
+    }
+
+    /// Append the record to the audit log if enabled, logging errors if any.
+    async fn audit(&self, record: AuditRecord) {
+        if let Some(audit_log) = self.audit_log.clone() {
+            let result = tokio::task::spawn_blocking(move || audit_log.append(&record)).await;
+            if let Err(e) = result.map_err(io::Error::from).and_then(|r| r) {
+                error!("Failed to write audit log: {}", e);
+            }
+        }
+    }
+
+    /// Settle a finished upload stored at `path`, which may be compressed: deduplicate it if
+    /// enabled, emit the event and record it.
+    async fn settle_finished(
+        &self,
+        token: UUID,
+        uploader: Uploader,
+        name: String,
+        path: PathBuf,
+        size: usize,
+        compressed_size: Option<u64>,
+    ) -> Finished {
+        let sha256 =
+            if self.config.dedup.is_some() || self.audit_log.is_some() || self.history.is_some() {
+                self.storage
+                    .sha256(&path)
+                    .await
+                    .inspect_err(|e| warn!("Failed to digest {:?}: {}", path, e))
+                    .ok()
+            } else {
+                None
+            };
+        let mut original = None;
+        // a file kept is a candidate for identical ones until settled
+        let mut _settling = None;
+        if let (Some(mode), Some(digest)) = (self.config.dedup, sha256.as_deref()) {
+            let result = self
+                .dedup_index
+                .deduplicate(
+                    &*self.storage,
+                    mode,
+                    || self.list_stored(),
+                    &path,
+                    compressed_size.unwrap_or(size as u64),
+                    digest,
+                )
+                .await;
+            match result {
+                Ok((Some(found), _)) => {
+                    info!("Deduplicated {:?} against {:?} by {:?}", path, found, mode);
+                    original = Some(found);
+                }
+                Ok((None, settling)) => _settling = settling,
+                // the upload is kept as is
+                Err(e) => warn!("Failed to deduplicate {:?}: {}", path, e),
+            }
+        }
+        // the upload is gone if dropped, so refer to the identical file instead
+        let stored = match (self.config.dedup, &original) {
+            (Some(DedupMode::Drop), Some(original)) => original.clone(),
+            _ => {
+                self.account_usage(compressed_size.unwrap_or(size as u64) as i64)
+                    .await;
+                path
+            }
+        };
+        self.emit(UploadEvent::Finish {
+            token,
+            size: size as u64,
+            path: stored.clone(),
+        });
+        self.record_finished(
+            uploader,
+            name,
+            stored.clone(),
+            size,
+            compressed_size,
+            sha256,
+        )
+        .await;
+        let mut finished = Finished {
+            size: size as u64,
+            compressed_size,
+            duplicate_of: original,
+            ..Default::default()
+        };
+        // deduplicated archives are extracted already
+        if finished.duplicate_of.is_none() {
+            match self.extract_stored(&stored).await {
+                Some(Ok(extracted)) => finished.extracted = Some(extracted),
+                Some(Err(e)) => finished.extract_error = Some(e.to_string()),
+                None => (),
+            }
+        }
+        finished
+    }
+
+    /// Compress the stored file at `path` into a new one named after it with the extension of the
+    /// codec and remove the original, return the path and the size of the compressed file.
+    async fn compress_stored(&self, path: &Path, codec: Codec) -> Result<(PathBuf, u64), Error> {
+        let name = format!(
+            "{}.{}",
+            path.file_name().unwrap_or_default().to_string_lossy(),
+            codec.extension()
+        );
+        let data = self.storage.open(path).await?;
+        let file = self.storage.create(&name, None, None).await?;
+        let _writing = self.writing.hold(file.path().to_owned());
+        let buffer_size = self.config.write_buffer_size;
+        // the compressed file is aborted once dropped if failed
+        let count =
+            write_stream_at(&*file, 0, None, buffer_size, compress_stream(codec, data)).await?;
+        file.finalize().await?;
+        if let Err(e) = self.storage.remove(path).await {
+            warn!("Failed to remove {:?} after compressed: {}", path, e);
+        }
+        Ok((file.path().to_owned(), count as u64))
+    }
+
+    /// Extract the stored file at `path` if it is an archive in the formats to extract, which is
+    /// detected by its name, so compressed or encrypted files are never extracted.
+    async fn extract_stored(&self, path: &Path) -> Option<io::Result<Extracted>> {
+        let name = path.file_name()?.to_str()?;
+        let (format, stem) = ArchiveFormat::detect(name, &self.config.extract)?;
+        let limits = self.config.extract_limits();
+        let result = match create_extract_dir(path.to_owned(), stem).await {
+            Ok(dir) => {
+                let _writing = self.writing.hold(dir.clone());
+                extract(path.to_owned(), format, dir, limits).await
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(ref extracted) => info!(
+                "Extracted {} entries of {:?} into {:?}",
+                extracted.entries.len(),
+                path,
+                extracted.dir
+            ),
+            Err(ref e) => warn!("Failed to extract {:?}: {}", path, e),
+        }
+        Some(result)
+    }
+
+    /// Record a finished upload in the audit log and the history if enabled, along with the
+    /// digest of the file.
+    async fn record_finished(
+        &self,
+        uploader: Uploader,
+        name: String,
+        path: PathBuf,
+        size: usize,
+        compressed_size: Option<u64>,
+        sha256: Option<String>,
+    ) {
+        if self.audit_log.is_none() && self.history.is_none() {
+            return;
+        }
+        let mut record = AuditRecord::new(uploader, name, Outcome::Finished);
+        record.size = Some(size as u64);
+        record.sha256 = sha256;
+        record.path = Some(path.clone());
+        if let Some(history) = self.history.clone() {
+            let entry = HistoryEntry {
+                time: record.time.clone(),
+                uploader: record.uploader.clone(),
+                name: record.name.clone(),
+                size: size as u64,
+                compressed_size,
+                sha256: record.sha256.clone(),
+                path,
+            };
+            let result = tokio::task::spawn_blocking(move || history.append(&entry)).await;
+            if let Err(e) = result.map_err(io::Error::from).and_then(|r| r) {
+                error!("Failed to write history: {}", e);
+            }
+        }
+        self.audit(record).await;
+    }
+
+    /// Query the history of completed uploads, see `HistoryStore::query`.
+    ///
+    /// Nothing is matched if the history is disabled.
+    pub async fn query_history(
+        &self,
+        query: HistoryQuery,
+    ) -> io::Result<(Vec<HistoryEntry>, bool)> {
+        match self.history.clone() {
+            Some(history) => tokio::task::spawn_blocking(move || history.query(&query)).await?,
+            None => Ok((vec![], false)),
+        }
+    }
+
+    /// Record a failed upload in the audit log if enabled.
+    async fn audit_failed(&self, uploader: Uploader, name: String, size: usize, error: &Error) {
+        let mut record = AuditRecord::new(uploader, name, Outcome::Failed);
+        record.size = Some(size as u64);
+        record.error = Some(error.to_string());
+        self.audit(record).await;
+    }
+
+    /// Check the name of a file against the extensions allowed and denied.
+    fn check_name(&self, name: &str) -> Result<(), Error> {
+        if self.config.is_name_allowed(name) {
+            Ok(())
+        } else {
+            Err(Error::TypeNotAllowed(name.to_owned()))
+        }
+    }
+
+    /// Check the type of the content sniffed from the data of a whole file if types are filtered,
+    /// return the data intact.
+    async fn check_content<T: AsRef<[u8]>>(
+        &self,
+        data: impl Stream<Item = io::Result<T>> + Unpin,
+    ) -> Result<impl Stream<Item = io::Result<Piece<T>>> + Unpin, Error> {
+        let (mime, data) = sniff(data, self.config.filters_types()).await?;
+        match mime {
+            Some(mime) if !self.config.is_type_allowed(mime) => {
+                Err(Error::TypeNotAllowed(mime.to_owned()))
+            }
+            _ => Ok(data),
+        }
+    }
+
+    /// Wrap the data stream to count the bytes received.
+    fn count_received<T: AsRef<[u8]>>(
+        &self,
+        data: impl Stream<Item = io::Result<T>> + Unpin,
+    ) -> impl Stream<Item = io::Result<T>> + Unpin {
+        let counter = self.metrics.bytes_received.clone();
+        data.inspect(move |bytes| {
+            if let Ok(bytes) = bytes {
+                counter.inc_by(bytes.as_ref().len() as u64);
+            }
+        })
     }
 
     pub async fn start_upload(
@@ -394,14 +1085,88 @@ impl State {
         name: String,
         size: usize,
         chunk_size: usize,
-    ) -> io::Result<UUID> {
-        let (file, path) = create_file(&name, Option::<String>::None).await?;
-        // create_file is a async job which may take much time, so here to acquire the lock only after that
-        Ok(self
-            .file_queue
-            .lock()
-            .await
-            .add_file(name, size, path, file, chunk_size))
+        uploader: Uploader,
+    ) -> Result<UUID, Error> {
+        self.check_name(&name)?;
+        let file = self
+            .storage
+            .create(&name, Some(size as u64), Some(chunk_size as u64))
+            .await?;
+        // creating is a async job which may take much time, so here to acquire the lock only after that
+        let token = self.file_queue.lock().await.add_file(
+            name.clone(),
+            uploader.clone(),
+            size,
+            file,
+            chunk_size,
+        );
+        self.metrics.uploads_started.inc();
+        self.emit(UploadEvent::Start {
+            token,
+            name,
+            size: Some(size as u64),
+            uploader,
+            received: 0,
+        });
+        Ok(token)
+    }
+
+    /// Store the file to upload without receiving its data if deduplication is enabled along with
+    /// `dedup_present` and an identical file of the size and the hex-encoded SHA-256 digest is
+    /// stored already, return the path of the identical file if so.
+    ///
+    /// The file is stored as a hard link to the identical one in the link mode, or not at all in
+    /// the drop mode, just like a deduplicated upload.
+    pub async fn put_present(
+        &self,
+        name: String,
+        size: usize,
+        sha256: &str,
+        uploader: Uploader,
+    ) -> Result<Option<PathBuf>, Error> {
+        self.check_name(&name)?;
+        let Some(mode) = self.config.dedup.filter(|_| self.config.dedup_present) else {
+            return Ok(None);
+        };
+        if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The SHA-256 digest is not 64 hex digits.",
+            )
+            .into());
+        }
+        let sha256 = sha256.to_ascii_lowercase();
+        let objects = self.list_stored().await?;
+        let original = self
+            .dedup_index
+            .find(&*self.storage, objects, size as u64, &sha256)
+            .await?;
+        let Some(original) = original else {
+            return Ok(None);
+        };
+        let path = match mode {
+            DedupMode::Link => {
+                let file = self.storage.create(&name, Some(size as u64), None).await?;
+                let path = file.path().to_owned();
+                let _writing = self.writing.hold(path.clone());
+                file.finalize().await?;
+                if let Err(e) = self.storage.link(&original, &path).await {
+                    let _ = self.storage.remove(&path).await;
+                    return Err(e.into());
+                }
+                self.account_usage(size as i64).await;
+                path
+            }
+            DedupMode::Drop => original.clone(),
+        };
+        info!(
+            "Already present: {:?} as {:?}, stored at {:?}",
+            name, original, path
+        );
+        self.metrics.uploads_finished.inc();
+        self.record_finished(uploader, name, path, size, None, Some(sha256))
+            .await;
+        Ok(Some(original))
     }
 
     pub async fn put_chunk(
@@ -410,87 +1175,257 @@ impl State {
         chunk_index: usize,
         data: impl Stream<Item = io::Result<impl AsRef<[u8]>>> + Unpin,
     ) -> Result<usize, Error> {
+        let mut received = None;
         let result = {
             // drop file_queue lock immediately
-            let _file = self.file_queue.lock().await.acquire_file(file_token)?;
-            let mut file = _file.lock().await;
-            // TODO: Does the lock/unlock sequence work as expected?
-            match file.write_chunk(chunk_index, data).await {
-                Err(Error::Io(e)) => {
-                    // already an IO error here, so discarding the new one
-                    let _ = file.cancel().await;
-                    Err(Error::Io(e))
+            let file = self.file_queue.lock().await.acquire_file(file_token)?;
+            // the lock of the file is only held when reserving and committing the chunk, so that
+            // chunks of one file can be written concurrently
+            let reservation = file.lock().await.reserve_chunk(chunk_index);
+            match reservation {
+                Ok((handle, pos, size)) => {
+                    let start = Instant::now();
+                    // the head of the file is sniffed once received, whichever chunks carry it,
+                    // so that the order of the chunks and their sizes make no difference
+                    let sniffs = self.config.filters_types();
+                    let head_len = if sniffs {
+                        SNIFF_SIZE.saturating_sub(pos)
+                    } else {
+                        0
+                    };
+                    let mut head = vec![];
+                    let data = self.count_received(data).inspect(|bytes| {
+                        if let Ok(bytes) = bytes {
+                            let bytes = bytes.as_ref();
+                            let n = min(head_len - head.len(), bytes.len());
+                            head.extend_from_slice(&bytes[..n]);
+                        }
+                    });
+                    let buffer_size = self.config.write_buffer_size;
+                    let result = write_chunk_at(handle, pos, size, buffer_size, data).await;
+                    self.metrics.observe_chunk_write(start.elapsed());
+                    let mut file = file.lock().await;
+                    let result = match result {
+                        Ok(count) if sniffs => match file.fill_head(pos, &head) {
+                            Some(mime) if !self.config.is_type_allowed(mime) => {
+                                Err(Error::TypeNotAllowed(mime.to_owned()))
+                            }
+                            _ => Ok(count),
+                        },
+                        result => result,
+                    };
+                    match result {
+                        Ok(_) => {
+                            file.commit_chunk(chunk_index);
+                            received = Some(file.received);
+                            self.emit(UploadEvent::Chunk {
+                                token: file_token,
+                                index: chunk_index,
+                                received: file.received,
+                            });
+                        }
+                        // the file is rejected as a whole if its content is not allowed
+                        Err(ref e @ (Error::Io(_) | Error::TypeNotAllowed(_))) => {
+                            // already an IO error here, so discarding the new one
+                            let _ = file.cancel().await;
+                            self.emit(UploadEvent::Cancel {
+                                token: file_token,
+                                error: e.to_string(),
+                            });
+                            let (uploader, name) = (file.uploader.clone(), file.name.clone());
+                            self.audit_failed(uploader, name, file.size, e).await;
+                        }
+                        Err(_) => file.abort_chunk(chunk_index),
+                    }
+                    result
                 }
-                other => other,
+                Err(e) => Err(e),
             }
         };
         let mut file_queue = self.file_queue.lock().await;
-        if let Err(Error::Io(ref _e)) = result {
+        if let Err(Error::Io(_) | Error::TypeNotAllowed(_)) = result {
             // The intenal file has been taken away and dropped. The pending file must be canceled.
             file_queue.discard(file_token)?;
+            self.metrics.uploads_cancelled.inc();
         } else {
-            // before calling release_file, the Arc<Mutex<PendingFile>> should be dropped
-            file_queue.release_file(file_token)?;
+            file_queue.release_file(file_token, received)?;
         }
         result
     }
 
-    pub async fn finish_upload(&self, file_token: UUID) -> Result<(), Error> {
-        let file = {
+    /// Get the period after which the pending file expires if no more chunks are received.
+    pub async fn ttl(&self, file_token: UUID) -> Result<Duration, Error> {
+        self.file_queue.lock().await.ttl(file_token)
+    }
+
+    /// Finish the pending file, compressing it if enabled unless encrypted.
+    pub async fn finish_upload(&self, file_token: UUID) -> Result<Finished, Error> {
+        let (file, _writing) = {
             let mut file_queue = self.file_queue.lock().await;
             let file = file_queue.acquire_file(file_token)?;
-            file_queue.discard(file_token)?;
-            file
+            // no longer pending but still being written until settled
+            let path = file_queue.discard(file_token)?;
+            (file, self.writing.hold(path))
         };
 
         let mut locked_file = file.lock().await;
-        locked_file.finish().await?;
+        let result = locked_file.finish().await;
         // make sure the file is finished
-        Ok(())
+        let (uploader, name, size) = (
+            locked_file.uploader.clone(),
+            locked_file.name.clone(),
+            locked_file.size,
+        );
+        match result {
+            Ok(_) => {
+                self.metrics.uploads_finished.inc();
+                let mut path = locked_file.path.clone();
+                let mut compressed_size = None;
+                // encrypted data is incompressible, so files are only compressed before encrypted
+                // when received in full
+                let codec = self
+                    .config
+                    .codec_for(&name)
+                    .filter(|_| self.config.encrypt_to.is_none());
+                if let Some(codec) = codec {
+                    match self.compress_stored(&path, codec).await {
+                        Ok((compressed, compressed_len)) => {
+                            info!("Compressed {:?} into {:?}", path, compressed);
+                            path = compressed;
+                            compressed_size = Some(compressed_len);
+                        }
+                        // the file is kept uncompressed
+                        Err(e) => warn!("Failed to compress {:?}: {}", path, e),
+                    }
+                }
+                Ok(self
+                    .settle_finished(file_token, uploader, name, path, size, compressed_size)
+                    .await)
+            }
+            // the file is removed once dropped
+            Err(e) => {
+                self.metrics.uploads_cancelled.inc();
+                self.emit(UploadEvent::Cancel {
+                    token: file_token,
+                    error: e.to_string(),
+                });
+                self.audit_failed(uploader, name, size, &e).await;
+                Err(e)
+            }
+        }
     }
 
     // TODO: cancel upload
 
+    /// Receive a file in full, compressing it on the fly if enabled.
+    ///
+    /// The file is rejected before written if its name or content is not allowed.
     pub async fn put_full(
         &self,
         name: String,
         size: Option<usize>,
-        mut data: impl Stream<Item = io::Result<impl AsRef<[u8]>>> + Unpin,
-    ) -> Result<usize, Error> {
-        let (mut file, path) = create_file(name, Option::<String>::None).await?;
-        let mut count = 0;
-        while let Some(bytes) = data.next().await {
-            let bytes = try_finally!(bytes, {
-                let _ = remove_file(path).compat().await;
-            });
-            count += bytes.as_ref().len();
-            if let Some(size) = size {
-                if count > size {
-                    // TODO: shutdown before remove?
-                    let _ = remove_file(path).compat().await;
-                    return Err(Error::DataNotFitIn(count));
+        data: impl Stream<Item = io::Result<impl AsRef<[u8]>>> + Unpin,
+        uploader: Uploader,
+    ) -> Result<Finished, Error> {
+        let checked = match self.check_name(&name) {
+            Ok(()) => self.check_content(self.count_received(data)).await,
+            Err(e) => Err(e),
+        };
+        let data = match checked {
+            Ok(data) => data,
+            Err(e) => {
+                let mut record = AuditRecord::new(uploader, name, Outcome::Failed);
+                record.size = size.map(|size| size as u64);
+                record.error = Some(e.to_string());
+                self.audit(record).await;
+                return Err(e);
+            }
+        };
+        let codec = self.config.codec_for(&name);
+        let file = match codec {
+            Some(codec) => {
+                let name = format!("{}.{}", name, codec.extension());
+                self.storage.create(&name, None, None).await?
+            }
+            None => {
+                self.storage
+                    .create(&name, size.map(|size| size as u64), None)
+                    .await?
+            }
+        };
+        let path = file.path().to_owned();
+        let _writing = self.writing.hold(path.clone());
+        self.metrics.uploads_started.inc();
+        let token = UUID::new_v4();
+        self.emit(UploadEvent::Start {
+            token,
+            name: name.clone(),
+            size: size.map(|size| size as u64),
+            uploader: uploader.clone(),
+            received: 0,
+        });
+        let buffer_size = self.config.write_buffer_size;
+        let result = match codec {
+            None => match write_stream_at(&*file, 0, size, buffer_size, data).await {
+                Ok(count) if size.is_none_or(|size| count == size) => file
+                    .finalize()
+                    .await
+                    .map(|_| (count, None))
+                    .map_err(Error::from),
+                Ok(count) => Err(Error::FileNotFilledUp(count)),
+                Err(e) => Err(e),
+            },
+            Some(codec) => {
+                // the size is checked against the data received instead of that written
+                let received = Arc::new(AtomicUsize::new(0));
+                let data = data.inspect({
+                    let received = received.clone();
+                    move |bytes| {
+                        if let Ok(bytes) = bytes {
+                            received.fetch_add(bytes.as_ref().len(), Ordering::Relaxed);
+                        }
+                    }
+                });
+                let data = compress_stream(codec, data);
+                match write_stream_at(&*file, 0, None, buffer_size, data).await {
+                    Ok(compressed) => match received.load(Ordering::Relaxed) {
+                        count if size.is_some_and(|size| count > size) => {
+                            Err(Error::DataNotFitIn(count))
+                        }
+                        count if size.is_some_and(|size| count < size) => {
+                            Err(Error::FileNotFilledUp(count))
+                        }
+                        count => file
+                            .finalize()
+                            .await
+                            .map(|_| (count, Some(compressed as u64)))
+                            .map_err(Error::from),
+                    },
+                    Err(e) => Err(e),
                 }
             }
-            // TODO: it seems that write_all flushes by design, which may result in unbearable
-            // performance penalty; even though there is no flush according to the code (?)
-            file = write_all(file, bytes).compat().await?.0;
-        }
-        if let Some(size) = size {
-            if count != size {
-                let _ = remove_file(path).compat().await;
-                return Err(Error::FileNotFilledUp(count));
+        };
+        match result {
+            Ok((count, compressed_size)) => {
+                info!("Uploaded file: {:?}", path);
+                self.metrics.uploads_finished.inc();
+                Ok(self
+                    .settle_finished(token, uploader, name, path, count, compressed_size)
+                    .await)
+            }
+            Err(e) => {
+                let _ = file.abort().await;
+                self.metrics.uploads_cancelled.inc();
+                self.emit(UploadEvent::Cancel {
+                    token,
+                    error: e.to_string(),
+                });
+                let mut record = AuditRecord::new(uploader, name, Outcome::Failed);
+                record.size = size.map(|size| size as u64);
+                record.error = Some(e.to_string());
+                self.audit(record).await;
+                Err(e)
             }
         }
-        poll_fn(|| file.poll_sync_data())
-            .compat()
-            .await
-            .map_err(|e| Error::from(e))?;
-        let result = shutdown(file)
-            .map(|_| count)
-            .map_err(|e| e.into())
-            .compat()
-            .await;
-        info!("Uploaded file: {:?}", path);
-        result
     }
 }
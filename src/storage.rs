@@ -0,0 +1,256 @@
+use bytes::Bytes;
+use futures::{future::BoxFuture, stream::BoxStream, StreamExt};
+use tokio::fs::{remove_file, File, OpenOptions};
+use tokio_util::io::ReaderStream;
+
+use std::{
+    ffi::{OsStr, OsString},
+    fmt::Debug,
+    fs::File as StdFile,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use crate::fs::{available_space, list_files, replace_with_link, sha256_file, sync_data, write_at};
+
+/// A backend where received files are staged while pending and stored once finished.
+///
+/// The local file system is used by default, see `LocalStorage`.
+pub trait Storage: Debug + Send + Sync {
+    /// Create a staging object for a file named `name` by the uploader, of which the size is
+    /// known or not, suffixing the name if it is taken.
+    ///
+    /// `chunk_size` is given if the file is uploaded in chunks, each of which is written
+    /// sequentially from its start, possibly again after a failed attempt. Otherwise, the file is
+    /// written sequentially from the start.
+    fn create<'a>(
+        &'a self,
+        name: &'a str,
+        size: Option<u64>,
+        chunk_size: Option<u64>,
+    ) -> BoxFuture<'a, io::Result<Arc<dyn Staging>>>;
+
+    /// List all the stored objects, including those being staged if they are visible.
+    fn list(&self) -> BoxFuture<'_, io::Result<Vec<Object>>>;
+
+    /// Open the stored object at `path` for reading as a stream of data.
+    fn open<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<BoxStream<'static, io::Result<Bytes>>>>;
+
+    /// Compute the hex-encoded SHA-256 digest of the stored object at `path`.
+    fn sha256<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<String>>;
+
+    /// Get the space (in bytes) available for new objects, `None` if unknown or unlimited.
+    fn available_space(&self) -> BoxFuture<'_, io::Result<Option<u64>>>;
+
+    /// Remove the stored object at `path`.
+    fn remove<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Replace the stored object at `link` with a hard link to the one at `original`, so that
+    /// they share the same data.
+    ///
+    /// `io::ErrorKind::Unsupported` is returned by default.
+    fn link<'a>(&'a self, original: &'a Path, link: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        let _ = (original, link);
+        Box::pin(async { Err(io::Error::from(io::ErrorKind::Unsupported)) })
+    }
+}
+
+/// An object being staged, into which disjoint regions may be written concurrently.
+///
+/// It is expected to be either finalized or aborted eventually. Otherwise, it is aborted on a
+/// best-effort basis when dropped.
+pub trait Staging: Debug + Send + Sync {
+    /// The path where the object is stored once finalized.
+    fn path(&self) -> &Path;
+
+    /// Write the whole buffer at the position `offset` of the object, return the buffer back for
+    /// reuse.
+    fn write_at(&self, buf: Vec<u8>, offset: u64) -> BoxFuture<'_, io::Result<Vec<u8>>>;
+
+    /// Store the object durably, after which it can no longer be written.
+    fn finalize(&self) -> BoxFuture<'_, io::Result<()>>;
+
+    /// Discard the object along with the data written so far, which is a no-op if it has been
+    /// finalized or aborted already.
+    fn abort(&self) -> BoxFuture<'_, io::Result<()>>;
+}
+
+/// The size of the buffer to read stored files with.
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A stored object as listed by `Storage::list`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Object {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// Generate the candidate names for a file named `file_name`, i.e. the name itself and then those
+/// suffixed with `_1`, `_2` and so on before the extension.
+pub(crate) fn candidate_names(file_name: &str) -> impl Iterator<Item = OsString> {
+    let path = PathBuf::from(file_name);
+    let stem = path
+        .file_stem()
+        .unwrap_or_else(|| OsStr::new("UnnamedFile"))
+        .to_owned();
+    let ext = path.extension().map(OsStr::to_owned);
+    (0..).map(move |count: usize| {
+        let mut s = stem.clone();
+        if count != 0 {
+            s.push("_");
+            s.push(count.to_string());
+        }
+        if let Some(ref ext) = ext {
+            s.push(".");
+            s.push(ext);
+        }
+        s
+    })
+}
+
+/// Storage on the local file system, where files are written in place under the directory.
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        LocalStorage { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Storage for LocalStorage {
+    fn create<'a>(
+        &'a self,
+        name: &'a str,
+        _size: Option<u64>,
+        _chunk_size: Option<u64>,
+    ) -> BoxFuture<'a, io::Result<Arc<dyn Staging>>> {
+        Box::pin(async move {
+            let (file, path) = create_file(&self.dir, name).await?;
+            Ok(Arc::new(LocalStaging::new(file, path)) as Arc<dyn Staging>)
+        })
+    }
+
+    fn list(&self) -> BoxFuture<'_, io::Result<Vec<Object>>> {
+        Box::pin(list_files(self.dir.clone()))
+    }
+
+    fn open<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<BoxStream<'static, io::Result<Bytes>>>> {
+        Box::pin(async move {
+            let file = File::open(path).await?;
+            Ok(ReaderStream::with_capacity(file, READ_BUFFER_SIZE).boxed())
+        })
+    }
+
+    fn sha256<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<String>> {
+        Box::pin(sha256_file(path.to_owned()))
+    }
+
+    fn available_space(&self) -> BoxFuture<'_, io::Result<Option<u64>>> {
+        Box::pin(async move { available_space(self.dir.clone()).await.map(Some) })
+    }
+
+    fn remove<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(remove_file(path))
+    }
+
+    fn link<'a>(&'a self, original: &'a Path, link: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(replace_with_link(original.to_owned(), link.to_owned()))
+    }
+}
+
+/// Create a new file named `file_name` under the directory, suffixing the name if it is taken.
+pub(crate) async fn create_file(dir: &Path, file_name: &str) -> io::Result<(StdFile, PathBuf)> {
+    for file_name in candidate_names(file_name) {
+        let path = dir.join(file_name);
+        let result = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await;
+        match result {
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(e),
+            Ok(file) => return Ok((file.into_std().await, path)),
+        }
+    }
+    unreachable!("Candidate names are endless")
+}
+
+/// A file being written in place, which is removed unless finalized.
+#[derive(Debug)]
+struct LocalStaging {
+    path: PathBuf,
+    file: Arc<StdFile>,
+    /// Whether it has been finalized or aborted
+    done: AtomicBool,
+}
+
+impl LocalStaging {
+    fn new(file: StdFile, path: PathBuf) -> Self {
+        LocalStaging {
+            path,
+            file: Arc::new(file),
+            done: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Staging for LocalStaging {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write_at(&self, buf: Vec<u8>, offset: u64) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        Box::pin(write_at(self.file.clone(), buf, offset))
+    }
+
+    fn finalize(&self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            sync_data(self.file.clone()).await?;
+            self.done.store(true, Ordering::SeqCst);
+            Ok(())
+        })
+    }
+
+    fn abort(&self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            if self.done.swap(true, Ordering::SeqCst) {
+                // already finalized or aborted
+                return Ok(());
+            }
+            remove_file(&self.path).await
+        })
+    }
+}
+
+impl Drop for LocalStaging {
+    fn drop(&mut self) {
+        // synchronously remove the file if it is neither finalized nor aborted
+        if !self.done.load(Ordering::SeqCst) {
+            trace!(
+                "Synchronously remove the file: {}, result: {:?}",
+                self.path.to_str().unwrap_or("INVALID_ENCODING_IN_PATH"),
+                std::fs::remove_file(&self.path)
+            );
+        }
+    }
+}
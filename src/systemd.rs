@@ -0,0 +1,142 @@
+//! Integration with systemd: socket activation and readiness notification, as described in
+//! `sd_listen_fds(3)` and `sd_notify(3)`.
+use tokio::net::{TcpListener, UnixListener};
+
+use std::{
+    env,
+    ffi::OsString,
+    io::{self, ErrorKind},
+    net::TcpListener as StdTcpListener,
+    os::unix::{
+        io::{FromRawFd, IntoRawFd, RawFd},
+        net::{UnixDatagram, UnixListener as StdUnixListener},
+    },
+    process,
+};
+
+use crate::listen::Listener;
+
+/// The first file descriptor passed by systemd
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// The environment of socket activation, i.e. the values of `LISTEN_PID` and `LISTEN_FDS`.
+#[derive(Debug, Default)]
+pub struct ListenEnv {
+    pub pid: Option<String>,
+    pub fds: Option<String>,
+}
+
+impl ListenEnv {
+    /// Take the environment of socket activation, unsetting the related variables so that they
+    /// are not inherited by child processes.
+    ///
+    /// Must be called before any other thread is spawned (e.g. by the tokio runtime), since the
+    /// environment is not safe to modify while read by other threads.
+    pub fn take() -> Self {
+        let pid = env::var("LISTEN_PID").ok();
+        let fds = env::var("LISTEN_FDS").ok();
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+        ListenEnv { pid, fds }
+    }
+}
+
+/// Take the listening sockets passed by systemd via `LISTEN_FDS`, if any.
+pub fn listen_fds(env: ListenEnv) -> io::Result<Vec<Listener>> {
+    match (env.pid, env.fds) {
+        (Some(pid), Some(fds)) if pid.parse() == Ok(process::id()) => {
+            let fds: RawFd = fds
+                .parse()
+                .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "Invalid LISTEN_FDS"))?;
+            (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds)
+                .map(|fd| unsafe { listener_from_raw_fd(fd) })
+                .collect()
+        }
+        // not passed or passed to another process
+        _ => Ok(vec![]),
+    }
+}
+
+/// Take the ownership of the socket and wrap it as a listener according to its address family.
+///
+/// # Safety
+/// `fd` must be a listening stream socket not owned elsewhere.
+unsafe fn listener_from_raw_fd(fd: RawFd) -> io::Result<Listener> {
+    let listener = StdTcpListener::from_raw_fd(fd);
+    // `getsockname` fails to parse the address if the socket is not in the inet families
+    if listener.local_addr().is_ok() {
+        listener.set_nonblocking(true)?;
+        Ok(Listener::Tcp(TcpListener::from_std(listener)?))
+    } else {
+        let listener = StdUnixListener::from_raw_fd(listener.into_raw_fd());
+        listener.local_addr()?;
+        listener.set_nonblocking(true)?;
+        Ok(Listener::Unix(UnixListener::from_std(listener)?))
+    }
+}
+
+/// Send the `state` (e.g. `READY=1`) to the service manager via `NOTIFY_SOCKET`.
+///
+/// Return `false` if `NOTIFY_SOCKET` is not set, i.e. not run by systemd with `Type=notify`.
+pub fn notify(state: &str) -> io::Result<bool> {
+    notify_socket(env::var_os("NOTIFY_SOCKET"), state)
+}
+
+/// Send the `state` to the socket at `path`, i.e. the value of `NOTIFY_SOCKET`, if any.
+fn notify_socket(path: Option<OsString>, state: &str) -> io::Result<bool> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(false),
+    };
+    let socket = UnixDatagram::unbound()?;
+    match path.to_str().and_then(|path| path.strip_prefix('@')) {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        Some(name) => {
+            // a socket in the abstract namespace
+            #[cfg(target_os = "android")]
+            use std::os::android::net::SocketAddrExt;
+            #[cfg(target_os = "linux")]
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), &path)?;
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{listen_fds, notify_socket, ListenEnv};
+    use std::os::unix::net::UnixDatagram;
+
+    // the environment is passed in rather than set, since tests run in parallel in one process
+    #[test]
+    fn test_notify() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        assert!(notify_socket(Some(path.into()), "READY=1").unwrap());
+        assert!(!notify_socket(None, "STOPPING=1").unwrap());
+        let mut buf = [0; 64];
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+
+    #[test]
+    fn test_listen_fds_for_other_process() {
+        let env = ListenEnv {
+            pid: Some("1".to_owned()),
+            fds: Some("1".to_owned()),
+        };
+        assert!(listen_fds(env).unwrap().is_empty());
+        let env = ListenEnv {
+            pid: None,
+            fds: Some("1".to_owned()),
+        };
+        assert!(listen_fds(env).unwrap().is_empty());
+    }
+}
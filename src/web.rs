@@ -1,8 +1,10 @@
-use mime_guess::from_path as mime_guess_from_path;
-use tide::{
-    http::{response::Builder as ResponseBuilder, StatusCode},
-    Response,
+use axum::{
+    body::Body,
+    extract::Path,
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::Response,
 };
+use mime_guess::from_path as mime_guess_from_path;
 
 #[derive(RustEmbed)]
 #[folder = "web/"]
@@ -13,22 +15,27 @@ pub fn serve_embedded_file(mut path: &str) -> Response {
         path = &path[1..];
     }
     match Assets::get(path) {
-        Some(content) => ResponseBuilder::new()
+        Some(content) => Response::builder()
             .status(StatusCode::OK)
             .header(
-                "Content-Type",
+                CONTENT_TYPE,
                 mime_guess_from_path(path).first_or_octet_stream().as_ref(),
             )
-            .body(content.as_ref().into())
+            .body(Body::from(content.data))
             .unwrap(),
-        None => ResponseBuilder::new()
+        None => Response::builder()
             .status(StatusCode::NOT_FOUND)
-            .body(
-                Assets::get("404.html")
-                    .expect("HTTP 404 Error Page")
-                    .as_ref()
-                    .into(),
-            )
+            .body(Body::from(
+                Assets::get("404.html").expect("HTTP 404 Error Page").data,
+            ))
             .unwrap(),
     }
 }
+
+pub async fn handle_index() -> Response {
+    serve_embedded_file("/index.html")
+}
+
+pub async fn handle_assets(Path(path): Path<String>) -> Response {
+    serve_embedded_file(&format!("assets/{}", path))
+}
@@ -0,0 +1,411 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{self, Path, Query},
+    http::{header::AUTHORIZATION, HeaderMap, Request, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use http_body_util::BodyExt;
+use intray::{
+    api::{ResponseUploadChunk, ResponseUploadFinish, ResponseUploadFull, ResponseUploadStart},
+    health::ResponseReadiness,
+    history::ResponseHistory,
+    s3::{S3Config, S3Storage},
+    Server, ServerBuilder,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use tempfile::{tempdir, TempDir};
+use tokio::net::TcpListener;
+use tower::ServiceExt;
+use uuid::Uuid as UUID;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::read_dir,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+const BUCKET: &str = "inbox";
+/// The minimum size of the parts of a multipart upload except the last one.
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// The bucket served by the emulator.
+#[derive(Debug, Default)]
+struct Bucket {
+    objects: BTreeMap<String, Bytes>,
+    /// Multipart uploads in progress by IDs, with their keys and parts by part numbers
+    uploads: HashMap<String, (String, BTreeMap<u64, Bytes>)>,
+    /// The number of the next parts to fail uploading
+    failing_parts: usize,
+}
+
+type Emulator = Arc<Mutex<Bucket>>;
+
+#[derive(Debug, Deserialize)]
+struct ListQuery {
+    prefix: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectQuery {
+    uploads: Option<String>,
+    upload_id: Option<String>,
+    part_number: Option<u64>,
+}
+
+fn authorized(headers: &HeaderMap) -> bool {
+    headers.get(AUTHORIZATION).is_some_and(|value| {
+        value
+            .to_str()
+            .unwrap()
+            .starts_with("AWS4-HMAC-SHA256 Credential=access/")
+    }) && headers.contains_key("x-amz-date")
+}
+
+fn error(status: StatusCode, code: &str) -> Response {
+    let body = format!(
+        "<Error><Code>{}</Code><Message>{}</Message></Error>",
+        code, code
+    );
+    (status, body).into_response()
+}
+
+/// Spawn a minimal stand-in of S3 serving a bucket in the path style, including multipart uploads,
+/// return its endpoint.
+async fn spawn_emulator(emulator: Emulator) -> String {
+    async fn list(
+        extract::State(emulator): extract::State<Emulator>,
+        Path(bucket): Path<String>,
+        headers: HeaderMap,
+        Query(query): Query<ListQuery>,
+    ) -> Response {
+        if !authorized(&headers) {
+            return error(StatusCode::FORBIDDEN, "AccessDenied");
+        }
+        if bucket != BUCKET {
+            return error(StatusCode::NOT_FOUND, "NoSuchBucket");
+        }
+        let prefix = query.prefix.unwrap_or_default();
+        let contents: String = emulator
+            .lock()
+            .unwrap()
+            .objects
+            .iter()
+            .filter(|(key, _)| key.starts_with(&prefix))
+            .map(|(key, data)| {
+                format!(
+                    "<Contents><Key>{}</Key><LastModified>2020-01-01T00:00:00.000Z</LastModified>\
+                     <Size>{}</Size></Contents>",
+                    key,
+                    data.len()
+                )
+            })
+            .collect();
+        format!(
+            "<ListBucketResult><IsTruncated>false</IsTruncated>{}</ListBucketResult>",
+            contents
+        )
+        .into_response()
+    }
+
+    async fn object(
+        extract::State(emulator): extract::State<Emulator>,
+        Path((bucket, key)): Path<(String, String)>,
+        Query(query): Query<ObjectQuery>,
+        headers: HeaderMap,
+        req: Request<Body>,
+    ) -> Response {
+        if !authorized(&headers) {
+            return error(StatusCode::FORBIDDEN, "AccessDenied");
+        }
+        if bucket != BUCKET {
+            return error(StatusCode::NOT_FOUND, "NoSuchBucket");
+        }
+        let method = req.method().clone();
+        let data = req.into_body().collect().await.unwrap().to_bytes();
+        let mut bucket = emulator.lock().unwrap();
+        match (method.as_str(), query.upload_id) {
+            ("POST", None) if query.uploads.is_some() => {
+                let upload_id = UUID::new_v4().to_string();
+                bucket
+                    .uploads
+                    .insert(upload_id.clone(), (key, BTreeMap::new()));
+                format!(
+                    "<InitiateMultipartUploadResult><UploadId>{}</UploadId>\
+                     </InitiateMultipartUploadResult>",
+                    upload_id
+                )
+                .into_response()
+            }
+            ("PUT", Some(_)) if bucket.failing_parts > 0 => {
+                bucket.failing_parts -= 1;
+                error(StatusCode::INTERNAL_SERVER_ERROR, "InternalError")
+            }
+            ("PUT", Some(upload_id)) => match bucket.uploads.get_mut(&upload_id) {
+                Some((_, parts)) => {
+                    let number = query.part_number.unwrap();
+                    let etag = format!("\"{}-{}\"", number, data.len());
+                    parts.insert(number, data);
+                    ([("ETag", etag)], "").into_response()
+                }
+                None => error(StatusCode::NOT_FOUND, "NoSuchUpload"),
+            },
+            ("POST", Some(upload_id)) => {
+                let Some((_, parts)) = bucket.uploads.remove(&upload_id) else {
+                    return error(StatusCode::NOT_FOUND, "NoSuchUpload");
+                };
+                let body = String::from_utf8(data.to_vec()).unwrap();
+                let numbers: Vec<u64> = body
+                    .split("<PartNumber>")
+                    .skip(1)
+                    .map(|s| s.split('<').next().unwrap().parse().unwrap())
+                    .collect();
+                if numbers.is_empty() || numbers.iter().any(|n| !parts.contains_key(n)) {
+                    return error(StatusCode::BAD_REQUEST, "InvalidPart");
+                }
+                if !numbers.is_sorted() {
+                    return error(StatusCode::BAD_REQUEST, "InvalidPartOrder");
+                }
+                let others = &numbers[..numbers.len() - 1];
+                if others.iter().any(|n| parts[n].len() < MIN_PART_SIZE) {
+                    return error(StatusCode::BAD_REQUEST, "EntityTooSmall");
+                }
+                let object: Vec<u8> = numbers.iter().flat_map(|n| parts[n].to_vec()).collect();
+                bucket.objects.insert(key, object.into());
+                "<CompleteMultipartUploadResult></CompleteMultipartUploadResult>".into_response()
+            }
+            ("DELETE", Some(upload_id)) => match bucket.uploads.remove(&upload_id) {
+                Some(_) => StatusCode::NO_CONTENT.into_response(),
+                None => error(StatusCode::NOT_FOUND, "NoSuchUpload"),
+            },
+            ("PUT", None) => {
+                bucket.objects.insert(key, data);
+                StatusCode::OK.into_response()
+            }
+            ("GET" | "HEAD", None) => match bucket.objects.get(&key) {
+                Some(data) => data.clone().into_response(),
+                None => error(StatusCode::NOT_FOUND, "NoSuchKey"),
+            },
+            ("DELETE", None) => {
+                bucket.objects.remove(&key);
+                StatusCode::NO_CONTENT.into_response()
+            }
+            _ => error(StatusCode::METHOD_NOT_ALLOWED, "MethodNotAllowed"),
+        }
+    }
+
+    let app = Router::new()
+        .route("/{bucket}", get(list))
+        .route(
+            "/{bucket}/{*key}",
+            get(object).put(object).post(object).delete(object),
+        )
+        .with_state(emulator);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    format!("http://{}", addr)
+}
+
+/// Spawn an emulator, build a server storing files into it.
+async fn build_server(
+    configure: impl FnOnce(ServerBuilder) -> ServerBuilder,
+) -> (Server, Emulator, TempDir) {
+    let emulator = Emulator::default();
+    let endpoint = spawn_emulator(emulator.clone()).await;
+    let dir = tempdir().unwrap();
+    let storage = S3Storage::new(S3Config {
+        endpoint,
+        bucket: BUCKET.to_owned(),
+        prefix: "uploads/".to_owned(),
+        region: "us-east-1".to_owned(),
+        access_key: "access".to_owned(),
+        secret_key: "secret".to_owned(),
+    })
+    .unwrap();
+    let builder = Server::builder()
+        .dir(dir.path())
+        .write_buffer_size(1024)
+        .storage(Arc::new(storage));
+    (configure(builder).build(), emulator, dir)
+}
+
+async fn post<T: DeserializeOwned>(app: &Router, uri: &str, body: impl Into<Body>) -> T {
+    let req = Request::post(uri)
+        .header("Content-Type", "application/json")
+        .body(body.into())
+        .unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    serde_json::from_slice(&res.into_body().collect().await.unwrap().to_bytes()).unwrap()
+}
+
+async fn start(app: &Router, name: &str, size: usize, chunk_size: usize) -> String {
+    let res: ResponseUploadStart = post(
+        app,
+        "/upload/start",
+        format!(
+            r#"{{"file_name": "{}", "file_size": {}, "chunk_size": {}}}"#,
+            name, size, chunk_size
+        ),
+    )
+    .await;
+    res.file_token.unwrap()
+}
+
+async fn put_chunk(app: &Router, token: &str, index: usize, data: Vec<u8>) {
+    let res: ResponseUploadChunk = post(app, &format!("/upload/{}/{}", token, index), data).await;
+    assert!(res.ok, "{:?}", res.error);
+}
+
+async fn finish(app: &Router, token: &str) {
+    let res: ResponseUploadFinish = post(
+        app,
+        "/upload/finish",
+        format!(r#"{{"file_token": "{}"}}"#, token),
+    )
+    .await;
+    assert!(res.ok, "{:?}", res.error);
+}
+
+#[tokio::test]
+async fn test_s3_storage() {
+    let history_dir = tempdir().unwrap();
+    let (server, emulator, dir) = build_server(|builder| {
+        builder
+            .history(Some(history_dir.path().join("history.jsonl")))
+            .public_activity(true)
+    })
+    .await;
+    let app = server.router();
+
+    let res: ResponseUploadFull = post(&app, "/upload/full/a%20b.txt", "first").await;
+    assert!(res.ok);
+    let res: ResponseUploadFull = post(&app, "/upload/full/a%20b.txt", "second").await;
+    assert!(res.ok);
+
+    // chunks smaller than the minimum part size are grouped into a part
+    let token = start(&app, "c.bin", 6, 4).await;
+    put_chunk(&app, &token, 1, b"ef".to_vec()).await;
+    put_chunk(&app, &token, 0, b"abcd".to_vec()).await;
+    finish(&app, &token).await;
+
+    // parts are uploaded as soon as received, regardless of the chunks
+    let token = start(&app, "d.bin", MIN_PART_SIZE + 3, MIN_PART_SIZE).await;
+    put_chunk(&app, &token, 1, vec![2; 3]).await;
+    assert_eq!(emulator.lock().unwrap().uploads.len(), 1);
+    put_chunk(&app, &token, 0, vec![1; MIN_PART_SIZE]).await;
+    finish(&app, &token).await;
+
+    // chunks larger than the minimum part size are split into parts of that size
+    let token = start(&app, "e.bin", MIN_PART_SIZE * 4, MIN_PART_SIZE * 2).await;
+    put_chunk(&app, &token, 0, vec![3; MIN_PART_SIZE * 2]).await;
+    {
+        let bucket = emulator.lock().unwrap();
+        let (_, parts) = bucket.uploads.values().next().unwrap();
+        assert_eq!(parts.len(), 2);
+        assert!(parts.values().all(|part| part.len() == MIN_PART_SIZE));
+    }
+    put_chunk(&app, &token, 1, vec![4; MIN_PART_SIZE * 2]).await;
+    finish(&app, &token).await;
+
+    // names are reserved by the files being uploaded
+    let tokens = [
+        start(&app, "f.bin", 1, 1).await,
+        start(&app, "f.bin", 1, 1).await,
+    ];
+    for (token, data) in tokens.iter().zip(["x", "y"]) {
+        put_chunk(&app, token, 0, data.into()).await;
+        finish(&app, token).await;
+    }
+
+    {
+        let bucket = emulator.lock().unwrap();
+        assert_eq!(bucket.objects.len(), 7);
+        assert_eq!(bucket.objects["uploads/a b.txt"], "first");
+        assert_eq!(bucket.objects["uploads/a b_1.txt"], "second");
+        assert_eq!(bucket.objects["uploads/c.bin"], "abcdef");
+        let object = &bucket.objects["uploads/d.bin"];
+        assert_eq!(object.len(), MIN_PART_SIZE + 3);
+        assert_eq!((object[0], object[MIN_PART_SIZE]), (1, 2));
+        let object = &bucket.objects["uploads/e.bin"];
+        assert_eq!(object.len(), MIN_PART_SIZE * 4);
+        assert_eq!((object[0], object[MIN_PART_SIZE * 3]), (3, 4));
+        assert_eq!(bucket.objects["uploads/f.bin"], "x");
+        assert_eq!(bucket.objects["uploads/f_1.bin"], "y");
+        // no multipart upload is left behind
+        assert!(bucket.uploads.is_empty());
+    }
+    // nothing is staged locally
+    assert_eq!(read_dir(dir.path()).unwrap().count(), 0);
+
+    let req = Request::get("/history?name=c.bin")
+        .body(Body::empty())
+        .unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    let res: ResponseHistory =
+        serde_json::from_slice(&res.into_body().collect().await.unwrap().to_bytes()).unwrap();
+    let entry = &res.entries[0];
+    assert_eq!(entry.path.to_str(), Some("s3://inbox/uploads/c.bin"));
+    // SHA-256 of "abcdef"
+    assert_eq!(
+        entry.sha256.as_deref(),
+        Some("bef57ec7f53a6d40beb640a780a639c83bc29ac8a9816f1fc6c5c6dcd93c4721")
+    );
+}
+
+#[tokio::test]
+async fn test_s3_part_retry() {
+    let (server, emulator, _dir) = build_server(|builder| builder).await;
+    let storage = server.state().storage();
+    let file = storage
+        .create("g.bin", Some(MIN_PART_SIZE as u64 * 2), None)
+        .await
+        .unwrap();
+    // a part failing to upload is uploaded again once its data is written again
+    emulator.lock().unwrap().failing_parts = 1;
+    assert!(file.write_at(vec![5; MIN_PART_SIZE], 0).await.is_err());
+    file.write_at(vec![5; 10], 0).await.unwrap();
+    file.write_at(vec![6; MIN_PART_SIZE], MIN_PART_SIZE as u64)
+        .await
+        .unwrap();
+    file.finalize().await.unwrap();
+    let bucket = emulator.lock().unwrap();
+    let object = &bucket.objects["uploads/g.bin"];
+    assert_eq!(object.len(), MIN_PART_SIZE * 2);
+    assert_eq!((object[0], object[MIN_PART_SIZE]), (5, 6));
+}
+
+#[tokio::test]
+async fn test_s3_expire() {
+    let (server, emulator, _dir) = build_server(|builder| {
+        builder
+            .pending_timeout(Duration::from_millis(100))
+            .sweep_interval(Duration::from_millis(20))
+    })
+    .await;
+    let app = server.router();
+
+    // the storage is probed for writing without leaving anything behind
+    let req = Request::get("/readyz").body(Body::empty()).unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    let res: ResponseReadiness =
+        serde_json::from_slice(&res.into_body().collect().await.unwrap().to_bytes()).unwrap();
+    assert!(res.checks.iter().any(|c| c.name == "storage" && c.ok));
+    assert!(emulator.lock().unwrap().uploads.is_empty());
+
+    let token = start(&app, "e.bin", MIN_PART_SIZE * 2, MIN_PART_SIZE).await;
+    put_chunk(&app, &token, 0, vec![0; MIN_PART_SIZE]).await;
+    assert_eq!(emulator.lock().unwrap().uploads.len(), 1);
+
+    let expire = tokio::spawn(server.expire());
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    expire.abort();
+    // the multipart upload is aborted once the pending file expires
+    let bucket = emulator.lock().unwrap();
+    assert!(bucket.uploads.is_empty());
+    assert!(bucket.objects.is_empty());
+}
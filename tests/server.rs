@@ -0,0 +1,993 @@
+use axum::{
+    body::{Body, Bytes},
+    http::{header::AUTHORIZATION, Request, StatusCode},
+    Router,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures::{future::join_all, stream};
+use http_body_util::BodyExt;
+use intray::{
+    admin::{ResponseAdminCancel, ResponseAdminStatus},
+    api::{
+        ResponseUploadChunk, ResponseUploadFinish, ResponseUploadFull, ResponseUploadOptions,
+        ResponseUploadStart,
+    },
+    audit::{AuditRecord, Outcome, Uploader},
+    compress::Codec,
+    dedup::DedupMode,
+    extract::{ArchiveFormat, ExtractLimits},
+    health::ResponseReadiness,
+    history::ResponseHistory,
+    retention::RetentionPolicy,
+    Server, ServerBuilder,
+};
+use serde::de::DeserializeOwned;
+use tempfile::{tempdir, TempDir};
+use tower::ServiceExt;
+
+use std::{
+    fs::{create_dir, read, read_to_string, write, File},
+    io,
+    time::{Duration, SystemTime},
+};
+
+fn spawn_server(credentials: &[&str]) -> (Router, TempDir) {
+    spawn_server_with(|builder| builder.credentials(credentials.iter().copied()))
+}
+
+fn spawn_server_with(configure: impl FnOnce(ServerBuilder) -> ServerBuilder) -> (Router, TempDir) {
+    let dir = tempdir().unwrap();
+    let server = configure(Server::builder().dir(dir.path()).write_buffer_size(3)).build();
+    (server.router(), dir)
+}
+
+async fn send(app: &Router, req: Request<Body>) -> (StatusCode, Bytes) {
+    let res = app.clone().oneshot(req).await.unwrap();
+    let status = res.status();
+    (status, res.into_body().collect().await.unwrap().to_bytes())
+}
+
+async fn post<T: DeserializeOwned>(app: &Router, uri: &str, body: impl Into<Body>) -> T {
+    let req = Request::post(uri)
+        .header("Content-Type", "application/json")
+        .body(body.into())
+        .unwrap();
+    let (status, body) = send(app, req).await;
+    assert_eq!(status, StatusCode::OK);
+    serde_json::from_slice(&body).unwrap()
+}
+
+/// Read the next Server-Sent Event from the streaming body.
+async fn next_event(body: &mut Body) -> String {
+    let frame = body.frame().await.unwrap().unwrap();
+    String::from_utf8(frame.into_data().unwrap().to_vec()).unwrap()
+}
+
+#[tokio::test]
+async fn test_isolated_instances() {
+    let (app1, dir1) = spawn_server(&[]);
+    let (app2, dir2) = spawn_server(&[]);
+    let res: ResponseUploadFull = post(&app1, "/upload/full/a.txt", "first").await;
+    assert!(res.ok);
+    assert_eq!(res.written, Some(5));
+    let res: ResponseUploadFull = post(&app2, "/upload/full/a.txt", "second").await;
+    assert!(res.ok);
+    assert_eq!(read(dir1.path().join("a.txt")).unwrap(), b"first");
+    assert_eq!(read(dir2.path().join("a.txt")).unwrap(), b"second");
+    // duplicated names are suffixed
+    let _: ResponseUploadFull = post(&app1, "/upload/full/a.txt", "third").await;
+    assert_eq!(read(dir1.path().join("a_1.txt")).unwrap(), b"third");
+}
+
+#[tokio::test]
+async fn test_upload_in_chunks() {
+    let (app, dir) = spawn_server(&[]);
+    let data = b"0123456789";
+    let res: ResponseUploadStart = post(
+        &app,
+        "/upload/start",
+        r#"{"file_name": "b.bin", "file_size": 10, "chunk_size": 4}"#,
+    )
+    .await;
+    assert_eq!(res.ttl, Some(30));
+    let token = res.file_token.unwrap();
+    for &i in [2, 0, 1].iter() {
+        let chunk = &data[i * 4..data.len().min((i + 1) * 4)];
+        let res: ResponseUploadChunk =
+            post(&app, &format!("/upload/{}/{}", token, i), chunk.to_vec()).await;
+        assert!(res.ok, "{:?}", res.error);
+    }
+    let res: ResponseUploadChunk =
+        post(&app, &format!("/upload/{}/0", token), data[..4].to_vec()).await;
+    assert!(!res.ok);
+    let res: ResponseUploadChunk =
+        post(&app, &format!("/upload/{}/3", token), data[..4].to_vec()).await;
+    assert!(!res.ok);
+    let res: ResponseUploadFinish = post(
+        &app,
+        "/upload/finish",
+        format!(r#"{{"file_token": "{}"}}"#, token),
+    )
+    .await;
+    assert!(res.ok, "{:?}", res.error);
+    assert_eq!(read(dir.path().join("b.bin")).unwrap(), data);
+}
+
+#[tokio::test]
+async fn test_basic_auth() {
+    let (app, _dir) = spawn_server(&["user:passwd"]);
+    let (status, _) = send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let req = Request::get("/")
+        .header(
+            AUTHORIZATION,
+            format!("Basic {}", BASE64.encode("user:passwd")),
+        )
+        .body(Body::empty())
+        .unwrap();
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_pending_max_lifetime() {
+    let (app, _dir) = spawn_server_with(|builder| {
+        builder
+            .pending_timeout(Duration::from_secs(60))
+            .pending_max_lifetime(Some(Duration::from_secs(0)))
+    });
+    let res: ResponseUploadStart = post(
+        &app,
+        "/upload/start",
+        r#"{"file_name": "c.bin", "file_size": 4, "chunk_size": 4}"#,
+    )
+    .await;
+    assert_eq!(res.ttl, Some(0));
+    let token = res.file_token.unwrap();
+    let res: ResponseUploadChunk = post(&app, &format!("/upload/{}/0", token), "0123").await;
+    assert!(!res.ok);
+    assert_eq!(res.error.as_deref(), Some("The file has expired."));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pending_snapshot() {
+    let dir = tempdir().unwrap();
+    let server = Server::builder()
+        .dir(dir.path())
+        .pending_timeout(Duration::from_millis(100))
+        .sweep_interval(Duration::from_millis(20))
+        .build();
+    let state = server.state().clone();
+    let expiration = tokio::spawn(server.expire());
+    let token = state
+        .start_upload("a.bin".to_owned(), 64, 4, Uploader::default())
+        .await
+        .unwrap();
+
+    // keep listing the pending files while the chunks complete
+    let polling = tokio::spawn({
+        let state = state.clone();
+        async move {
+            loop {
+                state.pending_uploads().await;
+                tokio::task::yield_now().await;
+            }
+        }
+    });
+    let chunks = (0..15).map(|index| {
+        let data = stream::iter([Ok::<_, io::Error>(Bytes::from_static(b"0123"))]);
+        state.put_chunk(token, index, data)
+    });
+    for result in join_all(chunks).await {
+        result.unwrap();
+    }
+    polling.abort();
+    let uploads = state.pending_uploads().await;
+    assert_eq!(uploads[0].received, 60);
+
+    // the upload left idle still expires, without the expiration task dying
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(state.pending_count().await, 0);
+    assert!(state.is_expiring());
+    expiration.abort();
+}
+
+#[tokio::test]
+async fn test_base_path() {
+    let (app, dir) = spawn_server_with(|builder| builder.base_path("intray/"));
+    let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+    let (status, _) = send(&app, get("/intray")).await;
+    assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+    let (status, _) = send(&app, get("/intray/")).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(&app, get("/intray/assets/index.js")).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(&app, get("/assets/index.js")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let res: ResponseUploadFull = post(&app, "/intray/upload/full/c.txt", "data").await;
+    assert!(res.ok);
+    assert_eq!(read(dir.path().join("c.txt")).unwrap(), b"data");
+}
+
+#[tokio::test]
+async fn test_metrics() {
+    let (app, _dir) = spawn_server_with(|builder| builder.metrics(true));
+    let _: ResponseUploadFull = post(&app, "/upload/full/d.txt", "12345").await;
+    let (status, body) = send(&app, Request::get("/metrics").body(Body::empty()).unwrap()).await;
+    assert_eq!(status, StatusCode::OK);
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    for line in [
+        "intray_uploads_started_total 1",
+        "intray_uploads_finished_total 1",
+        "intray_received_bytes_total 5",
+        "intray_pending_files 0",
+    ] {
+        assert!(body.lines().any(|l| l == line), "{} not in {}", line, body);
+    }
+    // not exposed unless enabled
+    let (app, _dir) = spawn_server(&[]);
+    let (status, _) = send(&app, Request::get("/metrics").body(Body::empty()).unwrap()).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_probes() {
+    let dir = tempdir().unwrap();
+    let server = Server::builder()
+        .dir(dir.path())
+        .credentials(vec!["user:passwd"])
+        .quota(Some(8))
+        .build();
+    let app = server.router();
+    let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+    // not subject to authentication
+    let (status, body) = send(&app, get("/healthz")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(&body[..], br#"{"ok":true}"#);
+    let (status, body) = send(&app, get("/readyz")).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    let res: ResponseReadiness = serde_json::from_slice(&body).unwrap();
+    let failed: Vec<_> = res
+        .checks
+        .iter()
+        .filter(|c| !c.ok)
+        .map(|c| &c.name)
+        .collect();
+    assert_eq!(failed, vec!["expiration"]);
+
+    let expiration_task = tokio::spawn(server.expire());
+    tokio::task::yield_now().await;
+    let (status, _) = send(&app, get("/readyz")).await;
+    assert_eq!(status, StatusCode::OK);
+    // the usage is cached but accounts for the files stored
+    let req = Request::post("/upload/full/e.bin")
+        .header(
+            AUTHORIZATION,
+            format!("Basic {}", BASE64.encode("user:passwd")),
+        )
+        .body(Body::from("012345678"))
+        .unwrap();
+    assert_eq!(send(&app, req).await.0, StatusCode::OK);
+    let (status, body) = send(&app, get("/readyz")).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    let res: ResponseReadiness = serde_json::from_slice(&body).unwrap();
+    assert!(res.checks.iter().any(|c| c.name == "quota" && !c.ok));
+    expiration_task.abort();
+    // no probe is left behind
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[tokio::test]
+async fn test_audit_log() {
+    let log_dir = tempdir().unwrap();
+    let log_path = log_dir.path().join("audit.log");
+    let (app, dir) = spawn_server_with(|builder| {
+        builder
+            .credentials(vec!["user:passwd"])
+            .audit_log(Some(log_path.clone()))
+    });
+    let req = Request::post("/upload/full/f.txt")
+        .header(
+            AUTHORIZATION,
+            format!("Basic {}", BASE64.encode("user:passwd")),
+        )
+        .body(Body::from("hello"))
+        .unwrap();
+    let (status, _) = send(&app, req).await;
+    assert_eq!(status, StatusCode::OK);
+    let record: AuditRecord =
+        serde_json::from_str(read_to_string(&log_path).unwrap().trim_end()).unwrap();
+    assert_eq!(record.outcome, Outcome::Finished);
+    assert_eq!(record.uploader.user.as_deref(), Some("user"));
+    assert_eq!(record.name, "f.txt");
+    assert_eq!(record.size, Some(5));
+    assert_eq!(record.path, Some(dir.path().join("f.txt")));
+    assert_eq!(
+        record.sha256.as_deref(),
+        Some("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")
+    );
+}
+
+#[tokio::test]
+async fn test_history() {
+    let history_dir = tempdir().unwrap();
+    let (app, _dir) = spawn_server_with(|builder| {
+        builder
+            .credentials(vec!["user:passwd", "other:passwd"])
+            .admins(["user"])
+            .history(Some(history_dir.path().join("history.jsonl")))
+    });
+    let auth = |user: &str| format!("Basic {}", BASE64.encode(format!("{}:passwd", user)));
+    for (user, name) in [("user", "g.pdf"), ("other", "h.pdf"), ("user", "i.txt")] {
+        let req = Request::post(format!("/upload/full/{}", name))
+            .header(AUTHORIZATION, auth(user))
+            .body(Body::from("data"))
+            .unwrap();
+        assert_eq!(send(&app, req).await.0, StatusCode::OK);
+    }
+    let query = |uri: &str| {
+        Request::get(uri)
+            .header(AUTHORIZATION, auth("user"))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let (status, body) = send(&app, query("/history?user=user&name=*.pdf")).await;
+    assert_eq!(status, StatusCode::OK);
+    let res: ResponseHistory = serde_json::from_slice(&body).unwrap();
+    assert!(!res.more);
+    assert_eq!(res.entries[0].name, "g.pdf");
+    assert_eq!(res.entries[0].size, 4);
+    let (_, body) = send(&app, query("/history?limit=2")).await;
+    let res: ResponseHistory = serde_json::from_slice(&body).unwrap();
+    assert!(res.more);
+    assert_eq!(res.entries.len(), 2);
+    let (status, _) = send(&app, query("/history?since=yesterday")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let req = Request::get("/history").body(Body::empty()).unwrap();
+    assert_eq!(send(&app, req).await.0, StatusCode::UNAUTHORIZED);
+    // restricted to the admins
+    let req = Request::get("/history")
+        .header(AUTHORIZATION, auth("other"))
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(send(&app, req).await.0, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_events() {
+    // not served without authentication unless opted in
+    let (app, _dir) = spawn_server_with(|builder| builder.events(true));
+    let req = Request::get("/events").body(Body::empty()).unwrap();
+    assert_eq!(send(&app, req).await.0, StatusCode::NOT_FOUND);
+    let (app, _dir) = spawn_server_with(|builder| builder.events(true).public_activity(true));
+    let res: ResponseUploadStart = post(
+        &app,
+        "/upload/start",
+        r#"{"file_name": "j.bin", "file_size": 6, "chunk_size": 4}"#,
+    )
+    .await;
+    let token = res.file_token.unwrap();
+    let res = app
+        .clone()
+        .oneshot(Request::get("/events").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(res.headers()["Content-Type"], "text/event-stream");
+    let mut body = res.into_body();
+    // the snapshot of pending files
+    let event = next_event(&mut body).await;
+    assert!(event.starts_with("event: start\n"), "{}", event);
+    assert!(event.contains(&token));
+    let _: ResponseUploadChunk = post(&app, &format!("/upload/{}/1", token), "45").await;
+    let event = next_event(&mut body).await;
+    assert!(event.starts_with("event: chunk\n"), "{}", event);
+    assert!(event.contains(r#""received":2"#), "{}", event);
+    let _: ResponseUploadFull = post(&app, "/upload/full/k.txt", "data").await;
+    assert!(next_event(&mut body).await.starts_with("event: start\n"));
+    assert!(next_event(&mut body).await.starts_with("event: finish\n"));
+}
+
+#[tokio::test]
+async fn test_admin() {
+    let (app, dir) = spawn_server_with(|builder| {
+        builder
+            .credentials(vec!["admin:passwd", "user:passwd"])
+            .admins(vec!["admin"])
+    });
+    let auth = |user: &str| format!("Basic {}", BASE64.encode(format!("{}:passwd", user)));
+    let req = Request::post("/upload/start")
+        .header(AUTHORIZATION, auth("user"))
+        .header("Content-Type", "application/json")
+        .body(Body::from(
+            r#"{"file_name": "k.bin", "file_size": 6, "chunk_size": 4}"#,
+        ))
+        .unwrap();
+    let (_, body) = send(&app, req).await;
+    let res: ResponseUploadStart = serde_json::from_slice(&body).unwrap();
+    let token = res.file_token.unwrap();
+
+    let get = |uri: &str, user: &str| {
+        Request::get(uri)
+            .header(AUTHORIZATION, auth(user))
+            .body(Body::empty())
+            .unwrap()
+    };
+    assert_eq!(
+        send(&app, get("/admin", "user")).await.0,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        send(&app, get("/admin/status", "user")).await.0,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(send(&app, get("/admin", "admin")).await.0, StatusCode::OK);
+    let (status, body) = send(&app, get("/admin/status", "admin")).await;
+    assert_eq!(status, StatusCode::OK);
+    let res: ResponseAdminStatus = serde_json::from_slice(&body).unwrap();
+    assert!(res.ok);
+    assert_eq!(res.pending.len(), 1);
+    assert_eq!(res.pending[0].token.to_string(), token);
+    assert_eq!(res.pending[0].uploader.user.as_deref(), Some("user"));
+    assert!(res.recent.is_none());
+
+    let cancel = |user: &str| {
+        Request::post(format!("/admin/cancel/{}", token))
+            .header(AUTHORIZATION, auth(user))
+            .body(Body::empty())
+            .unwrap()
+    };
+    assert_eq!(send(&app, cancel("user")).await.0, StatusCode::FORBIDDEN);
+    let (_, body) = send(&app, cancel("admin")).await;
+    let res: ResponseAdminCancel = serde_json::from_slice(&body).unwrap();
+    assert!(res.ok);
+    let (_, body) = send(&app, cancel("admin")).await;
+    let res: ResponseAdminCancel = serde_json::from_slice(&body).unwrap();
+    assert!(!res.ok);
+    assert!(!dir.path().join("k.bin").exists());
+    // the token no longer works for the uploader
+    let req = Request::post(format!("/upload/{}/0", token))
+        .header(AUTHORIZATION, auth("user"))
+        .body(Body::from("abcd"))
+        .unwrap();
+    let (_, body) = send(&app, req).await;
+    let res: ResponseUploadChunk = serde_json::from_slice(&body).unwrap();
+    assert!(!res.ok);
+}
+
+#[tokio::test]
+async fn test_dedup() {
+    let (app, dir) = spawn_server_with(|builder| builder.dedup(Some(DedupMode::Drop)));
+    let res: ResponseUploadFull = post(&app, "/upload/full/a.txt", "same").await;
+    assert!(res.ok);
+    assert!(!res.deduplicated);
+    let res: ResponseUploadFull = post(&app, "/upload/full/b.txt", "same").await;
+    assert!(res.ok);
+    assert!(res.deduplicated);
+    assert_eq!(res.existing.as_deref(), Some("a.txt"));
+    assert!(!dir.path().join("b.txt").exists());
+    // files of the same size but different content are kept
+    let res: ResponseUploadFull = post(&app, "/upload/full/c.txt", "diff").await;
+    assert!(!res.deduplicated);
+    assert_eq!(read(dir.path().join("c.txt")).unwrap(), b"diff");
+    // identical uploads finished concurrently are deduplicated against each other
+    let uris: Vec<_> = (0..4)
+        .map(|i| format!("/upload/full/twin{}.txt", i))
+        .collect();
+    let results = futures::future::join_all(
+        uris.iter()
+            .map(|uri| post::<ResponseUploadFull>(&app, uri, "twin")),
+    )
+    .await;
+    assert_eq!(results.iter().filter(|res| !res.deduplicated).count(), 1);
+    let twins = std::fs::read_dir(dir.path())
+        .unwrap()
+        .filter(|entry| read(entry.as_ref().unwrap().path()).unwrap() == b"twin")
+        .count();
+    assert_eq!(twins, 1);
+
+    let (app, dir) = spawn_server_with(|builder| builder.dedup(Some(DedupMode::Link)));
+    let _: ResponseUploadFull = post(&app, "/upload/full/a.txt", "0123456789").await;
+    let res: ResponseUploadStart = post(
+        &app,
+        "/upload/start",
+        r#"{"file_name": "b.bin", "file_size": 10, "chunk_size": 4}"#,
+    )
+    .await;
+    let token = res.file_token.unwrap();
+    for (i, chunk) in [&b"0123"[..], b"4567", b"89"].iter().enumerate() {
+        let res: ResponseUploadChunk =
+            post(&app, &format!("/upload/{}/{}", token, i), chunk.to_vec()).await;
+        assert!(res.ok, "{:?}", res.error);
+    }
+    let res: ResponseUploadFinish = post(
+        &app,
+        "/upload/finish",
+        format!(r#"{{"file_token": "{}"}}"#, token),
+    )
+    .await;
+    assert!(res.ok, "{:?}", res.error);
+    assert!(res.deduplicated);
+    assert_eq!(res.existing.as_deref(), Some("a.txt"));
+    assert_eq!(read(dir.path().join("b.bin")).unwrap(), b"0123456789");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = std::fs::metadata(dir.path().join("a.txt")).unwrap();
+        assert_eq!(metadata.nlink(), 2);
+    }
+}
+
+#[tokio::test]
+async fn test_upload_present() {
+    // SHA-256 of "0123456789"
+    let sha256 = "84d89877f0d4041efb6bf91a16f0248f2fd573e6af05c19f96bedb9f882f7882";
+    let start = |name: &str, sha256: &str| {
+        format!(
+            r#"{{"file_name": "{}", "file_size": 10, "chunk_size": 4, "sha256": "{}"}}"#,
+            name, sha256
+        )
+    };
+    let (app, dir) =
+        spawn_server_with(|builder| builder.dedup(Some(DedupMode::Link)).dedup_present(true));
+    // clients are told whether to hash files beforehand
+    let options = |app: Router| async move {
+        let req = Request::get("/upload/options").body(Body::empty()).unwrap();
+        let (status, body) = send(&app, req).await;
+        assert_eq!(status, StatusCode::OK);
+        serde_json::from_slice::<ResponseUploadOptions>(&body).unwrap()
+    };
+    assert!(options(app.clone()).await.present);
+    let _: ResponseUploadFull = post(&app, "/upload/full/a.txt", "0123456789").await;
+    let res: ResponseUploadStart = post(&app, "/upload/start", start("b.bin", sha256)).await;
+    assert!(res.ok, "{:?}", res.error);
+    assert!(res.present);
+    assert!(res.file_token.is_none());
+    assert_eq!(read(dir.path().join("b.bin")).unwrap(), b"0123456789");
+    // the upload proceeds if the content is not present
+    let res: ResponseUploadStart =
+        post(&app, "/upload/start", start("c.bin", &"0".repeat(64))).await;
+    assert!(res.ok);
+    assert!(!res.present);
+    assert!(res.file_token.is_some());
+    let res: ResponseUploadStart = post(&app, "/upload/start", start("d.bin", "xyz")).await;
+    assert!(!res.ok);
+
+    // the digest is ignored unless opted in, or if deduplication is disabled
+    for configure in [
+        |builder: ServerBuilder| builder.dedup(Some(DedupMode::Link)),
+        |builder: ServerBuilder| builder.dedup_present(true),
+    ] {
+        let (app, _dir) = spawn_server_with(configure);
+        assert!(!options(app.clone()).await.present);
+        let _: ResponseUploadFull = post(&app, "/upload/full/a.txt", "0123456789").await;
+        let res: ResponseUploadStart = post(&app, "/upload/start", start("b.bin", sha256)).await;
+        assert!(res.ok);
+        assert!(!res.present);
+        assert!(res.file_token.is_some());
+    }
+}
+
+#[tokio::test]
+async fn test_compress() {
+    let (app, dir) = spawn_server_with(|builder| {
+        builder
+            .compress(Some(Codec::Zstd))
+            .compress_rules(["log", "csv"], ["skip.log"])
+    });
+    let text = "line\n".repeat(100);
+    let res: ResponseUploadFull = post(&app, "/upload/full/a.log", text.clone()).await;
+    assert!(res.ok, "{:?}", res.error);
+    assert_eq!(res.written, Some(text.len()));
+    let compressed = read(dir.path().join("a.log.zst")).unwrap();
+    assert_eq!(res.compressed_size, Some(compressed.len() as u64));
+    assert!(compressed.len() < text.len());
+    assert_eq!(zstd::decode_all(&compressed[..]).unwrap(), text.as_bytes());
+    assert!(!dir.path().join("a.log").exists());
+
+    // files not included or excluded are stored as is
+    for name in ["b.bin", "c.skip.log"] {
+        let res: ResponseUploadFull = post(&app, &format!("/upload/full/{}", name), "raw").await;
+        assert_eq!(res.compressed_size, None);
+        assert_eq!(read(dir.path().join(name)).unwrap(), b"raw");
+    }
+
+    // chunked uploads are compressed once finished
+    let res: ResponseUploadStart = post(
+        &app,
+        "/upload/start",
+        r#"{"file_name": "d.csv", "file_size": 10, "chunk_size": 4}"#,
+    )
+    .await;
+    let token = res.file_token.unwrap();
+    for (i, chunk) in [&b"a,b\n"[..], b"1,2\n", b"3\n"].iter().enumerate() {
+        let res: ResponseUploadChunk =
+            post(&app, &format!("/upload/{}/{}", token, i), chunk.to_vec()).await;
+        assert!(res.ok, "{:?}", res.error);
+    }
+    let res: ResponseUploadFinish = post(
+        &app,
+        "/upload/finish",
+        format!(r#"{{"file_token": "{}"}}"#, token),
+    )
+    .await;
+    assert!(res.ok, "{:?}", res.error);
+    assert_eq!(res.size, Some(10));
+    let compressed = read(dir.path().join("d.csv.zst")).unwrap();
+    assert_eq!(res.compressed_size, Some(compressed.len() as u64));
+    assert_eq!(zstd::decode_all(&compressed[..]).unwrap(), b"a,b\n1,2\n3\n");
+    assert!(!dir.path().join("d.csv").exists());
+
+    // the size is checked against the data received
+    let req = Request::post("/upload/full/e.log")
+        .header("Content-Length", "100")
+        .body(Body::from("short"))
+        .unwrap();
+    let (_, body) = send(&app, req).await;
+    let res: ResponseUploadFull = serde_json::from_slice(&body).unwrap();
+    assert!(!res.ok);
+    assert!(!dir.path().join("e.log.zst").exists());
+}
+
+#[tokio::test]
+async fn test_encrypt() {
+    let identity = age::x25519::Identity::generate();
+    let recipient = identity.to_public().to_string().parse().unwrap();
+    let (app, dir) = spawn_server_with(|builder| {
+        builder
+            .encrypt_to(Some(recipient))
+            .compress(Some(Codec::Zstd))
+            .compress_rules(["log"], Vec::<String>::new())
+    });
+    let decrypt = |name: &str| {
+        let encrypted = read(dir.path().join(name)).unwrap();
+        let decryptor = age::Decryptor::new(&encrypted[..]).unwrap();
+        let mut reader = decryptor
+            .decrypt(std::iter::once(&identity as &dyn age::Identity))
+            .unwrap();
+        let mut decrypted = vec![];
+        std::io::Read::read_to_end(&mut reader, &mut decrypted).unwrap();
+        decrypted
+    };
+
+    let res: ResponseUploadFull = post(&app, "/upload/full/a.txt", "secret").await;
+    assert!(res.ok, "{:?}", res.error);
+    assert_eq!(decrypt("a.txt.age"), b"secret");
+    assert!(!dir.path().join("a.txt").exists());
+
+    // files are compressed before encrypted
+    let text = "line\n".repeat(100);
+    let res: ResponseUploadFull = post(&app, "/upload/full/b.log", text.clone()).await;
+    assert!(res.ok, "{:?}", res.error);
+    assert_eq!(
+        zstd::decode_all(&decrypt("b.log.zst.age")[..]).unwrap(),
+        text.as_bytes()
+    );
+
+    // chunks are encrypted as received, in any order
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let res: ResponseUploadStart = post(
+        &app,
+        "/upload/start",
+        r#"{"file_name": "c.log", "file_size": 200000, "chunk_size": 65536}"#,
+    )
+    .await;
+    assert!(res.ok, "{:?}", res.error);
+    let token = res.file_token.unwrap();
+    for (i, chunk) in data.chunks(65536).enumerate().rev() {
+        let res: ResponseUploadChunk =
+            post(&app, &format!("/upload/{}/{}", token, i), chunk.to_vec()).await;
+        assert!(res.ok, "{:?}", res.error);
+    }
+    let res: ResponseUploadFinish = post(
+        &app,
+        "/upload/finish",
+        format!(r#"{{"file_token": "{}"}}"#, token),
+    )
+    .await;
+    assert!(res.ok, "{:?}", res.error);
+    assert_eq!(decrypt("c.log.age"), data);
+
+    // chunks of any size are encrypted even if not aligned with the segments
+    let res: ResponseUploadStart = post(
+        &app,
+        "/upload/start",
+        r#"{"file_name": "d.txt", "file_size": 10, "chunk_size": 4}"#,
+    )
+    .await;
+    assert!(res.ok, "{:?}", res.error);
+    let token = res.file_token.unwrap();
+    for (i, chunk) in b"0123456789".chunks(4).enumerate().rev() {
+        let res: ResponseUploadChunk =
+            post(&app, &format!("/upload/{}/{}", token, i), chunk.to_vec()).await;
+        assert!(res.ok, "{:?}", res.error);
+    }
+    let res: ResponseUploadFinish = post(
+        &app,
+        "/upload/finish",
+        format!(r#"{{"file_token": "{}"}}"#, token),
+    )
+    .await;
+    assert!(res.ok, "{:?}", res.error);
+    assert_eq!(decrypt("d.txt.age"), b"0123456789");
+}
+
+#[tokio::test]
+async fn test_extract() {
+    let (app, dir) = spawn_server_with(|builder| {
+        builder
+            .extract([ArchiveFormat::Zip, ArchiveFormat::TarGz])
+            .extract_limits(ExtractLimits {
+                max_size: 1000,
+                max_entries: 10,
+            })
+    });
+    let zip = |entries: &[(&str, &[u8])]| {
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+        for (name, data) in entries {
+            writer
+                .start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            std::io::Write::write_all(&mut writer, data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    };
+
+    let archive = zip(&[("a.txt", b"a"), ("sub/b.txt", b"b")]);
+    let res: ResponseUploadFull = post(&app, "/upload/full/docs.zip", archive).await;
+    assert!(res.ok, "{:?}", res.error);
+    let extracted = res.extracted.unwrap();
+    assert_eq!(extracted.dir, "docs");
+    assert_eq!(extracted.entries, vec!["a.txt", "sub/b.txt"]);
+    assert_eq!(read(dir.path().join("docs/sub/b.txt")).unwrap(), b"b");
+    assert!(dir.path().join("docs.zip").exists());
+
+    // tarballs uploaded in chunks are extracted as well
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+        vec![],
+        flate2::Compression::default(),
+    ));
+    let mut header = tar::Header::new_gnu();
+    header.set_size(3);
+    header.set_mode(0o644);
+    builder
+        .append_data(&mut header, "c.txt", &b"ccc"[..])
+        .unwrap();
+    let archive = builder.into_inner().unwrap().finish().unwrap();
+    let res: ResponseUploadStart = post(
+        &app,
+        "/upload/start",
+        format!(
+            r#"{{"file_name": "logs.tar.gz", "file_size": {}, "chunk_size": 1048576}}"#,
+            archive.len()
+        ),
+    )
+    .await;
+    let token = res.file_token.unwrap();
+    let res: ResponseUploadChunk = post(&app, &format!("/upload/{}/0", token), archive).await;
+    assert!(res.ok, "{:?}", res.error);
+    let res: ResponseUploadFinish = post(
+        &app,
+        "/upload/finish",
+        format!(r#"{{"file_token": "{}"}}"#, token),
+    )
+    .await;
+    assert!(res.ok, "{:?}", res.error);
+    assert_eq!(res.extracted.unwrap().entries, vec!["c.txt"]);
+    assert_eq!(read(dir.path().join("logs/c.txt")).unwrap(), b"ccc");
+
+    // unsafe archives are kept but not extracted
+    for archive in [
+        zip(&[("../escaped.txt", b"x")]),
+        zip(&[("big.bin", &[0; 1001])]),
+        zip(&["a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k"].map(|name| (name, &b""[..]))),
+    ] {
+        let res: ResponseUploadFull = post(&app, "/upload/full/bad.zip", archive).await;
+        assert!(res.ok, "{:?}", res.error);
+        assert!(res.extracted.is_none());
+        assert!(res.extract_error.is_some());
+    }
+    assert!(!dir.path().join("escaped.txt").exists());
+    assert!(!dir.path().join("bad").exists());
+    assert!(dir.path().join("bad_2.zip").exists());
+
+    // other files are stored as is
+    let res: ResponseUploadFull = post(&app, "/upload/full/a.tar", "raw").await;
+    assert!(res.extracted.is_none() && res.extract_error.is_none());
+}
+
+#[tokio::test]
+async fn test_type_filter() {
+    let (app, dir) = spawn_server_with(|builder| {
+        builder
+            .extension_rules(["pdf", "png"], Vec::<String>::new())
+            .type_rules(["application/pdf", "image/*"], ["image/gif"])
+    });
+    let pdf = b"%PDF-1.7\n".repeat(2000);
+    let res: ResponseUploadFull = post(&app, "/upload/full/a.pdf", pdf.clone()).await;
+    assert!(res.ok, "{:?}", res.error);
+    assert_eq!(read(dir.path().join("a.pdf")).unwrap(), pdf);
+
+    // rejected by the extension or the content, before anything is written
+    for (name, data) in [
+        ("b.exe", &b"%PDF-1.7\n"[..]),
+        ("c.pdf", b"MZ plain text"),
+        ("d.png", b"GIF89a"),
+    ] {
+        let req = Request::post(format!("/upload/full/{}", name))
+            .body(Body::from(data))
+            .unwrap();
+        let (status, body) = send(&app, req).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let res: ResponseUploadFull = serde_json::from_slice(&body).unwrap();
+        assert!(!res.ok);
+        assert!(!dir.path().join(name).exists());
+    }
+
+    let req = Request::post("/upload/start")
+        .header("Content-Type", "application/json")
+        .body(Body::from(
+            r#"{"file_name": "e.exe", "file_size": 10, "chunk_size": 4}"#,
+        ))
+        .unwrap();
+    assert_eq!(send(&app, req).await.0, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    // the content of chunked uploads is checked once the head is received, which cancels the file
+    let res: ResponseUploadStart = post(
+        &app,
+        "/upload/start",
+        r#"{"file_name": "f.png", "file_size": 12, "chunk_size": 6}"#,
+    )
+    .await;
+    let token = res.file_token.unwrap();
+    let res: ResponseUploadChunk = post(&app, &format!("/upload/{}/1", token), "secret").await;
+    assert!(res.ok, "{:?}", res.error);
+    let req = Request::post(format!("/upload/{}/0", token))
+        .body(Body::from("GIF89a"))
+        .unwrap();
+    assert_eq!(send(&app, req).await.0, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    let res: ResponseUploadChunk = post(&app, &format!("/upload/{}/0", token), "GIF89a").await;
+    assert!(!res.ok);
+    assert!(!dir.path().join("f.png").exists());
+    let res: ResponseUploadFinish = post(
+        &app,
+        "/upload/finish",
+        format!(r#"{{"file_token": "{}"}}"#, token),
+    )
+    .await;
+    assert!(!res.ok);
+
+    // the head is checked whichever chunks carry it and in whatever order they arrive
+    let upload = |name: &'static str, data: &'static [u8]| {
+        let app = app.clone();
+        async move {
+            let res: ResponseUploadStart = post(
+                &app,
+                "/upload/start",
+                format!(
+                    r#"{{"file_name": "{}", "file_size": {}, "chunk_size": 2}}"#,
+                    name,
+                    data.len()
+                ),
+            )
+            .await;
+            let token = res.file_token.unwrap();
+            for (index, chunk) in data.chunks(2).enumerate().rev() {
+                let req = Request::post(format!("/upload/{}/{}", token, index))
+                    .body(Body::from(chunk))
+                    .unwrap();
+                let (status, _) = send(&app, req).await;
+                if status != StatusCode::OK {
+                    return status;
+                }
+            }
+            let req = Request::post("/upload/finish")
+                .header("Content-Type", "application/json")
+                .body(Body::from(format!(r#"{{"file_token": "{}"}}"#, token)))
+                .unwrap();
+            send(&app, req).await.0
+        }
+    };
+    assert_eq!(
+        upload("g.png", b"GIF89a secret").await,
+        StatusCode::UNSUPPORTED_MEDIA_TYPE
+    );
+    assert!(!dir.path().join("g.png").exists());
+    let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    assert_eq!(upload("h.png", png).await, StatusCode::OK);
+    assert_eq!(read(dir.path().join("h.png")).unwrap(), png);
+}
+
+#[tokio::test]
+async fn test_retention() {
+    let dir = tempdir().unwrap();
+    let archive = tempdir().unwrap();
+    let now = SystemTime::now();
+    let days = |days: u64| now - Duration::from_secs(days * 24 * 60 * 60);
+    create_dir(dir.path().join("sub")).unwrap();
+    // the files of intray itself are never cleaned up however old they are
+    let own_files = [
+        "history.jsonl",
+        "audit.log",
+        "audit.log.1",
+        ".intray-probe-0",
+    ];
+    let files = [
+        ("old", days(100)),
+        ("sub/older", days(200)),
+        ("new", days(1)),
+    ];
+    for (name, modified) in own_files
+        .map(|name| (name, days(300)))
+        .into_iter()
+        .chain(files)
+    {
+        let path = dir.path().join(name);
+        write(&path, name).unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+    let server = |policy: RetentionPolicy| {
+        Server::builder()
+            .dir(dir.path())
+            .history(Some(dir.path().join("history.jsonl")))
+            .audit_log(Some(dir.path().join("audit.log")))
+            .retention(RetentionPolicy {
+                max_age: Some(Duration::from_secs(90 * 24 * 60 * 60)),
+                ..policy
+            })
+            .build()
+    };
+
+    let cleaned = server(RetentionPolicy {
+        dry_run: true,
+        ..Default::default()
+    })
+    .state()
+    .retain_once()
+    .await
+    .unwrap();
+    assert_eq!(
+        cleaned,
+        vec![dir.path().join("sub/older"), dir.path().join("old")]
+    );
+    assert!(dir.path().join("sub/older").exists());
+    assert!(dir.path().join("old").exists());
+
+    // moved with the relative paths kept
+    let cleaned = server(RetentionPolicy {
+        move_to: Some(archive.path().to_owned()),
+        ..Default::default()
+    })
+    .state()
+    .retain_once()
+    .await
+    .unwrap();
+    assert_eq!(cleaned.len(), 2);
+    // the directory left empty is removed
+    assert!(!dir.path().join("sub").exists());
+    assert_eq!(
+        read_to_string(archive.path().join("sub/older")).unwrap(),
+        "sub/older"
+    );
+    assert_eq!(read_to_string(archive.path().join("old")).unwrap(), "old");
+
+    // deleted beyond the size budget, oldest first
+    write(dir.path().join("newer"), "newer").unwrap();
+    let cleaned = server(RetentionPolicy {
+        max_size: Some(5),
+        ..Default::default()
+    })
+    .state()
+    .retain_once()
+    .await
+    .unwrap();
+    assert_eq!(cleaned, vec![dir.path().join("new")]);
+    assert!(dir.path().join("newer").exists());
+    for name in own_files {
+        assert!(dir.path().join(name).exists(), "{}", name);
+    }
+}
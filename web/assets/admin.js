@@ -0,0 +1,156 @@
+// Interval in milliseconds at which the status is refreshed.
+const REFRESH_INTERVAL = 10 * 1000;
+
+const logBase = (base, number) => Math.log(number) / Math.log(base);
+function size_to_readable(size) {
+    if (size === null || size === undefined) {
+        return "-";
+    }
+    if (size === 0) {
+        return "0 B";
+    }
+    const units = ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB", "ZiB", "YiB"];
+    const level = Math.min(Math.floor(logBase(1024, size)), units.length - 1);
+    const number = size / 1024 ** level;
+    return `${number.toFixed(2)} ${units[level]}`;
+}
+
+function cell(name, text) {
+    const td = document.createElement("td");
+    td.setAttribute("name", name);
+    td.textContent = text;
+    return td;
+}
+
+// The rows of in-flight uploads by token.
+const pendingRows = new Map();
+
+function showError(message) {
+    document.getElementById("error").textContent = message || "";
+}
+
+function progress(received, size) {
+    const percent = size > 0 ? Math.floor(received / size * 100) : 100;
+    return `${size_to_readable(received)} / ${size_to_readable(size)} (${percent}%)`;
+}
+
+function addPending(upload) {
+    removePending(upload.token);
+    const tr = document.createElement("tr");
+    tr.dataset.size = upload.size;
+    tr.appendChild(cell("name", upload.name));
+    tr.appendChild(cell("user", upload.user || "-"));
+    tr.appendChild(cell("client", upload.client || "-"));
+    tr.appendChild(cell("progress", progress(upload.received, upload.size)));
+    const td = document.createElement("td");
+    const button = document.createElement("button");
+    button.className = "button is-small is-danger";
+    button.textContent = "Cancel";
+    button.addEventListener("click", () => cancel(upload.token, upload.name));
+    td.appendChild(button);
+    tr.appendChild(td);
+    document.getElementById("pending").appendChild(tr);
+    pendingRows.set(upload.token, tr);
+}
+
+function updatePending(token, received) {
+    const tr = pendingRows.get(token);
+    if (tr) {
+        tr.querySelector("td[name=progress]").textContent = progress(received, Number(tr.dataset.size));
+    }
+}
+
+function removePending(token) {
+    const tr = pendingRows.get(token);
+    if (tr) {
+        tr.remove();
+        pendingRows.delete(token);
+    }
+}
+
+async function cancel(token, name) {
+    if (!confirm(`Cancel the upload of ${name}? The uploader will no longer be able to continue.`)) {
+        return;
+    }
+    try {
+        const result = await (await fetch(`admin/cancel/${token}`, { method: "POST" })).json();
+        if (result.ok) {
+            removePending(token);
+        } else {
+            showError(result.error);
+        }
+    } catch (error) {
+        showError(`Failed to cancel the upload: ${error}`);
+    }
+}
+
+async function refresh() {
+    let status;
+    try {
+        status = await (await fetch("admin/status")).json();
+    } catch (error) {
+        showError(`Failed to get the status: ${error}`);
+        return;
+    }
+    showError(status.error);
+    document.getElementById("disk-free").textContent = size_to_readable(status.disk.free);
+    document.getElementById("disk-usage").textContent = size_to_readable(status.disk.usage);
+    document.getElementById("disk-quota").textContent =
+        status.disk.quota === null ? "unlimited" : size_to_readable(status.disk.quota);
+    for (const token of Array.from(pendingRows.keys())) {
+        if (!status.pending.some((upload) => upload.token === token)) {
+            removePending(token);
+        }
+    }
+    for (const upload of status.pending) {
+        if (pendingRows.has(upload.token)) {
+            updatePending(upload.token, upload.received);
+        } else {
+            addPending(upload);
+        }
+    }
+    const recent = document.getElementById("recent");
+    recent.textContent = "";
+    if (status.recent === null) {
+        const tr = document.createElement("tr");
+        const td = cell("note", "The history is disabled.");
+        td.colSpan = 5;
+        tr.appendChild(td);
+        recent.appendChild(tr);
+        return;
+    }
+    for (const entry of status.recent) {
+        const tr = document.createElement("tr");
+        tr.appendChild(cell("time", new Date(entry.time).toLocaleString()));
+        tr.appendChild(cell("name", entry.name));
+        tr.appendChild(cell("user", entry.user || "-"));
+        tr.appendChild(cell("client", entry.client || "-"));
+        tr.appendChild(cell("size", size_to_readable(entry.size)));
+        recent.appendChild(tr);
+    }
+}
+
+// Track the progress of uploads live, with the status refreshed periodically for the rest.
+const events = new EventSource("admin/events");
+events.addEventListener("start", (event) => {
+    const upload = JSON.parse(event.data);
+    // full uploads are shown as well until finished, though they cannot be canceled
+    if (!pendingRows.has(upload.token)) {
+        addPending(upload);
+    }
+});
+events.addEventListener("chunk", (event) => {
+    const chunk = JSON.parse(event.data);
+    updatePending(chunk.token, chunk.received);
+});
+for (const type of ["finish", "expire", "cancel"]) {
+    events.addEventListener(type, (event) => {
+        removePending(JSON.parse(event.data).token);
+        if (type === "finish") {
+            refresh();
+        }
+    });
+}
+
+refresh();
+setInterval(refresh, REFRESH_INTERVAL);
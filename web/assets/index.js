@@ -6,6 +6,8 @@ const ONESHOT_THRESHHOLD = CHUNK_SIZE;
 const CONCURRENT_WORKER = 3;
 // Retry to upload chunks if failed for at most `CHUNK_RETRY` times;
 const CHUNK_RETRY = 3;
+// Number of chunks of one file to be uploaded concurrently.
+const CONCURRENT_CHUNKS = 3;
 
 if (Object.fromEntries == undefined) {
     Object.fromEntries = function(entries) {
@@ -182,6 +184,20 @@ async function upload_worker(token) {
     console.log(`Worker [${token}] ends.`);
 }
 
+// The options of the server, fetched once when first needed.
+let upload_options;
+function get_upload_options() {
+    if (upload_options === undefined) {
+        upload_options = fetch("upload/options")
+            .then(res => res.json())
+            .catch(e => {
+                console.log(`Failed to get the upload options: ${e}`);
+                return { present: false };
+            });
+    }
+    return upload_options;
+}
+
 function upload(task) {
     if (task.file.size <= ONESHOT_THRESHHOLD) {
         return upload_oneshot(task);
@@ -191,6 +207,17 @@ function upload(task) {
     }
 }
 
+// Report the entries extracted by the server if the file is an archive.
+function log_extraction(file, result) {
+    if (result.extracted) {
+        console.log(`${file.name} is extracted into ${result.extracted.dir}/ with \
+                ${result.extracted.entries.length} files.`);
+    }
+    else if (result.extract_error) {
+        console.warn(`${file.name} is not extracted: ${result.extract_error}`);
+    }
+}
+
 async function upload_oneshot(task) {
     console.log("upload_oneshot");
     const file = task.file;
@@ -211,6 +238,7 @@ async function upload_oneshot(task) {
         console.log(`Successfully uploaded ${file.name} with \
                 ${file.size / 1024 / 1024} MiBs in ${elapsed / 1000} seconds at \
                 ${file.size / 1024 / 1024 / (elapsed / 1000)} MiB/sec.`);
+        log_extraction(file, result);
     }
     else {
         throw new Error(`Server error ${result.error}`);
@@ -223,10 +251,22 @@ async function upload_in_chunks(task) {
     console.log("upload_in_chunks");
     const file = task.file;
     const start_at = Date.now();
+    // Hash the file first if the server skips the upload when it holds the content already.
+    let sha256;
+    if ((await get_upload_options()).present) {
+        try {
+            sha256 = await sha256_file(file, progress => task.setProgress(progress));
+        }
+        catch (e) {
+            throw new Error(`Error when hashing: ${e}`);
+        }
+        task.setProgress(0);
+    }
     const metadata = {
         'file_name': file.name,
         'file_size': file.size,
-        'chunk_size': CHUNK_SIZE
+        'chunk_size': CHUNK_SIZE,
+        'sha256': sha256
     };
     console.log("Uploading", metadata);
     // Here treat `job` as initialized uploading instance of `task`.
@@ -241,34 +281,51 @@ async function upload_in_chunks(task) {
     catch (e) {
         throw new Error(`Error when initialzing: ${e}`);
     }
-    if (job.ok) {
+    if (job.ok && job.present) {
+        console.log(`${file.name} is already present on the server, skipped.`);
+        task.setProgress(100);
+    }
+    else if (job.ok) {
         const chunk_number = Math.ceil(file.size / CHUNK_SIZE);
         const file_token = job.file_token;
-        for (let chunk_index = 0; chunk_index < chunk_number; chunk_index++) {
-            let chunk_ok;
-            for (let retry = 0; retry < CHUNK_RETRY; retry++) {
-                try {
-                    const chunk = await (await fetch(`upload/${file_token}/${chunk_index}`, {
-                        method: "POST",
-                        headers: { 'Content-Type': "application/octet-stream" },
-                        body: file.slice(chunk_index * CHUNK_SIZE, (chunk_index + 1) * CHUNK_SIZE)
-                    })).json();
-                    if (chunk.ok) {
-                        task.setProgress((chunk_index + 1) / (chunk_number) * 100);
-                        console.log(`Uploaded ${chunk_index + 1}/${chunk_number} chunks.`);
-                        chunk_ok = true;
-                        break;
+        let next_chunk = 0;
+        let uploaded = 0;
+        // Each lane keeps fetching the next chunk that is not taken yet until all are uploaded.
+        const upload_lane = async function () {
+            while (next_chunk < chunk_number) {
+                const chunk_index = next_chunk;
+                next_chunk += 1;
+                let chunk_ok;
+                for (let retry = 0; retry < CHUNK_RETRY; retry++) {
+                    try {
+                        const chunk = await (await fetch(`upload/${file_token}/${chunk_index}`, {
+                            method: "POST",
+                            headers: { 'Content-Type': "application/octet-stream" },
+                            body: file.slice(chunk_index * CHUNK_SIZE, (chunk_index + 1) * CHUNK_SIZE)
+                        })).json();
+                        if (chunk.ok) {
+                            uploaded += 1;
+                            task.setProgress(uploaded / chunk_number * 100);
+                            console.log(`Uploaded ${uploaded}/${chunk_number} chunks.`);
+                            chunk_ok = true;
+                            break;
+                        }
+                    }
+                    catch (e) {
+                        console.log(`Failed: ${e}, retrying.`);
+                        throw e;
                     }
                 }
-                catch (e) {
-                    console.log(`Failed: ${e}, retrying.`);
-                    throw e;
+                if (chunk_ok !== true) {
+                    throw new Error(`Maximum retry times reached.`)
                 }
             }
-            if (chunk_ok !== true) {
-                throw new Error(`Maximum retry times reached.`)
-            }
+        };
+        const lanes = [];
+        for (let i = 0; i < Math.min(CONCURRENT_CHUNKS, chunk_number); i++) {
+            lanes.push(upload_lane());
         }
+        await Promise.all(lanes);
         let result;
         try {
             result = await (await fetch("upload/finish", {
@@ -287,6 +344,10 @@ async function upload_in_chunks(task) {
             console.log(`Successfully uploaded ${file.name} with \
                     ${file.size / 1024 / 1024} MiBs in ${elapsed / 1000} seconds at \
                     ${file.size / 1024 / 1024 / (elapsed / 1000)} MiB/sec.`);
+            if (result.deduplicated) {
+                console.log(`${file.name} is identical to ${result.existing} on the server, deduplicated.`);
+            }
+            log_extraction(file, result);
         }
         else {
             throw new Error(`Server error: ${result.error}`);
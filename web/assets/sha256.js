@@ -0,0 +1,96 @@
+// Incremental SHA-256, since `crypto.subtle.digest` can neither hash a file piece by piece nor
+// be used in insecure contexts (i.e. plain HTTP other than localhost).
+// Files up to this size are hashed at once by `crypto.subtle` instead if available, which is much
+// faster but takes the whole file in memory.
+const SUBTLE_HASH_LIMIT = 256 * 1024 * 1024;
+const SHA256_K = new Uint32Array([
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+]);
+
+class Sha256 {
+    constructor() {
+        this.state = new Uint32Array([
+            0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+            0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+        ]);
+        this.block = new Uint8Array(64);
+        this.filled = 0;
+        this.length = 0;
+        this.w = new Uint32Array(64);
+    }
+
+    update(data) {
+        this.length += data.length;
+        let pos = 0;
+        while (pos < data.length) {
+            const n = Math.min(64 - this.filled, data.length - pos);
+            this.block.set(data.subarray(pos, pos + n), this.filled);
+            this.filled += n;
+            pos += n;
+            if (this.filled === 64) {
+                this.compress();
+                this.filled = 0;
+            }
+        }
+    }
+
+    compress() {
+        const w = this.w, b = this.block;
+        for (let i = 0; i < 16; i++) {
+            w[i] = (b[4 * i] << 24) | (b[4 * i + 1] << 16) | (b[4 * i + 2] << 8) | b[4 * i + 3];
+        }
+        for (let i = 16; i < 64; i++) {
+            const x = w[i - 15], y = w[i - 2];
+            const s0 = ((x >>> 7) | (x << 25)) ^ ((x >>> 18) | (x << 14)) ^ (x >>> 3);
+            const s1 = ((y >>> 17) | (y << 15)) ^ ((y >>> 19) | (y << 13)) ^ (y >>> 10);
+            w[i] = (w[i - 16] + s0 + w[i - 7] + s1) | 0;
+        }
+        let [a, bb, c, d, e, f, g, h] = this.state;
+        for (let i = 0; i < 64; i++) {
+            const S1 = ((e >>> 6) | (e << 26)) ^ ((e >>> 11) | (e << 21)) ^ ((e >>> 25) | (e << 7));
+            const ch = (e & f) ^ (~e & g);
+            const t1 = (h + S1 + ch + SHA256_K[i] + w[i]) | 0;
+            const S0 = ((a >>> 2) | (a << 30)) ^ ((a >>> 13) | (a << 19)) ^ ((a >>> 22) | (a << 10));
+            const maj = (a & bb) ^ (a & c) ^ (bb & c);
+            const t2 = (S0 + maj) | 0;
+            h = g; g = f; f = e; e = (d + t1) | 0;
+            d = c; c = bb; bb = a; a = (t1 + t2) | 0;
+        }
+        const s = this.state;
+        s[0] += a; s[1] += bb; s[2] += c; s[3] += d; s[4] += e; s[5] += f; s[6] += g; s[7] += h;
+    }
+
+    hex() {
+        const bits = this.length * 8;
+        const padding = new Uint8Array((this.filled < 56 ? 56 : 120) - this.filled + 8);
+        padding[0] = 0x80;
+        const view = new DataView(padding.buffer);
+        view.setUint32(padding.length - 8, Math.floor(bits / 2 ** 32));
+        view.setUint32(padding.length - 4, bits >>> 0);
+        this.update(padding);
+        return Array.from(this.state, x => x.toString(16).padStart(8, "0")).join("");
+    }
+}
+
+// Hash the file piece by piece, reporting the progress in percent.
+async function sha256_file(file, progress_fn, piece_size = 4 * 1024 * 1024) {
+    if (window.crypto && window.crypto.subtle && file.size <= SUBTLE_HASH_LIMIT) {
+        const digest = await window.crypto.subtle.digest("SHA-256", await file.arrayBuffer());
+        progress_fn && progress_fn(100);
+        return Array.from(new Uint8Array(digest), x => x.toString(16).padStart(2, "0")).join("");
+    }
+    const hasher = new Sha256();
+    for (let pos = 0; pos < file.size; pos += piece_size) {
+        const piece = await file.slice(pos, pos + piece_size).arrayBuffer();
+        hasher.update(new Uint8Array(piece));
+        progress_fn && progress_fn(Math.round(100 * Math.min(pos + piece_size, file.size) / file.size));
+    }
+    return hasher.hex();
+}